@@ -46,8 +46,13 @@ fn ui_system(
                 //     .add_filter("Magica Voxel VOX File", &["vox"])
                 //     .pick_file().block_on();
 
-                let path = tinyfiledialogs::open_file_dialog("Select file", "", None);
-                *load_voxel_world = LoadVoxelWorld::File(path.unwrap());
+                if let Some(path) = tinyfiledialogs::open_file_dialog("Select file", "", None) {
+                    *load_voxel_world = if path.ends_with(".bvw") {
+                        LoadVoxelWorld::Native(path)
+                    } else {
+                        LoadVoxelWorld::File(path)
+                    };
+                }
             }
             for (i, (mut trace_settings, bloom_settings, tonemapping, fxaa)) in
                 camera_settings_query.iter_mut().enumerate()
@@ -56,6 +61,10 @@ fn ui_system(
                     ui.checkbox(&mut trace_settings.show_ray_steps, "Show ray steps");
                     ui.add(Slider::new(&mut trace_settings.samples, 1..=8).text("Samples"));
                     ui.checkbox(&mut trace_settings.shadows, "Shadows");
+                    ui.add(
+                        Slider::new(&mut trace_settings.indirect_bounces, 0..=2)
+                            .text("Indirect bounces"),
+                    );
                     if let Some(bloom_settings) = bloom_settings {
                         ui.add(
                             Slider::new(&mut bloom_settings.into_inner().intensity, 0.0..=1.0)
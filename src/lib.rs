@@ -1,17 +1,44 @@
 use bevy::{
     core_pipeline::tonemapping::Tonemapping,
     prelude::*,
-    render::{camera::CameraRenderGraph, camera::CameraMainTextureUsages, primitives::Frustum, view::VisibleEntities},
+    render::{
+        camera::CameraMainTextureUsages,
+        camera::CameraRenderGraph,
+        primitives::Frustum,
+        render_resource::{ShaderDefVal, TextureFormat},
+        renderer::RenderAdapter,
+        settings::WgpuFeatures,
+        view::VisibleEntities,
+        RenderApp,
+    },
+};
+pub use load::{
+    GridHierarchy, HeightmapOptions, Material, NoiseGenerator, Palette, PrefabStamp, RegionCache,
+    StampMode, VoxWorldAsset, VoxelAnimation, VoxelPrefab, WorldGenerator,
 };
+pub use material::{MaterialRegistry, WideMaterials};
 use physics::PhysicsPlugin;
+#[allow(deprecated)]
 pub use physics::VOXELS_PER_METER;
-use voxel_pipeline::{RenderPlugin, VoxelGraph};
+use std::{borrow::Cow, sync::Arc};
+use voxel_pipeline::{
+    voxel_world::{MAX_ACTIVE_CHUNKS, MAX_BRICK_CAPACITY},
+    RenderPlugin, VoxelGraph,
+};
 pub use voxel_pipeline::{
-    trace::TraceSettings, voxelization::VoxelizationMaterial,
+    compute::bricks::BrickPoolOverflow,
+    edit::{StampPrefab, Voxel, VoxelWorld},
+    objects::VoxelObject,
+    save::{save_world_native, save_world_vox},
+    streaming::ChunkAnchor,
+    trace::TraceSettings,
+    voxel_world::{ExtraVoxelWorld, TargetVoxelWorld},
+    voxelization::VoxelizationMaterial,
     voxelization::VoxelizationMaterialType, RenderGraphSettings,
 };
 
 mod load;
+mod material;
 mod physics;
 mod voxel_pipeline;
 
@@ -39,6 +66,45 @@ pub struct Box {
     pub half_size: IVec3,
 }
 
+/// Stamps a prefab centred on the transform every frame through the animation
+/// pass, like [`Box`]. It only fills air, so the mode of the stamp is ignored.
+/// The flags are added to the flags of every voxel, set
+/// [`Flags::ANIMATION_FLAG`] for the stamp to be cleared the next frame.
+#[derive(Component)]
+pub struct AnimatedPrefab {
+    pub prefab: Handle<VoxelPrefab>,
+    pub stamp: PrefabStamp,
+    pub flags: u8,
+}
+
+/// Plays a [`VoxelAnimation`] centred on the transform, stamping the current
+/// frame through the animation pass like [`AnimatedPrefab`]. The frames are
+/// stamped with [`Flags::ANIMATION_FLAG`] and the flags added.
+#[derive(Component)]
+pub struct AnimatedVoxelModel {
+    pub animation: Handle<VoxelAnimation>,
+    /// Frames per second.
+    pub frame_rate: f32,
+    /// Seconds the animation has played for, advanced while playing.
+    pub elapsed: f32,
+    pub playing: bool,
+    pub stamp: PrefabStamp,
+    pub flags: u8,
+}
+
+impl AnimatedVoxelModel {
+    pub fn new(animation: Handle<VoxelAnimation>, frame_rate: f32) -> Self {
+        Self {
+            animation,
+            frame_rate,
+            elapsed: 0.0,
+            playing: true,
+            stamp: PrefabStamp::default(),
+            flags: Flags::NONE,
+        }
+    }
+}
+
 #[derive(Component)]
 pub struct VoxelPhysics {
     pub velocity: Vec3,
@@ -155,20 +221,368 @@ pub struct VoxelizationBundle {
     pub view_visibility: ViewVisibility,
 }
 
-pub struct BevyVoxelEnginePlugin;
+#[derive(Default)]
+pub struct BevyVoxelEnginePlugin {
+    pub settings: VoxelEngineSettings,
+}
 
 impl Plugin for BevyVoxelEnginePlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(Msaa::Off)
+        let settings = match self.settings.validate() {
+            Ok(()) => self.settings.clone(),
+            Err(e) => {
+                error!("Invalid voxel engine settings, using the defaults: {}", e);
+                VoxelEngineSettings::default()
+            }
+        };
+
+        // the pipelines are built from the settings so they can't change later
+        app.sub_app_mut(RenderApp)
+            .insert_resource(settings.clone());
+        app.insert_resource(settings)
+            .insert_resource(Msaa::Off)
             .add_plugins(PhysicsPlugin)
             .add_plugins(RenderPlugin);
     }
 }
 
-#[derive(Resource)]
+/// Fixed when the engine is added, pass them in through
+/// [`BevyVoxelEnginePlugin`]. Smaller chunks and fewer active chunks use less
+/// gpu memory. Invalid settings are logged and replaced by the defaults.
+#[derive(Resource, Clone, Debug)]
+pub struct VoxelEngineSettings {
+    /// Voxels along one meter of world space.
+    pub voxels_per_meter: f32,
+    /// Size of the chunk textures before a world is loaded, and the largest
+    /// chunks a world can be loaded with. A power of two between 8 and 256.
+    pub chunk_size: u32,
+    /// Active chunks along each axis around the origin chunk, odd so the
+    /// origin chunk is in the middle. At most 125 chunks in total. Every
+    /// active chunk is traced, rebuilt and simulated each frame, so gpu time
+    /// and memory grow with the chunk count. Meshes are only voxelized into
+    /// the origin chunk.
+    pub chunk_grid: UVec3,
+    /// How the chunks are bound to the shaders. Automatic is replaced with the
+    /// storage the gpu supports once the renderer has started.
+    pub chunk_storage: ChunkStorage,
+    /// Bricks in the pool of [`ChunkStorage::Bricks`], each holds 8x8x8
+    /// voxels and takes 1 kB of gpu memory, 2 kB with [`VoxelFormat::Wide`].
+    /// Unused by the other storages.
+    pub brick_capacity: u32,
+    /// How voxels are stored on the gpu.
+    pub voxel_format: VoxelFormat,
+}
+
+/// Every storage binds the chunks as read and write storage textures of the
+/// [`VoxelFormat`], which needs
+/// [`WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChunkStorage {
+    /// Texture arrays if the gpu supports them, the atlas otherwise.
+    #[default]
+    Automatic,
+    /// Every chunk in its own texture, bound as a texture array. Needs
+    /// [`ChunkStorage::REQUIRED_FEATURES`].
+    TextureArray,
+    /// All chunks side by side in one large texture, works without the texture
+    /// array features as long as the atlas fits in the largest 3d texture the
+    /// gpu supports.
+    Atlas,
+    /// Chunks are split into 8x8x8 bricks that are only given memory once
+    /// something is written to them, so empty space costs nothing. The bricks
+    /// come from a shared pool of [`VoxelEngineSettings::brick_capacity`]
+    /// bricks, writes into empty space are dropped once it is full and
+    /// counted in [`BrickPoolOverflow`]. Bricks left empty by the simulation
+    /// are returned to the pool whenever the grid hierarchy is rebuilt. Never
+    /// picked automatically.
+    Bricks,
+}
+
+/// Bit layout of the voxels in the chunk textures, see LAYOUT.md. Chunk data
+/// on the cpu, the files and the edit apis always use the narrow layout,
+/// chunks are widened when they are uploaded and narrowed when they are read
+/// back. The shaders go through the codec in common.wgsl.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VoxelFormat {
+    /// 16 bits, an 8 bit material and the flags.
+    #[default]
+    Narrow,
+    /// 32 bits, a 12 bit material, the flags and 12 bits of state the shaders
+    /// can use for things like damage or fluid levels. The colours and
+    /// properties of the materials above 255 come from [`WideMaterials`].
+    /// The state isn't saved. Worlds with materials above 255 can't be saved,
+    /// and those voxels are emptied when their chunk is evicted into the
+    /// [`RegionCache`], which logs an error.
+    Wide,
+}
+
+impl VoxelFormat {
+    pub fn texture_format(self) -> TextureFormat {
+        match self {
+            Self::Narrow => TextureFormat::R16Uint,
+            Self::Wide => TextureFormat::R32Uint,
+        }
+    }
+
+    pub fn bytes_per_voxel(self) -> u32 {
+        match self {
+            Self::Narrow => 2,
+            Self::Wide => 4,
+        }
+    }
+
+    fn material_bits(self) -> u32 {
+        match self {
+            Self::Narrow => 8,
+            Self::Wide => 12,
+        }
+    }
+
+    /// Largest material a voxel can hold.
+    pub fn max_material(self) -> u16 {
+        (1 << self.material_bits()) - 1
+    }
+
+    /// Like `encode_voxel` in common.wgsl, the state is dropped by the narrow
+    /// format.
+    pub fn encode(self, material: u16, flags: u8, state: u16) -> u32 {
+        let bits = self.material_bits();
+        let state = match self {
+            Self::Narrow => 0,
+            Self::Wide => (state as u32 & 0xFFF) << 20,
+        };
+        (material as u32 & self.max_material() as u32) | (flags as u32) << bits | state
+    }
+
+    /// Material, flags and state of a voxel.
+    pub fn decode(self, voxel: u32) -> (u16, u8, u16) {
+        let bits = self.material_bits();
+        let state = match self {
+            Self::Narrow => 0,
+            Self::Wide => (voxel >> 20) as u16,
+        };
+        (
+            (voxel & self.max_material() as u32) as u16,
+            (voxel >> bits) as u8,
+            state,
+        )
+    }
+
+    /// Chunk data in the layout of the textures.
+    pub(crate) fn widen(self, data: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            Self::Narrow => Cow::Borrowed(data),
+            Self::Wide => Cow::Owned(
+                data.chunks_exact(2)
+                    .flat_map(|voxel| self.encode(voxel[0] as u16, voxel[1], 0).to_le_bytes())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Like [`Self::narrow`] but empties the voxels with materials that don't
+    /// fit instead of failing, returns how many there were.
+    pub(crate) fn narrow_lossy(self, data: Vec<u8>) -> (Vec<u8>, usize) {
+        match self {
+            Self::Narrow => (data, 0),
+            Self::Wide => {
+                let mut lost = 0;
+                let data = data
+                    .chunks_exact(4)
+                    .flat_map(|voxel| {
+                        let voxel = u32::from_le_bytes(voxel.try_into().unwrap());
+                        let (material, flags, _) = self.decode(voxel);
+                        match u8::try_from(material) {
+                            Ok(material) => [material, flags],
+                            Err(_) => {
+                                lost += 1;
+                                [0, 0]
+                            }
+                        }
+                    })
+                    .collect();
+                (data, lost)
+            }
+        }
+    }
+
+    /// Chunk data from texture data, fails if a material doesn't fit in the
+    /// narrow layout.
+    pub(crate) fn narrow(self, data: Vec<u8>) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Narrow => Ok(data),
+            Self::Wide => data
+                .chunks_exact(4)
+                .map(|voxel| {
+                    let voxel = u32::from_le_bytes(voxel.try_into().unwrap());
+                    let (material, flags, _) = self.decode(voxel);
+                    u8::try_from(material)
+                        .map(|material| [material, flags])
+                        .map_err(|_| format!("Material {} doesn't fit in 8 bits", material))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(|voxels| voxels.concat()),
+        }
+    }
+}
+
+impl ChunkStorage {
+    pub const REQUIRED_FEATURES: WgpuFeatures = WgpuFeatures::TEXTURE_BINDING_ARRAY
+        .union(WgpuFeatures::STORAGE_RESOURCE_BINDING_ARRAY)
+        .union(WgpuFeatures::UNIFORM_BUFFER_AND_STORAGE_TEXTURE_ARRAY_NON_UNIFORM_INDEXING);
+
+    /// Picks the storage for automatic and fails if the device can't read and
+    /// write chunk textures of the voxel format, which every storage does.
+    pub(crate) fn resolve(
+        self,
+        adapter: &RenderAdapter,
+        features: WgpuFeatures,
+        voxel_format: VoxelFormat,
+    ) -> Result<Self, String> {
+        // the format features wgpu validates storage textures against
+        let format = voxel_format.texture_format();
+        let downlevel = !adapter
+            .get_downlevel_capabilities()
+            .flags
+            .contains(wgpu::DownlevelFlags::WEBGPU_TEXTURE_FORMAT_SUPPORT);
+        let adapter_specific =
+            downlevel || features.contains(WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+        let format_features = if adapter_specific {
+            adapter.get_texture_format_features(format)
+        } else {
+            format.guaranteed_format_features(features)
+        };
+        if !format_features
+            .flags
+            .contains(wgpu::TextureFormatFeatureFlags::STORAGE_READ_WRITE)
+        {
+            return Err(format!(
+                "Chunk textures need read and write access to {:?} storage textures, which \
+                 needs WgpuFeatures::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES and an adapter \
+                 that supports it",
+                format
+            ));
+        }
+
+        Ok(match self {
+            Self::Automatic if features.contains(Self::REQUIRED_FEATURES) => Self::TextureArray,
+            Self::Automatic => Self::Atlas,
+            storage => storage,
+        })
+    }
+}
+
+impl Default for VoxelEngineSettings {
+    fn default() -> Self {
+        Self {
+            voxels_per_meter: 4.0,
+            chunk_size: 256,
+            chunk_grid: UVec3::splat(3),
+            chunk_storage: ChunkStorage::Automatic,
+            brick_capacity: 65536,
+            voxel_format: VoxelFormat::Narrow,
+        }
+    }
+}
+
+impl VoxelEngineSettings {
+    pub fn active_chunk_count(&self) -> usize {
+        (self.chunk_grid.x * self.chunk_grid.y * self.chunk_grid.z) as usize
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.voxels_per_meter <= 0.0 {
+            return Err(format!(
+                "Voxels per meter must be positive, got {}",
+                self.voxels_per_meter
+            ));
+        }
+        let chunk_sizes = 8..=load::MAX_CHUNK_SIZE;
+        if !self.chunk_size.is_power_of_two() || !chunk_sizes.contains(&self.chunk_size) {
+            return Err(format!("Invalid chunk size {}", self.chunk_size));
+        }
+        if self.chunk_grid.to_array().iter().any(|size| size % 2 == 0) {
+            return Err(format!(
+                "The chunk grid {} has to be odd along every axis",
+                self.chunk_grid
+            ));
+        }
+        if self.active_chunk_count() > MAX_ACTIVE_CHUNKS {
+            return Err(format!(
+                "The chunk grid {} has more than {} chunks",
+                self.chunk_grid, MAX_ACTIVE_CHUNKS
+            ));
+        }
+        if self.chunk_storage == ChunkStorage::Bricks
+            && !(1..=MAX_BRICK_CAPACITY).contains(&self.brick_capacity)
+        {
+            return Err(format!(
+                "The brick capacity has to be between 1 and {}, got {}",
+                MAX_BRICK_CAPACITY, self.brick_capacity
+            ));
+        }
+        Ok(())
+    }
+
+    /// Defines the shaders are built with.
+    pub(crate) fn shader_defs(&self) -> Vec<ShaderDefVal> {
+        let mut shader_defs = vec![
+            ShaderDefVal::UInt("ACTIVE_CHUNKS".into(), self.active_chunk_count() as u32),
+            ShaderDefVal::UInt("CHUNK_GRID_X".into(), self.chunk_grid.x),
+            ShaderDefVal::UInt("CHUNK_GRID_Y".into(), self.chunk_grid.y),
+            ShaderDefVal::UInt("CHUNK_GRID_Z".into(), self.chunk_grid.z),
+        ];
+        match self.chunk_storage {
+            ChunkStorage::Atlas => shader_defs.push("CHUNK_ATLAS".into()),
+            ChunkStorage::Bricks => shader_defs.push("CHUNK_BRICKS".into()),
+            _ => (),
+        }
+        if self.voxel_format == VoxelFormat::Wide {
+            shader_defs.push("VOXEL_WIDE".into());
+        }
+        shader_defs
+    }
+}
+
+/// Loads a world into the main world as a resource, or into an
+/// [`ExtraVoxelWorld`] as a component of it. Files are read and worlds are
+/// generated on the task pool, a newer request replaces one that is still
+/// loading.
+#[derive(Resource, Component)]
 pub enum LoadVoxelWorld {
     Empty(u32),
+    /// A .vox, .qb or native world file, see [`GridHierarchy::from_file`].
     File(String),
+    Native(String),
+    Asset(Handle<VoxWorldAsset>),
+    /// Builds the world on the cpu, the same seed always gives the same world.
+    Generated {
+        seed: u64,
+        generator: Arc<dyn WorldGenerator>,
+    },
+    None,
+}
+
+/// Sent when a world requested through the [`LoadVoxelWorld`] resource has
+/// been loaded.
+#[derive(Event)]
+pub struct WorldLoaded;
+
+/// Sent with the error when a world requested through the [`LoadVoxelWorld`]
+/// resource could not be loaded.
+#[derive(Event)]
+pub struct WorldLoadFailed(pub String);
+
+/// Recolours the world with a [`Palette`] instead of the palette it was loaded
+/// with. The voxels are not reloaded, only the colours change, and saves keep
+/// the palette of the loaded world. Set it back to none to return to it.
+#[derive(Resource, Default)]
+pub struct ActivePalette(pub Option<Handle<Palette>>);
+
+#[derive(Resource)]
+pub enum SaveVoxelWorld {
+    Vox(String),
+    Native(String),
     None,
 }
 
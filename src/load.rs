@@ -1,18 +1,241 @@
-use bevy::prelude::*;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    utils::HashMap,
+};
+use std::{path::Path, sync::Arc};
 
 use crate::Flags;
 
+mod animation;
+mod generate;
+mod heightmap;
+mod palette;
+mod prefab;
+mod qb;
+mod region;
+
+pub use animation::{VoxelAnimation, VoxelAnimationLoader};
+pub use generate::{NoiseGenerator, WorldGenerator};
+pub use heightmap::HeightmapOptions;
+pub use palette::{Palette, PaletteLoader};
+pub use prefab::{PrefabStamp, StampMode, VoxelPrefab};
+pub use region::RegionCache;
+pub(crate) use region::QueuedSave;
+
+/// Largest scene magica voxel can build.
+const MAX_VOX_EXTENT: i32 = 2048;
+
+/// Largest size of a single chunk texture.
+pub(crate) const MAX_CHUNK_SIZE: u32 = 256;
+
+const NATIVE_MAGIC: &[u8; 4] = b"BVXW";
+
+/// Bump when the layout of native world files changes.
+/// Version 2 added the material table.
+const NATIVE_VERSION: u32 = 2;
+
 #[derive(Clone)]
 pub struct GridHierarchy {
     pub levels: [u32; 8],
     pub texture_size: u32,
-    pub texture_data: Vec<u8>,
+    /// Voxel data of every chunk that is not empty, keyed by chunk coordinate.
+    pub chunks: HashMap<IVec3, Vec<u8>>,
     pub pallete: Pallete,
+    pub materials: [Material; 256],
 }
 
 #[derive(Clone, Deref, DerefMut)]
 pub struct Pallete([[f32; 4]; 256]);
 
+/// Surface properties of a palette index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Material {
+    pub roughness: f32,
+    pub metalness: f32,
+    pub specular: f32,
+    pub transparency: f32,
+    pub ior: f32,
+    pub emission: f32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            roughness: 1.0,
+            metalness: 0.0,
+            specular: 0.0,
+            transparency: 0.0,
+            ior: 1.0,
+            emission: 0.0,
+        }
+    }
+}
+
+impl Material {
+    fn from_vox(vox_material: &dot_vox::Material) -> Self {
+        let mut material = Material::default();
+        match vox_material.material_type() {
+            Some("_metal") | Some("_glass") | Some("_blend") => {
+                material.roughness = vox_material.roughness().unwrap_or(0.1);
+                material.metalness = vox_material.metalness().unwrap_or(0.0);
+                material.specular = vox_material.specular().unwrap_or(0.0);
+                material.transparency = vox_material.transparency().unwrap_or(0.0);
+                // magica voxel stores the index of refraction minus one
+                material.ior = 1.0 + vox_material.refractive_index().unwrap_or(0.3);
+            }
+            Some("_emit") => {
+                let emit = vox_material.emission().unwrap_or(0.0);
+                let flux = vox_material.radiant_flux().unwrap_or(1.0);
+                material.emission = (1.0 + emit).powf(flux);
+            }
+            _ => {}
+        }
+        material
+    }
+
+    fn to_vox(self) -> dot_vox::Dict {
+        let material_type = if self.emission > 0.0 {
+            "_emit"
+        } else if self.transparency > 0.0 {
+            "_glass"
+        } else if self.metalness > 0.0 || self.roughness < 1.0 {
+            "_metal"
+        } else {
+            "_diffuse"
+        };
+
+        let mut properties = dot_vox::Dict::new();
+        properties.insert("_type".to_string(), material_type.to_string());
+        properties.insert("_rough".to_string(), self.roughness.to_string());
+        properties.insert("_metal".to_string(), self.metalness.to_string());
+        properties.insert("_sp".to_string(), self.specular.to_string());
+        properties.insert("_trans".to_string(), self.transparency.to_string());
+        properties.insert("_ior".to_string(), (self.ior - 1.0).to_string());
+        if self.emission > 0.0 {
+            properties.insert("_emit".to_string(), (self.emission - 1.0).max(0.0).to_string());
+            properties.insert("_flux".to_string(), "1".to_string());
+        }
+        properties
+    }
+}
+
+/// A voxel world loaded from a .vox, Qubicle (.qb) or native world file (.bvw).
+#[derive(Asset, TypePath)]
+pub struct VoxWorldAsset(pub(crate) Arc<GridHierarchy>);
+
+impl From<GridHierarchy> for VoxWorldAsset {
+    fn from(gh: GridHierarchy) -> Self {
+        Self(Arc::new(gh))
+    }
+}
+
+/// Splits worlds into chunks of the size in the engine settings.
+pub struct VoxWorldLoader {
+    pub(crate) chunk_size: u32,
+}
+
+impl AssetLoader for VoxWorldLoader {
+    type Asset = VoxWorldAsset;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<VoxWorldAsset, String> {
+        let gh = read_grid_hierarchy(reader, load_context, self.chunk_size).await?;
+        Ok(VoxWorldAsset(Arc::new(gh)))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox", "qb", "bvw"]
+    }
+}
+
+/// Loads the files of [`VoxWorldLoader`] as prefabs, when they are loaded as
+/// a [`VoxelPrefab`].
+#[derive(Default)]
+pub struct VoxelPrefabLoader;
+
+impl AssetLoader for VoxelPrefabLoader {
+    type Asset = VoxelPrefab;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<VoxelPrefab, String> {
+        let gh = read_grid_hierarchy(reader, load_context, MAX_CHUNK_SIZE).await?;
+        Ok(VoxelPrefab::from(&gh))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox", "qb", "bvw"]
+    }
+}
+
+async fn read_grid_hierarchy(
+    reader: &mut Reader<'_>,
+    load_context: &LoadContext<'_>,
+    chunk_size: u32,
+) -> Result<GridHierarchy, String> {
+    let mut file = Vec::new();
+    reader
+        .read_to_end(&mut file)
+        .await
+        .map_err(|e| e.to_string())?;
+    GridHierarchy::from_file(&file, load_context.path(), chunk_size)
+}
+
+/// Reads little endian values from a file.
+struct ByteReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < length {
+            return Err("Unexpected end of file".to_string());
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+}
+
+/// A model placed in a .vox scene, in magica voxel space.
+struct VoxInstance {
+    model: usize,
+    rotation: Mat3,
+    translation: IVec3,
+}
+
 impl GridHierarchy {
     pub fn empty(texture_size: u32) -> Self {
         let mut levels = [0; 8];
@@ -24,8 +247,9 @@ impl GridHierarchy {
         Self {
             levels,
             texture_size,
-            texture_data: vec![0; (texture_size * texture_size * texture_size * 2) as usize],
+            chunks: HashMap::new(),
             pallete: Pallete([[0.0; 4]; 256]),
+            materials: [Material::default(); 256],
         }
     }
 
@@ -51,63 +275,494 @@ impl GridHierarchy {
         Self::get_buffer_size_from_levels(&self.levels)
     }
 
-    pub fn from_vox(file: &[u8]) -> Result<GridHierarchy, String> {
+    pub fn chunk_length(&self) -> usize {
+        (self.texture_size * self.texture_size * self.texture_size * 2) as usize
+    }
+
+    /// Returns the chunk data or zeros if the chunk is empty.
+    pub fn get_chunk(&self, chunk: IVec3) -> Vec<u8> {
+        match self.chunks.get(&chunk) {
+            Some(data) => data.clone(),
+            None => vec![0; self.chunk_length()],
+        }
+    }
+
+    /// Reads any of the world files, native files are recognised by their
+    /// header and Qubicle files by their extension. Everything else is read
+    /// as .vox. Scenes are split into chunks of at most `chunk_size`, native
+    /// files keep the chunks they were saved with.
+    pub fn from_file(file: &[u8], path: &Path, chunk_size: u32) -> Result<GridHierarchy, String> {
+        let is_qb = path.extension().is_some_and(|extension| extension == "qb");
+        if file.starts_with(NATIVE_MAGIC) {
+            Self::from_native(file)
+        } else if is_qb {
+            Self::from_qb(file, chunk_size)
+        } else {
+            Self::from_vox(file, chunk_size)
+        }
+    }
+
+    /// Scenes larger than `chunk_size` are split into chunks of that size.
+    pub fn from_vox(file: &[u8], chunk_size: u32) -> Result<GridHierarchy, String> {
         let vox = dot_vox::load_bytes(file)?;
-        let size = vox.models[0].size;
-        let max_dim = size.x.max(size.y).max(size.z);
-        let dim = Self::next_power_of_2(max_dim as u32) as usize;
 
-        println!("dim: {:?}", dim);
+        // place every model in engine space, magica voxel is z up
+        let mut placed = Vec::new();
+        for instance in Self::vox_instances(&vox) {
+            let model = vox.models.get(instance.model).ok_or_else(|| {
+                format!(
+                    "The scene places model {}, the file has {}",
+                    instance.model,
+                    vox.models.len()
+                )
+            })?;
+            let size = IVec3::new(model.size.x as i32, model.size.y as i32, model.size.z as i32);
+            let pivot = size / 2;
+            for voxel in &model.voxels {
+                let local = IVec3::new(voxel.x as i32, voxel.y as i32, voxel.z as i32) - pivot;
+                let pos = instance.rotation * local.as_vec3();
+                let pos = pos.round().as_ivec3() + instance.translation;
+                placed.push((IVec3::new(-pos.x, pos.z, pos.y), voxel.i, Flags::COLLISION_FLAG));
+            }
+        }
 
-        if dim > 256 {
-            return Err(format!(
-                "Model is too large to fit in the texture. Max dimension is {}",
-                dim
-            ));
+        let mut gh = Self::from_placed(placed, chunk_size)?;
+        gh.set_vox_palette(&vox.palette);
+
+        // material ids start at one like the colour indices in the file
+        for vox_material in &vox.materials {
+            let index = vox_material.id as usize;
+            if (1..=256).contains(&index) {
+                gh.materials[index - 1] = Material::from_vox(vox_material);
+            }
         }
 
-        let mut gh = GridHierarchy::empty(dim as u32);
+        Ok(gh)
+    }
 
-        for i in 0..256 {
-            let colour = vox.palette[i];
-            let mut material = Vec4::new(
+    /// Converts the srgb colours of a .vox palette to linear.
+    fn set_vox_palette(&mut self, palette: &[dot_vox::Color]) {
+        for (i, colour) in palette.iter().take(256).enumerate() {
+            let colour = Vec4::new(
                 colour.r as f32 / 255.0,
                 colour.g as f32 / 255.0,
                 colour.b as f32 / 255.0,
                 0.0,
             );
-            material = material.powf(2.2);
-
-            if let Some(vox_material) = vox.materials.get(i) {
-                let vox_material = vox_material.properties.clone();
-                if vox_material["_type"] == "_emit" {
-                    material *= 1.0 + vox_material["_emit"].parse::<f32>().unwrap();
-                    if vox_material.contains_key("_flux") {
-                        material = material.powf(vox_material["_flux"].parse::<f32>().unwrap());
-                    }
-                    material.w = 1.0;
+            self.pallete[i] = colour.powf(2.2).to_array();
+        }
+    }
+
+    /// Builds a world from voxels in engine space, see [`Self::fitting`].
+    /// Voxels are position, material and flags.
+    fn from_placed(placed: Vec<(IVec3, u8, u8)>, chunk_size: u32) -> Result<GridHierarchy, String> {
+        let min = placed.iter().fold(IVec3::MAX, |min, (pos, _, _)| min.min(*pos));
+        let max = placed.iter().fold(IVec3::MIN, |max, (pos, _, _)| max.max(*pos));
+        let (mut gh, offset) = if placed.is_empty() {
+            Self::fitting(IVec3::ZERO, IVec3::ONE, chunk_size)?
+        } else {
+            Self::fitting(min, max - min + IVec3::ONE, chunk_size)?
+        };
+
+        let chunk_length = gh.chunk_length();
+        let size = IVec3::splat(gh.texture_size as i32);
+        let dim = gh.texture_size as usize;
+        for (pos, material, flags) in placed {
+            let pos = pos + offset;
+            let chunk = pos.div_euclid(size);
+            let pos = pos.rem_euclid(size).as_uvec3();
+            let index = pos.x as usize * dim * dim + pos.y as usize * dim + pos.z as usize;
+
+            let data = gh
+                .chunks
+                .entry(chunk)
+                .or_insert_with(|| vec![0; chunk_length]);
+            data[index * 2] = material;
+            data[index * 2 + 1] = flags;
+        }
+
+        Ok(gh)
+    }
+
+    /// An empty world for a scene of the given extent, and the offset that
+    /// moves the scene into it. Scenes that fit in `chunk_size` get a texture
+    /// that just fits them, larger ones are centered on chunk zero and split
+    /// into chunks of `chunk_size`.
+    fn fitting(
+        min: IVec3,
+        extent: IVec3,
+        chunk_size: u32,
+    ) -> Result<(GridHierarchy, IVec3), String> {
+        if !chunk_size.is_power_of_two() || !(8..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(format!("Invalid chunk size {}", chunk_size));
+        }
+        if extent.max_element() > MAX_VOX_EXTENT {
+            return Err(format!(
+                "Scene is {}x{}x{} voxels. Max dimension is {}",
+                extent.x, extent.y, extent.z, MAX_VOX_EXTENT
+            ));
+        }
+
+        let max_dim = extent.max_element() as u32;
+        let (dim, offset) = if max_dim <= chunk_size {
+            (Self::next_power_of_2(max_dim).max(8), -min)
+        } else {
+            let center = IVec3::splat(chunk_size as i32 / 2);
+            (chunk_size, center - min - extent / 2)
+        };
+        Ok((GridHierarchy::empty(dim), offset))
+    }
+
+    /// Walks the scene graph and returns every visible model with its
+    /// transform. Files without a scene graph place each model at the origin.
+    fn vox_instances(vox: &dot_vox::DotVoxData) -> Vec<VoxInstance> {
+        let mut instances = Vec::new();
+        if vox.scenes.is_empty() {
+            for model in 0..vox.models.len() {
+                instances.push(VoxInstance {
+                    model,
+                    rotation: Mat3::IDENTITY,
+                    translation: IVec3::ZERO,
+                });
+            }
+        } else {
+            Self::walk_vox_scene(vox, 0, Mat3::IDENTITY, IVec3::ZERO, &mut instances);
+        }
+        instances
+    }
+
+    fn walk_vox_scene(
+        vox: &dot_vox::DotVoxData,
+        node: u32,
+        rotation: Mat3,
+        translation: IVec3,
+        instances: &mut Vec<VoxInstance>,
+    ) {
+        let Some(scene_node) = vox.scenes.get(node as usize) else {
+            return;
+        };
+
+        match scene_node {
+            dot_vox::SceneNode::Transform {
+                attributes,
+                frames,
+                child,
+                layer_id,
+            } => {
+                let hidden_layer = vox
+                    .layers
+                    .get(*layer_id as usize)
+                    .is_some_and(|layer| layer.hidden());
+                if hidden_layer || attributes.get("_hidden").is_some_and(|h| h == "1") {
+                    return;
+                }
+
+                let (local_rotation, local_translation) = match frames.first() {
+                    Some(frame) => (
+                        frame.orientation().map_or(Mat3::IDENTITY, |r| {
+                            Mat3::from_cols_array_2d(&r.to_cols_array_2d())
+                        }),
+                        frame
+                            .position()
+                            .map_or(IVec3::ZERO, |p| IVec3::new(p.x, p.y, p.z)),
+                    ),
+                    None => (Mat3::IDENTITY, IVec3::ZERO),
+                };
+
+                let translation =
+                    translation + (rotation * local_translation.as_vec3()).round().as_ivec3();
+                Self::walk_vox_scene(vox, *child, rotation * local_rotation, translation, instances);
+            }
+            dot_vox::SceneNode::Group { children, .. } => {
+                for child in children {
+                    Self::walk_vox_scene(vox, *child, rotation, translation, instances);
                 }
             }
+            dot_vox::SceneNode::Shape { models, .. } => {
+                if let Some(shape_model) = models.first() {
+                    instances.push(VoxInstance {
+                        model: shape_model.model_id as usize,
+                        rotation,
+                        translation,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Writes the world as a .vox file split into models of at most 256³.
+    /// Flags can't be stored in a .vox file so they are dropped, and neither
+    /// can material 255.
+    pub fn to_vox(&self) -> Result<Vec<u8>, String> {
+        // every solid voxel in magica voxel space
+        let size = self.texture_size as i32;
+        let dim = self.texture_size as usize;
+        let mut placed = Vec::new();
+        for (chunk, data) in self.chunks.iter() {
+            for (index, voxel) in data.chunks_exact(2).enumerate() {
+                if voxel[0] == 0 {
+                    continue;
+                }
+                // the file shifts materials up by one to keep zero for air
+                if voxel[0] == u8::MAX {
+                    return Err("Material 255 can't be stored in a .vox file".to_string());
+                }
+                let local = IVec3::new(
+                    (index / (dim * dim)) as i32,
+                    (index / dim % dim) as i32,
+                    (index % dim) as i32,
+                );
+                let pos = *chunk * size + local;
+                placed.push((IVec3::new(-pos.x, pos.z, pos.y), voxel[0]));
+            }
+        }
 
-            gh.pallete[i] = material.to_array();
+        let min = placed.iter().fold(IVec3::MAX, |min, (pos, _)| min.min(*pos));
+        let max = placed.iter().fold(IVec3::MIN, |max, (pos, _)| max.max(*pos));
+        let extent = if placed.is_empty() {
+            IVec3::ONE
+        } else {
+            max - min + IVec3::ONE
+        };
+
+        let region_size = IVec3::splat(MAX_CHUNK_SIZE as i32);
+        let mut regions: HashMap<IVec3, dot_vox::Model> = HashMap::new();
+        for (pos, material) in placed {
+            let pos = pos - min;
+            let region = pos / region_size;
+            let local = pos - region * region_size;
+            let model = regions.entry(region).or_insert_with(|| {
+                let model_size = (extent - region * region_size).min(region_size);
+                dot_vox::Model {
+                    size: dot_vox::Size {
+                        x: model_size.x as u32,
+                        y: model_size.y as u32,
+                        z: model_size.z as u32,
+                    },
+                    voxels: Vec::new(),
+                }
+            });
+            model.voxels.push(dot_vox::Voxel {
+                x: local.x as u8,
+                y: local.y as u8,
+                z: local.z as u8,
+                i: material,
+            });
         }
 
-        for voxel in &vox.models[0].voxels {
-            let pos = IVec3::new(
-                size.x as i32 - 1 - voxel.x as i32,
-                voxel.z as i32,
-                voxel.y as i32,
+        let mut regions: Vec<_> = regions.into_iter().collect();
+        regions.sort_by_key(|(region, _)| (region.z, region.y, region.x));
+
+        // root transform -> group -> a transform and shape per model
+        let mut scenes = vec![
+            dot_vox::SceneNode::Transform {
+                attributes: dot_vox::Dict::new(),
+                frames: vec![dot_vox::Frame::new(dot_vox::Dict::new())],
+                child: 1,
+                layer_id: u32::MAX,
+            },
+            dot_vox::SceneNode::Group {
+                attributes: dot_vox::Dict::new(),
+                children: Vec::new(),
+            },
+        ];
+        let mut children = Vec::new();
+        let mut models = Vec::new();
+        for (model_id, (region, model)) in regions.into_iter().enumerate() {
+            // from_vox places voxels relative to the center of the model
+            let model_size = IVec3::new(model.size.x as i32, model.size.y as i32, model.size.z as i32);
+            let translation = min + region * region_size + model_size / 2;
+
+            let mut frame = dot_vox::Dict::new();
+            frame.insert(
+                "_t".to_string(),
+                format!("{} {} {}", translation.x, translation.y, translation.z),
             );
 
-            let index = pos.x as usize * dim * dim + pos.y as usize * dim + pos.z as usize;
+            children.push(scenes.len() as u32);
+            scenes.push(dot_vox::SceneNode::Transform {
+                attributes: dot_vox::Dict::new(),
+                frames: vec![dot_vox::Frame::new(frame)],
+                child: scenes.len() as u32 + 1,
+                layer_id: 0,
+            });
+            scenes.push(dot_vox::SceneNode::Shape {
+                attributes: dot_vox::Dict::new(),
+                models: vec![dot_vox::ShapeModel {
+                    model_id: model_id as u32,
+                    attributes: dot_vox::Dict::new(),
+                }],
+            });
+            models.push(model);
+        }
+        if let dot_vox::SceneNode::Group {
+            children: group_children,
+            ..
+        } = &mut scenes[1]
+        {
+            *group_children = children;
+        }
+
+        let mut palette = Vec::with_capacity(256);
+        let mut materials = Vec::with_capacity(256);
+        for i in 0..256 {
+            let colour = Vec3::from_slice(&self.pallete[i]);
+            let colour = (colour.powf(1.0 / 2.2) * 255.0).round();
+            palette.push(dot_vox::Color {
+                r: colour.x.clamp(0.0, 255.0) as u8,
+                g: colour.y.clamp(0.0, 255.0) as u8,
+                b: colour.z.clamp(0.0, 255.0) as u8,
+                a: 255,
+            });
+            materials.push(dot_vox::Material {
+                id: i as u32 + 1,
+                properties: self.materials[i].to_vox(),
+            });
+        }
+
+        let vox = dot_vox::DotVoxData {
+            version: 150,
+            index_map: Vec::new(),
+            models,
+            palette,
+            materials,
+            scenes,
+            layers: vec![dot_vox::Layer {
+                attributes: dot_vox::Dict::new(),
+            }],
+        };
+
+        let mut file = Vec::new();
+        vox.write_vox(&mut file).map_err(|e| e.to_string())?;
+        Ok(file)
+    }
+
+    /// Writes the world in the engine's own format. Unlike .vox this keeps
+    /// the flags of every voxel. Each chunk is run length encoded.
+    pub fn to_native(&self) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(NATIVE_MAGIC);
+        file.extend_from_slice(&NATIVE_VERSION.to_le_bytes());
+        file.extend_from_slice(&self.texture_size.to_le_bytes());
+        for entry in self.pallete.iter() {
+            for value in entry {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        for material in self.materials.iter() {
+            for value in [
+                material.roughness,
+                material.metalness,
+                material.specular,
+                material.transparency,
+                material.ior,
+                material.emission,
+            ] {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+
+        let mut chunks: Vec<_> = self.chunks.iter().collect();
+        chunks.sort_by_key(|(chunk, _)| (chunk.z, chunk.y, chunk.x));
+
+        file.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+        for (chunk, data) in chunks {
+            let runs = Self::encode_runs(data);
+            for value in chunk.to_array() {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+            file.extend_from_slice(&(runs.len() as u32 / 6).to_le_bytes());
+            file.extend_from_slice(&runs);
+        }
+        file
+    }
+
+    pub fn from_native(file: &[u8]) -> Result<GridHierarchy, String> {
+        let mut reader = ByteReader { data: file };
+        if reader.bytes(4)? != NATIVE_MAGIC {
+            return Err("Not a native world file".to_string());
+        }
+        let version = reader.u32()?;
+        if version == 0 || version > NATIVE_VERSION {
+            return Err(format!(
+                "World file version {} is not supported, the newest is {}",
+                version, NATIVE_VERSION
+            ));
+        }
+
+        let texture_size = reader.u32()?;
+        if !texture_size.is_power_of_two() || !(8..=MAX_CHUNK_SIZE).contains(&texture_size) {
+            return Err(format!("Invalid chunk size {}", texture_size));
+        }
+
+        let mut gh = GridHierarchy::empty(texture_size);
+        for i in 0..256 {
+            for j in 0..4 {
+                gh.pallete[i][j] = reader.f32()?;
+            }
+        }
+        if version >= 2 {
+            for material in gh.materials.iter_mut() {
+                material.roughness = reader.f32()?;
+                material.metalness = reader.f32()?;
+                material.specular = reader.f32()?;
+                material.transparency = reader.f32()?;
+                material.ior = reader.f32()?;
+                material.emission = reader.f32()?;
+            }
+        }
 
-            gh.texture_data[index as usize * 2] = voxel.i;
-            gh.texture_data[index as usize * 2 + 1] = Flags::COLLISION_FLAG;
+        let chunk_length = gh.chunk_length();
+        let chunk_count = reader.u32()?;
+        for _ in 0..chunk_count {
+            let chunk = IVec3::new(reader.i32()?, reader.i32()?, reader.i32()?);
+            let run_count = reader.u32()? as usize;
+            let runs = reader.bytes(run_count * 6)?;
+            let data = Self::decode_runs(runs, chunk_length)
+                .ok_or_else(|| format!("Chunk {} has the wrong length", chunk))?;
+            gh.chunks.insert(chunk, data);
         }
 
         Ok(gh)
     }
 
+    /// Runs are a u32 length followed by the 16 bit voxel.
+    fn encode_runs(data: &[u8]) -> Vec<u8> {
+        let mut runs = Vec::new();
+        let mut voxels = data.chunks_exact(2);
+        let Some(mut current) = voxels.next() else {
+            return runs;
+        };
+        let mut length = 1u32;
+        for voxel in voxels {
+            if voxel == current {
+                length += 1;
+            } else {
+                runs.extend_from_slice(&length.to_le_bytes());
+                runs.extend_from_slice(current);
+                current = voxel;
+                length = 1;
+            }
+        }
+        runs.extend_from_slice(&length.to_le_bytes());
+        runs.extend_from_slice(current);
+        runs
+    }
+
+    fn decode_runs(runs: &[u8], chunk_length: usize) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(chunk_length);
+        for run in runs.chunks_exact(6) {
+            let length = u32::from_le_bytes(run[0..4].try_into().unwrap()) as usize;
+            if data.len() + length * 2 > chunk_length {
+                return None;
+            }
+            for _ in 0..length {
+                data.extend_from_slice(&run[4..6]);
+            }
+        }
+        (data.len() == chunk_length).then_some(data)
+    }
+
     fn next_power_of_2(number: u32) -> u32 {
         let mut n = number;
 
@@ -127,3 +782,210 @@ impl GridHierarchy {
         n
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Voxels of a world relative to its smallest voxel, sorted.
+    fn voxels(gh: &GridHierarchy) -> Vec<(IVec3, u8, u8)> {
+        let size = gh.texture_size as i32;
+        let mut voxels: Vec<_> = gh
+            .chunks
+            .iter()
+            .flat_map(|(chunk, data)| {
+                data.chunks_exact(2)
+                    .enumerate()
+                    .filter(|(_, voxel)| voxel[0] != 0)
+                    .map(move |(i, voxel)| {
+                        let i = i as i32;
+                        let local = IVec3::new(i / (size * size), i / size % size, i % size);
+                        (*chunk * size + local, voxel[0], voxel[1])
+                    })
+            })
+            .collect();
+        let min = voxels.iter().fold(IVec3::MAX, |min, (pos, _, _)| min.min(*pos));
+        for (pos, _, _) in voxels.iter_mut() {
+            *pos -= min;
+        }
+        voxels.sort_by_key(|(pos, _, _)| pos.to_array());
+        voxels
+    }
+
+    /// Voxels in two chunks that aren't next to each other.
+    fn world(flags: u8) -> GridHierarchy {
+        let mut gh = GridHierarchy::empty(8);
+        let mut set = |chunk: IVec3, local: UVec3, material: u8| {
+            let index = ((local.x * 64 + local.y * 8 + local.z) * 2) as usize;
+            let data = gh.chunks.entry(chunk).or_insert_with(|| vec![0; 1024]);
+            data[index] = material;
+            data[index + 1] = flags;
+        };
+        set(IVec3::ZERO, UVec3::new(1, 2, 3), 5);
+        set(IVec3::ZERO, UVec3::new(7, 7, 7), 6);
+        set(IVec3::new(1, 0, -1), UVec3::new(0, 4, 2), 254);
+        gh.pallete[4] = [1.0, 0.0, 1.0, 0.0];
+        gh.materials[5].roughness = 0.25;
+        gh.materials[5].metalness = 0.5;
+        gh
+    }
+
+    #[test]
+    fn vox_round_trip() {
+        // .vox files can't store flags, every voxel gets the collision flag
+        let gh = world(Flags::COLLISION_FLAG);
+        let loaded = GridHierarchy::from_vox(&gh.to_vox().unwrap(), MAX_CHUNK_SIZE).unwrap();
+
+        assert_eq!(voxels(&loaded), voxels(&gh));
+        assert_eq!(loaded.pallete[4], [1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(loaded.materials[5].roughness, 0.25);
+        assert_eq!(loaded.materials[5].metalness, 0.5);
+
+        // the scene is 14 voxels deep, too much for one chunk of 8
+        let file = gh.to_vox().unwrap();
+        assert_eq!(loaded.texture_size, 16);
+        let split = GridHierarchy::from_vox(&file, 8).unwrap();
+        assert_eq!(split.texture_size, 8);
+        assert_eq!(voxels(&split), voxels(&gh));
+        assert!(GridHierarchy::from_vox(&file, 12).is_err());
+
+        let mut gh = gh;
+        gh.chunks.get_mut(&IVec3::ZERO).unwrap()[0] = 255;
+        assert!(gh.to_vox().is_err());
+    }
+
+    #[test]
+    fn vox_scene_graph_places_models() {
+        let dict = |entries: &[(&str, &str)]| -> dot_vox::Dict {
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        let transform = |attributes, frame, child, layer_id| dot_vox::SceneNode::Transform {
+            attributes,
+            frames: vec![dot_vox::Frame::new(frame)],
+            child,
+            layer_id,
+        };
+        let shape = |model_id| dot_vox::SceneNode::Shape {
+            attributes: dot_vox::Dict::new(),
+            models: vec![dot_vox::ShapeModel {
+                model_id,
+                attributes: dot_vox::Dict::new(),
+            }],
+        };
+        let voxel = |x, i| dot_vox::Voxel { x, y: 0, z: 0, i };
+
+        let mut vox = dot_vox::DotVoxData {
+            version: 150,
+            index_map: Vec::new(),
+            models: vec![
+                dot_vox::Model {
+                    size: dot_vox::Size { x: 3, y: 1, z: 1 },
+                    voxels: vec![voxel(0, 1), voxel(2, 2)],
+                },
+                dot_vox::Model {
+                    size: dot_vox::Size { x: 1, y: 1, z: 1 },
+                    voxels: vec![voxel(0, 3)],
+                },
+            ],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: vec![
+                transform(dict(&[]), dict(&[]), 1, 0),
+                dot_vox::SceneNode::Group {
+                    attributes: dot_vox::Dict::new(),
+                    children: vec![2, 4, 6, 8],
+                },
+                // a quarter turn around z, x goes to y
+                transform(dict(&[]), dict(&[("_r", "17"), ("_t", "10 0 0")]), 3, 0),
+                shape(0),
+                transform(dict(&[]), dict(&[("_t", "50 50 50")]), 5, 1),
+                shape(0),
+                transform(dict(&[("_hidden", "1")]), dict(&[("_t", "-50 0 0")]), 7, 0),
+                shape(1),
+                transform(dict(&[]), dict(&[]), 9, 0),
+                shape(1),
+            ],
+            layers: vec![
+                dot_vox::Layer {
+                    attributes: dict(&[]),
+                },
+                dot_vox::Layer {
+                    attributes: dict(&[("_hidden", "1")]),
+                },
+            ],
+        };
+
+        let instances = GridHierarchy::vox_instances(&vox);
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].model, 0);
+        assert_eq!(instances[0].translation, IVec3::new(10, 0, 0));
+        assert_eq!(instances[0].rotation * Vec3::X, Vec3::Y);
+
+        // the rotated model ends up along y in the file, which is z in the
+        // engine, and 10 voxels from the other one along x
+        let mut file = Vec::new();
+        vox.write_vox(&mut file).unwrap();
+        let gh = GridHierarchy::from_vox(&file, MAX_CHUNK_SIZE).unwrap();
+        let collision = Flags::COLLISION_FLAG;
+        assert_eq!(
+            voxels(&gh),
+            vec![
+                (IVec3::new(0, 0, 0), 1, collision),
+                (IVec3::new(0, 0, 2), 2, collision),
+                (IVec3::new(10, 0, 1), 3, collision),
+            ]
+        );
+
+        // shapes of models that aren't in the file fail to load
+        vox.scenes[9] = shape(2);
+        let mut file = Vec::new();
+        vox.write_vox(&mut file).unwrap();
+        assert!(GridHierarchy::from_vox(&file, MAX_CHUNK_SIZE).is_err());
+    }
+
+    #[test]
+    fn native_round_trip() {
+        let gh = world(Flags::SAND_FLAG | Flags::AUTOMATA_FLAG);
+        let loaded = GridHierarchy::from_native(&gh.to_native()).unwrap();
+
+        assert_eq!(loaded.texture_size, 8);
+        assert_eq!(loaded.chunks, gh.chunks);
+        assert_eq!(loaded.pallete[4], [1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(loaded.materials, gh.materials);
+    }
+
+    #[test]
+    fn native_rejects_truncated_files() {
+        let file = world(Flags::NONE).to_native();
+        for length in 0..file.len() {
+            assert!(GridHierarchy::from_native(&file[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn runs_round_trip() {
+        let mut data = vec![0; 64];
+        data[10] = 3;
+        data[11] = Flags::COLLISION_FLAG;
+        data[62] = 7;
+        let runs = GridHierarchy::encode_runs(&data);
+        assert_eq!(runs.len(), 4 * 6);
+        assert_eq!(GridHierarchy::decode_runs(&runs, data.len()), Some(data));
+
+        // a whole chunk of the largest size is a single run
+        let chunk_length = GridHierarchy::empty(MAX_CHUNK_SIZE).chunk_length();
+        let data = [5, Flags::SAND_FLAG].repeat(chunk_length / 2);
+        let runs = GridHierarchy::encode_runs(&data);
+        assert_eq!(runs.len(), 6);
+        assert_eq!(u32::from_le_bytes(runs[..4].try_into().unwrap()), 256 * 256 * 256);
+        assert_eq!(GridHierarchy::decode_runs(&runs, chunk_length), Some(data));
+
+        // runs that don't fill the chunk, or overflow it
+        assert_eq!(GridHierarchy::decode_runs(&runs[..5], chunk_length), None);
+        assert_eq!(GridHierarchy::decode_runs(&runs, chunk_length - 2), None);
+        assert_eq!(GridHierarchy::decode_runs(&runs, chunk_length + 2), None);
+    }
+}
@@ -0,0 +1,158 @@
+use super::VoxelPrefab;
+use crate::Flags;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+};
+
+/// The models of a .vox file as the frames of an animation, in the order they
+/// are stored. Frames keep the whole box of their model so they line up when
+/// played with [`AnimatedVoxelModel`](crate::AnimatedVoxelModel).
+#[derive(Asset, TypePath, Clone, Debug, Default)]
+pub struct VoxelAnimation {
+    pub frames: Vec<VoxelPrefab>,
+}
+
+impl VoxelAnimation {
+    pub fn from_vox(file: &[u8]) -> Result<Self, String> {
+        let vox = dot_vox::load_bytes(file)?;
+        if vox.models.is_empty() {
+            return Err("The file has no models".to_string());
+        }
+
+        let frames = vox
+            .models
+            .iter()
+            .map(|model| {
+                // magica voxel is z up, like in GridHierarchy::from_vox
+                let size = UVec3::new(model.size.x, model.size.z, model.size.y);
+                // broken files can have voxels outside of their model
+                let voxels = model
+                    .voxels
+                    .iter()
+                    .filter(|voxel| (voxel.x as u32) < model.size.x)
+                    .map(|voxel| {
+                        let pos = UVec3::new(
+                            model.size.x - 1 - voxel.x as u32,
+                            voxel.z as u32,
+                            voxel.y as u32,
+                        );
+                        (pos, voxel.i, Flags::COLLISION_FLAG)
+                    });
+                VoxelPrefab::from_voxels(size, voxels)
+            })
+            .collect();
+        Ok(Self { frames })
+    }
+
+    /// The frame shown after some time at a frame rate, looping.
+    pub fn frame(&self, elapsed: f32, frame_rate: f32) -> Option<&VoxelPrefab> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        let frame = (elapsed * frame_rate).max(0.0) as usize % self.frames.len();
+        Some(&self.frames[frame])
+    }
+}
+
+/// Loads .vox files as a [`VoxelAnimation`], when they are loaded as one.
+#[derive(Default)]
+pub struct VoxelAnimationLoader;
+
+impl AssetLoader for VoxelAnimationLoader {
+    type Asset = VoxelAnimation;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        _load_context: &'a mut LoadContext<'_>,
+    ) -> Result<VoxelAnimation, String> {
+        let mut file = Vec::new();
+        reader
+            .read_to_end(&mut file)
+            .await
+            .map_err(|e| e.to_string())?;
+        VoxelAnimation::from_vox(&file)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Voxel;
+
+    fn model(size: dot_vox::Size, voxels: Vec<dot_vox::Voxel>) -> dot_vox::Model {
+        dot_vox::Model { size, voxels }
+    }
+
+    #[test]
+    fn models_become_frames() {
+        let voxel = |x, y, z, i| dot_vox::Voxel { x, y, z, i };
+        let vox = dot_vox::DotVoxData {
+            version: 150,
+            index_map: Vec::new(),
+            models: vec![
+                model(dot_vox::Size { x: 2, y: 3, z: 4 }, vec![voxel(0, 2, 3, 5)]),
+                model(dot_vox::Size { x: 1, y: 1, z: 1 }, vec![voxel(0, 0, 0, 7)]),
+            ],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+        let mut file = Vec::new();
+        vox.write_vox(&mut file).unwrap();
+
+        let animation = VoxelAnimation::from_vox(&file).unwrap();
+        assert_eq!(animation.frames.len(), 2);
+
+        // z up to y up, x is flipped
+        let first = &animation.frames[0];
+        assert_eq!(first.size(), UVec3::new(2, 4, 3));
+        assert_eq!(
+            first.get_voxel(UVec3::new(1, 3, 2)),
+            Some(Voxel::new(5, Flags::COLLISION_FLAG))
+        );
+
+        assert_eq!(animation.frame(0.4, 2.0).unwrap().size(), UVec3::new(2, 4, 3));
+        assert_eq!(animation.frame(0.6, 2.0).unwrap().size(), UVec3::ONE);
+        assert_eq!(animation.frame(1.1, 2.0).unwrap().size(), UVec3::new(2, 4, 3));
+    }
+
+    #[test]
+    fn voxels_outside_of_their_model_are_left_out() {
+        let voxel = |x, y, z, i| dot_vox::Voxel { x, y, z, i };
+        let vox = dot_vox::DotVoxData {
+            version: 150,
+            index_map: Vec::new(),
+            models: vec![model(
+                dot_vox::Size { x: 2, y: 2, z: 2 },
+                vec![voxel(1, 1, 1, 5), voxel(2, 0, 0, 6), voxel(0, 9, 0, 7)],
+            )],
+            palette: Vec::new(),
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+        let mut file = Vec::new();
+        vox.write_vox(&mut file).unwrap();
+
+        let frame = &VoxelAnimation::from_vox(&file).unwrap().frames[0];
+        let solid: Vec<_> = (0..8)
+            .map(|i| UVec3::new(i / 4, i / 2 % 2, i % 2))
+            .filter_map(|pos| Some((pos, frame.get_voxel(pos)?)))
+            .filter(|(_, voxel)| voxel.material != 0)
+            .collect();
+        assert_eq!(
+            solid,
+            vec![(UVec3::new(0, 1, 1), Voxel::new(5, Flags::COLLISION_FLAG))]
+        );
+    }
+}
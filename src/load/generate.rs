@@ -0,0 +1,362 @@
+use super::GridHierarchy;
+use crate::{material, Flags};
+use bevy::prelude::*;
+
+/// Builds a world from a seed. Generators must be deterministic, the same
+/// seed has to produce the same world every time.
+pub trait WorldGenerator: Send + Sync + 'static {
+    fn generate(&self, seed: u64) -> Result<GridHierarchy, String>;
+
+    /// Builds a single chunk for worlds that stream in around a
+    /// [`ChunkAnchor`](crate::ChunkAnchor). Returns none for empty chunks,
+    /// generators that only build a fixed world can keep the default.
+    fn generate_chunk(&self, _seed: u64, _chunk: IVec3, _chunk_size: u32) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Fractal simplex noise terrain. [`WorldGenerator::generate`] fills the
+/// 3x3x3 active chunks and every other chunk can be streamed in. The surface
+/// lies around the middle of chunk zero so the world origin is close to the
+/// ground. Materials are indices into the default magica voxel palette.
+#[derive(Clone, Debug)]
+pub struct NoiseGenerator {
+    /// Size of every chunk in voxels, a power of two between 8 and 256.
+    pub chunk_size: u32,
+    /// Horizontal size of hills in voxels.
+    pub terrain_scale: f32,
+    /// Height in voxels between the lowest valleys and the highest hills.
+    pub terrain_height: f32,
+    /// Size of caves in voxels.
+    pub cave_scale: f32,
+    /// Noise value above which rock is carved out, higher means fewer caves.
+    pub cave_threshold: f32,
+    /// Size of ore pockets in voxels.
+    pub ore_scale: f32,
+    /// Noise value above which rock turns into ore.
+    pub ore_threshold: f32,
+    pub octaves: u32,
+    pub surface: u8,
+    pub subsurface: u8,
+    pub rock: u8,
+    pub ore: u8,
+    /// Subsurface voxels between the surface and the rock.
+    pub subsurface_depth: u32,
+}
+
+impl Default for NoiseGenerator {
+    fn default() -> Self {
+        Self {
+            chunk_size: 64,
+            terrain_scale: 96.0,
+            terrain_height: 48.0,
+            cave_scale: 24.0,
+            cave_threshold: 0.35,
+            ore_scale: 6.0,
+            ore_threshold: 0.7,
+            octaves: 4,
+            surface: material::GRASS,
+            subsurface: material::DIRT,
+            rock: material::ROCK,
+            ore: 186,
+            subsurface_depth: 3,
+        }
+    }
+}
+
+impl WorldGenerator for NoiseGenerator {
+    fn generate(&self, seed: u64) -> Result<GridHierarchy, String> {
+        let size = self.chunk_size;
+        if !size.is_power_of_two() || !(8..=256).contains(&size) {
+            return Err(format!("Invalid chunk size {}", size));
+        }
+
+        let mut gh = GridHierarchy::empty(size);
+        gh.set_vox_palette(&dot_vox::DEFAULT_PALETTE);
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let chunk = IVec3::new(x, y, z);
+                    if let Some(data) = self.generate_chunk(seed, chunk, size) {
+                        gh.chunks.insert(chunk, data);
+                    }
+                }
+            }
+        }
+
+        Ok(gh)
+    }
+
+    fn generate_chunk(&self, seed: u64, chunk: IVec3, chunk_size: u32) -> Option<Vec<u8>> {
+        // every feature gets its own permutation so they don't line up
+        let terrain = Simplex::new(seed);
+        let caves = Simplex::new(seed.wrapping_add(1));
+        let ores = Simplex::new(seed.wrapping_add(2));
+
+        let size = chunk_size as i32;
+        let min = chunk * size;
+        let subsurface_depth = self.subsurface_depth as i32;
+        let mut data = vec![0; (chunk_size * chunk_size * chunk_size * 2) as usize];
+        let mut empty = true;
+        for x in 0..size {
+            for z in 0..size {
+                let column = Vec3::new((min.x + x) as f32, 0.0, (min.z + z) as f32);
+                let height = terrain.fractal(column / self.terrain_scale, self.octaves)
+                    * self.terrain_height
+                    * 0.5;
+                // the surface is always placed relative to chunk zero
+                let top = self.chunk_size as i32 / 2 + height.round() as i32;
+
+                for y in 0..size.min(top - min.y + 1) {
+                    let pos = min + IVec3::new(x, y, z);
+                    let depth = top - pos.y;
+                    let material = if depth == 0 {
+                        self.surface
+                    } else if depth <= subsurface_depth {
+                        self.subsurface
+                    } else {
+                        // caves stay below the subsurface so they never open
+                        // up a hole in the grass
+                        let p = pos.as_vec3();
+                        if caves.fractal(p / self.cave_scale, self.octaves) > self.cave_threshold {
+                            continue;
+                        }
+                        if ores.noise(p / self.ore_scale) > self.ore_threshold {
+                            self.ore
+                        } else {
+                            self.rock
+                        }
+                    };
+
+                    let index = (x * size * size + y * size + z) as usize;
+                    data[index * 2] = material;
+                    data[index * 2 + 1] = Flags::COLLISION_FLAG;
+                    empty = false;
+                }
+            }
+        }
+
+        (!empty).then_some(data)
+    }
+}
+
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+/// Seeded 3d simplex noise.
+struct Simplex {
+    perm: [u8; 512],
+}
+
+impl Simplex {
+    fn new(seed: u64) -> Self {
+        // splitmix64, so the permutation doesn't depend on the rand version
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        };
+
+        let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+        for i in (1..256).rev() {
+            let j = (next() % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        Self {
+            perm: std::array::from_fn(|i| table[i & 255]),
+        }
+    }
+
+    /// Returns a value between roughly -1 and 1.
+    fn noise(&self, p: Vec3) -> f32 {
+        const F3: f32 = 1.0 / 3.0;
+        const G3: f32 = 1.0 / 6.0;
+
+        // skew into the simplex grid to find the cell
+        let s = (p.x + p.y + p.z) * F3;
+        let cell = (p + s).floor();
+        let t = (cell.x + cell.y + cell.z) * G3;
+        let p0 = p - (cell - t);
+
+        // the other corners of the tetrahedron the point is in
+        let (o1, o2) = if p0.x >= p0.y {
+            if p0.y >= p0.z {
+                (IVec3::X, IVec3::new(1, 1, 0))
+            } else if p0.x >= p0.z {
+                (IVec3::X, IVec3::new(1, 0, 1))
+            } else {
+                (IVec3::Z, IVec3::new(1, 0, 1))
+            }
+        } else if p0.y < p0.z {
+            (IVec3::Z, IVec3::new(0, 1, 1))
+        } else if p0.x < p0.z {
+            (IVec3::Y, IVec3::new(0, 1, 1))
+        } else {
+            (IVec3::Y, IVec3::new(1, 1, 0))
+        };
+
+        let corners = [
+            (IVec3::ZERO, p0),
+            (o1, p0 - o1.as_vec3() + G3),
+            (o2, p0 - o2.as_vec3() + 2.0 * G3),
+            (IVec3::ONE, p0 - 1.0 + 3.0 * G3),
+        ];
+
+        let cell = cell.as_ivec3() & IVec3::splat(255);
+        let mut total = 0.0;
+        for (offset, d) in corners {
+            let t = 0.6 - d.length_squared();
+            if t <= 0.0 {
+                continue;
+            }
+            let c = cell + offset;
+            let hash = self.perm[c.x as usize
+                + self.perm[c.y as usize + self.perm[c.z as usize] as usize] as usize];
+            let gradient = Vec3::from_array(GRADIENTS[hash as usize % 12]);
+            total += t * t * t * t * gradient.dot(d);
+        }
+        32.0 * total
+    }
+
+    /// Sums octaves of noise, each at double the frequency and half the
+    /// amplitude of the last. Scaled back to roughly -1 to 1.
+    fn fractal(&self, p: Vec3, octaves: u32) -> f32 {
+        let mut total = 0.0;
+        let mut amplitude = 1.0;
+        let mut max = 0.0;
+        for octave in 0..octaves.max(1) {
+            total += amplitude * self.noise(p * (1 << octave) as f32);
+            max += amplitude;
+            amplitude *= 0.5;
+        }
+        total / max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small() -> NoiseGenerator {
+        NoiseGenerator {
+            chunk_size: 16,
+            terrain_scale: 24.0,
+            terrain_height: 8.0,
+            cave_scale: 6.0,
+            cave_threshold: 0.2,
+            ore_scale: 3.0,
+            ore_threshold: 0.4,
+            ..default()
+        }
+    }
+
+    fn voxels(gh: &GridHierarchy) -> impl Iterator<Item = (IVec3, u8, u8)> + '_ {
+        let size = gh.texture_size as i32;
+        gh.chunks.iter().flat_map(move |(chunk, data)| {
+            data.chunks_exact(2).enumerate().map(move |(i, voxel)| {
+                let i = i as i32;
+                let local = IVec3::new(i / (size * size), i / size % size, i % size);
+                (*chunk * size + local, voxel[0], voxel[1])
+            })
+        })
+    }
+
+    #[test]
+    fn same_seed_same_world() {
+        let a = small().generate(7).unwrap();
+        let b = small().generate(7).unwrap();
+        let c = small().generate(8).unwrap();
+        assert_eq!(a.chunks, b.chunks);
+        assert_ne!(a.chunks, c.chunks);
+    }
+
+    #[test]
+    fn fits_the_active_chunks() {
+        let gh = small().generate(1).unwrap();
+        assert_eq!(gh.texture_size, 16);
+        assert!(gh.chunks.keys().all(|chunk| chunk.abs().max_element() <= 1));
+        assert!(gh.chunks.contains_key(&IVec3::ZERO));
+    }
+
+    #[test]
+    fn grass_on_top_of_every_column() {
+        let generator = small();
+        let gh = generator.generate(3).unwrap();
+
+        let mut tops = bevy::utils::HashMap::new();
+        for (pos, material, flags) in voxels(&gh) {
+            if material == 0 {
+                continue;
+            }
+            assert_eq!(flags, Flags::COLLISION_FLAG);
+            let top = tops.entry((pos.x, pos.z)).or_insert((pos.y, material));
+            if pos.y > top.0 {
+                *top = (pos.y, material);
+            }
+        }
+
+        assert_eq!(tops.len(), 48 * 48);
+        assert!(tops.values().all(|(_, material)| *material == generator.surface));
+    }
+
+    #[test]
+    fn caves_and_ores() {
+        let generator = small();
+        let gh = generator.generate(5).unwrap();
+
+        let mut ore = 0;
+        let mut rock = 0;
+        let mut empty_underground = 0;
+        for (pos, material, _) in voxels(&gh) {
+            if material == generator.ore {
+                ore += 1;
+            } else if material == generator.rock {
+                rock += 1;
+            } else if material == 0 && pos.y < 0 {
+                empty_underground += 1;
+            }
+        }
+
+        assert!(ore > 0);
+        assert!(rock > ore);
+        assert!(empty_underground > 0);
+    }
+
+    #[test]
+    fn streamed_chunks_match_the_world() {
+        let generator = small();
+        let gh = generator.generate(9).unwrap();
+        for (chunk, data) in &gh.chunks {
+            assert_eq!(generator.generate_chunk(9, *chunk, 16).as_ref(), Some(data));
+        }
+
+        // far above the surface is air, far below is solid
+        assert!(generator.generate_chunk(9, IVec3::new(5, 10, -3), 16).is_none());
+        assert!(generator.generate_chunk(9, IVec3::new(5, -10, -3), 16).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_size() {
+        let generator = NoiseGenerator {
+            chunk_size: 100,
+            ..default()
+        };
+        assert!(generator.generate(0).is_err());
+    }
+}
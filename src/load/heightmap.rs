@@ -0,0 +1,240 @@
+use super::GridHierarchy;
+use crate::{material, Flags};
+use bevy::{prelude::*, render::render_resource::TextureFormat};
+
+/// How [`GridHierarchy::from_heightmap`] turns pixels into voxel columns.
+/// Materials are indices into the default magica voxel palette the terrain is
+/// created with.
+#[derive(Clone, Debug)]
+pub struct HeightmapOptions {
+    /// Height in voxels of a white pixel.
+    pub vertical_scale: f32,
+    /// Columns that end below sea level get subsurface on top instead of
+    /// surface, and are filled with water up to it when `water` is set.
+    pub sea_level: u32,
+    pub surface: u8,
+    pub subsurface: u8,
+    pub rock: u8,
+    pub water: Option<u8>,
+    /// Subsurface voxels between the surface and the rock.
+    pub subsurface_depth: u32,
+}
+
+impl Default for HeightmapOptions {
+    fn default() -> Self {
+        Self {
+            vertical_scale: 64.0,
+            sea_level: 0,
+            surface: material::GRASS,
+            subsurface: material::DIRT,
+            rock: material::ROCK,
+            water: None,
+            subsurface_depth: 3,
+        }
+    }
+}
+
+impl GridHierarchy {
+    /// Builds terrain with one voxel column per pixel. Only the first channel
+    /// of the image is used, so any grayscale png works. Terrain larger than
+    /// `chunk_size` is split into chunks of that size.
+    pub fn from_heightmap(
+        image: &Image,
+        options: &HeightmapOptions,
+        chunk_size: u32,
+    ) -> Result<GridHierarchy, String> {
+        let format = image.texture_descriptor.format;
+        let sample: fn(&[u8]) -> f32 = match format {
+            TextureFormat::R8Unorm
+            | TextureFormat::Rg8Unorm
+            | TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb => |pixel| pixel[0] as f32 / 255.0,
+            TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb => {
+                |pixel| pixel[2] as f32 / 255.0
+            }
+            TextureFormat::R16Uint
+            | TextureFormat::R16Unorm
+            | TextureFormat::Rg16Uint
+            | TextureFormat::Rg16Unorm
+            | TextureFormat::Rgba16Uint
+            | TextureFormat::Rgba16Unorm => {
+                |pixel| u16::from_le_bytes([pixel[0], pixel[1]]) as f32 / 65535.0
+            }
+            TextureFormat::R32Float | TextureFormat::Rg32Float | TextureFormat::Rgba32Float => {
+                |pixel| f32::from_le_bytes([pixel[0], pixel[1], pixel[2], pixel[3]])
+            }
+            format => return Err(format!("Heightmap format {:?} is not supported", format)),
+        };
+
+        let pixel_size = format.block_copy_size(None).unwrap() as usize;
+        let (width, depth) = (image.width() as usize, image.height() as usize);
+        if image.data.len() < width * depth * pixel_size {
+            return Err("Heightmap is missing pixel data".to_string());
+        }
+
+        let tops: Vec<i32> = (0..width * depth)
+            .map(|i| {
+                let pixel = &image.data[i * pixel_size..];
+                (sample(pixel).clamp(0.0, 1.0) * options.vertical_scale).round() as i32
+            })
+            .collect();
+
+        let sea_level = options.sea_level as i32;
+        let water_level = options.water.map_or(0, |_| sea_level);
+        let height = tops
+            .iter()
+            .fold(water_level, |height, top| height.max(top + 1));
+        let extent = IVec3::new(width as i32, height, depth as i32).max(IVec3::ONE);
+        let (mut gh, offset) = Self::fitting(IVec3::ZERO, extent, chunk_size)?;
+
+        // columns are written chunk by chunk straight into the chunks
+        let size = gh.texture_size as i32;
+        let chunk_length = gh.chunk_length();
+        let subsurface_depth = options.subsurface_depth as i32;
+        for (i, top) in tops.into_iter().enumerate() {
+            // grass doesn't grow under water
+            let surface = if top < sea_level {
+                options.subsurface
+            } else {
+                options.surface
+            };
+            let voxel = |y: i32| {
+                if y > top {
+                    // only below sea level with water
+                    (options.water.unwrap_or(0), Flags::NONE)
+                } else if y == top {
+                    (surface, Flags::COLLISION_FLAG)
+                } else if y >= top - subsurface_depth {
+                    (options.subsurface, Flags::COLLISION_FLAG)
+                } else {
+                    (options.rock, Flags::COLLISION_FLAG)
+                }
+            };
+
+            let column = IVec3::new((i % width) as i32, 0, (i / width) as i32) + offset;
+            let column_height = (top + 1).max(water_level);
+            let mut start = 0;
+            while start < column_height {
+                let pos = column + IVec3::Y * start;
+                let chunk = pos.div_euclid(IVec3::splat(size));
+                let local = pos.rem_euclid(IVec3::splat(size));
+                let end = column_height.min(start + size - local.y);
+                let data = gh
+                    .chunks
+                    .entry(chunk)
+                    .or_insert_with(|| vec![0; chunk_length]);
+                for y in start..end {
+                    let index = ((local.x * size + local.y + y - start) * size + local.z) as usize;
+                    (data[index * 2], data[index * 2 + 1]) = voxel(y);
+                }
+                start = end;
+            }
+        }
+
+        gh.set_vox_palette(&dot_vox::DEFAULT_PALETTE);
+        Ok(gh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::MAX_CHUNK_SIZE;
+    use bevy::render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureDimension},
+    };
+
+    /// A 2x2 grayscale heightmap, rows are z.
+    fn heightmap(pixels: [u8; 4]) -> Image {
+        Image::new(
+            Extent3d {
+                width: 2,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            pixels.to_vec(),
+            TextureFormat::R8Unorm,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    /// Material and flags of the voxels of a column, from the bottom up.
+    fn column(gh: &GridHierarchy, x: u32, z: u32) -> Vec<(u8, u8)> {
+        let size = gh.texture_size;
+        let data = &gh.chunks[&IVec3::ZERO];
+        (0..size)
+            .map(|y| (x * size * size + y * size + z) as usize * 2)
+            .map(|i| (data[i], data[i + 1]))
+            .take_while(|(material, _)| *material != 0)
+            .collect()
+    }
+
+    #[test]
+    fn columns_follow_the_heightmap() {
+        let options = HeightmapOptions {
+            vertical_scale: 4.0,
+            ..default()
+        };
+        let gh = GridHierarchy::from_heightmap(&heightmap([0, 255, 128, 64]), &options, 8).unwrap();
+
+        let heights: Vec<_> = [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .into_iter()
+            .map(|(x, z)| column(&gh, x, z).len())
+            .collect();
+        assert_eq!(heights, vec![1, 5, 3, 2]);
+
+        let solid = Flags::COLLISION_FLAG;
+        assert_eq!(
+            column(&gh, 1, 0),
+            vec![
+                (material::ROCK, solid),
+                (material::DIRT, solid),
+                (material::DIRT, solid),
+                (material::DIRT, solid),
+                (material::GRASS, solid),
+            ]
+        );
+        assert_eq!(column(&gh, 0, 0), vec![(material::GRASS, solid)]);
+    }
+
+    #[test]
+    fn low_columns_fill_with_water() {
+        let options = HeightmapOptions {
+            vertical_scale: 4.0,
+            sea_level: 3,
+            water: Some(material::WATER),
+            ..default()
+        };
+        let gh = GridHierarchy::from_heightmap(&heightmap([0, 255, 128, 64]), &options, 8).unwrap();
+
+        let (solid, water) = (Flags::COLLISION_FLAG, (material::WATER, Flags::NONE));
+        assert_eq!(
+            column(&gh, 0, 0),
+            vec![(material::DIRT, solid), water, water]
+        );
+        assert_eq!(column(&gh, 1, 0).last(), Some(&(material::GRASS, solid)));
+    }
+
+    #[test]
+    fn tall_columns_are_split_into_chunks() {
+        let options = HeightmapOptions {
+            vertical_scale: 400.0,
+            ..default()
+        };
+        for chunk_size in [64, MAX_CHUNK_SIZE] {
+            let image = heightmap([0, 255, 128, 64]);
+            let gh = GridHierarchy::from_heightmap(&image, &options, chunk_size).unwrap();
+            assert_eq!(gh.texture_size, chunk_size);
+            assert!(gh.chunks.len() > 1);
+
+            let voxels: usize = gh
+                .chunks
+                .values()
+                .map(|data| data.chunks_exact(2).filter(|voxel| voxel[0] != 0).count())
+                .sum();
+            assert_eq!(voxels, 1 + 401 + 202 + 101);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use super::Pallete;
+use bevy::{
+    asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext},
+    prelude::*,
+    render::{
+        render_asset::RenderAssetUsages,
+        texture::{CompressedImageFormats, ImageSampler, ImageType},
+    },
+};
+
+/// 256 linear colours, indexed by material. Make it the active palette with
+/// [`ActivePalette`](crate::ActivePalette) to recolour the world without
+/// touching the voxels.
+#[derive(Asset, TypePath, Clone)]
+pub struct Palette(pub(crate) Pallete);
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self(Pallete([[0.0; 4]; 256]))
+    }
+}
+
+impl Palette {
+    /// Builds a palette from srgb colours, slots past the last colour are black.
+    pub fn from_srgb(colours: &[[u8; 3]]) -> Self {
+        let mut palette = Self::default();
+        for (i, colour) in colours.iter().take(256).enumerate() {
+            let colour = Vec4::new(
+                colour[0] as f32 / 255.0,
+                colour[1] as f32 / 255.0,
+                colour[2] as f32 / 255.0,
+                0.0,
+            );
+            palette.0[i] = colour.powf(2.2).to_array();
+        }
+        palette
+    }
+
+    pub fn get(&self, material: u8) -> LinearRgba {
+        let [red, green, blue, _] = self.0[material as usize];
+        LinearRgba::rgb(red, green, blue)
+    }
+
+    pub fn set(&mut self, material: u8, colour: impl Into<LinearRgba>) {
+        let colour: LinearRgba = colour.into();
+        self.0[material as usize] = [colour.red, colour.green, colour.blue, 0.0];
+    }
+
+    /// Uses the palette of a .vox file.
+    pub fn from_vox(file: &[u8]) -> Result<Self, String> {
+        let vox = dot_vox::load_bytes(file)?;
+        let colours: Vec<_> = vox.palette.iter().map(|c| [c.r, c.g, c.b]).collect();
+        Ok(Self::from_srgb(&colours))
+    }
+
+    /// Uses the first row of an image, one pixel per material like the
+    /// palette images magica voxel exports.
+    pub fn from_png(file: &[u8]) -> Result<Self, String> {
+        let image = Image::from_buffer(
+            file,
+            ImageType::Extension("png"),
+            CompressedImageFormats::NONE,
+            true,
+            ImageSampler::Default,
+            RenderAssetUsages::default(),
+        )
+        .map_err(|e| e.to_string())?;
+        let image = image
+            .try_into_dynamic()
+            .map_err(|e| e.to_string())?
+            .to_rgba8();
+
+        let colours: Vec<_> = image
+            .as_raw()
+            .chunks_exact(4)
+            .take(image.width() as usize)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect();
+        Ok(Self::from_srgb(&colours))
+    }
+
+    /// Reads a GIMP palette. Every line after the header is a colour, comments
+    /// and the name and column lines are skipped.
+    pub fn from_gpl(file: &str) -> Result<Self, String> {
+        let mut lines = file.lines();
+        if lines.next().map(str::trim) != Some("GIMP Palette") {
+            return Err("Missing GIMP Palette header".to_string());
+        }
+
+        let mut colours = Vec::new();
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("Name:")
+                || line.starts_with("Columns:")
+            {
+                continue;
+            }
+
+            let mut channels = line.split_whitespace().map(str::parse::<u8>);
+            match (channels.next(), channels.next(), channels.next()) {
+                (Some(Ok(r)), Some(Ok(g)), Some(Ok(b))) => colours.push([r, g, b]),
+                _ => return Err(format!("Invalid palette line \"{}\"", line)),
+            }
+        }
+        Ok(Self::from_srgb(&colours))
+    }
+}
+
+/// Loads .gpl files, and the palettes of .vox files and magica voxel palette
+/// images as `.palette.vox` and `.palette.png`. Other .vox and .png files are
+/// left to the world and image loaders, unless they are loaded as a
+/// [`Palette`].
+#[derive(Default)]
+pub struct PaletteLoader;
+
+impl AssetLoader for PaletteLoader {
+    type Asset = Palette;
+    type Settings = ();
+    type Error = String;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Palette, String> {
+        let mut file = Vec::new();
+        reader
+            .read_to_end(&mut file)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let extension = load_context
+            .path()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        match extension {
+            "png" => Palette::from_png(&file),
+            "gpl" => Palette::from_gpl(&String::from_utf8_lossy(&file)),
+            _ => Palette::from_vox(&file),
+        }
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["palette.vox", "palette.png", "gpl"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+    const COLOURS: [[u8; 3]; 3] = [[255, 0, 0], [0, 128, 255], [10, 20, 30]];
+
+    #[test]
+    fn gimp_palettes_skip_everything_but_colours() {
+        let file = "GIMP Palette\n\
+            Name: Test\n\
+            Columns: 4\n\
+            # a comment\n\
+            255   0   0\tRed\n  \
+            0 128 255 Sky blue\n\
+            \n\
+            10 20 30\n";
+        let palette = Palette::from_gpl(file).unwrap();
+        assert_eq!(palette.0 .0, Palette::from_srgb(&COLOURS).0 .0);
+        assert_eq!(palette.get(3), LinearRgba::BLACK);
+    }
+
+    #[test]
+    fn broken_gimp_palettes_fail() {
+        assert!(Palette::from_gpl("255 0 0\n").is_err());
+        assert!(Palette::from_gpl("GIMP Palette\n255 0\n").is_err());
+        assert!(Palette::from_gpl("GIMP Palette\n256 0 0\n").is_err());
+        assert!(Palette::from_gpl("GIMP Palette\nred green blue\n").is_err());
+    }
+
+    #[test]
+    fn vox_palettes_are_read() {
+        let mut palette = vec![
+            dot_vox::Color {
+                r: 0,
+                g: 0,
+                b: 0,
+                a: 255
+            };
+            256
+        ];
+        for (colour, [r, g, b]) in palette.iter_mut().zip(COLOURS) {
+            *colour = dot_vox::Color { r, g, b, a: 255 };
+        }
+        let vox = dot_vox::DotVoxData {
+            version: 150,
+            index_map: Vec::new(),
+            models: vec![dot_vox::Model {
+                size: dot_vox::Size { x: 1, y: 1, z: 1 },
+                voxels: Vec::new(),
+            }],
+            palette,
+            materials: Vec::new(),
+            scenes: Vec::new(),
+            layers: Vec::new(),
+        };
+        let mut file = Vec::new();
+        vox.write_vox(&mut file).unwrap();
+
+        let palette = Palette::from_vox(&file).unwrap();
+        assert_eq!(palette.0 .0, Palette::from_srgb(&COLOURS).0 .0);
+    }
+
+    #[test]
+    fn png_palettes_use_the_first_row() {
+        // the second row is left out
+        let mut data: Vec<u8> = COLOURS
+            .iter()
+            .flat_map(|[r, g, b]| [*r, *g, *b, 255])
+            .collect();
+        data.extend_from_slice(&[99; 12]);
+        let image = Image::new(
+            Extent3d {
+                width: 3,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8UnormSrgb,
+            RenderAssetUsages::default(),
+        );
+        let path = std::env::temp_dir().join(format!(
+            "bevy_voxel_engine_palette_{}.png",
+            std::process::id()
+        ));
+        image.try_into_dynamic().unwrap().save(&path).unwrap();
+        let file = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let palette = Palette::from_png(&file.unwrap()).unwrap();
+        assert_eq!(palette.0 .0, Palette::from_srgb(&COLOURS).0 .0);
+    }
+}
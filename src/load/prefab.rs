@@ -0,0 +1,217 @@
+use super::GridHierarchy;
+use crate::Voxel;
+use bevy::prelude::*;
+
+/// How the voxels of a stamped [`VoxelPrefab`] are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StampMode {
+    /// Only the voxels the prefab sets are written.
+    #[default]
+    Merge,
+    /// The whole box of the prefab is written, its air clears the world.
+    Overwrite,
+}
+
+/// Orientation of a stamped [`VoxelPrefab`]. It is mirrored first, then
+/// turned around x, y and z in that order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrefabStamp {
+    /// Quarter turns around each axis.
+    pub quarter_turns: UVec3,
+    pub mirror: BVec3,
+    pub mode: StampMode,
+}
+
+/// The voxels of a model cropped to the ones that are set, to be stamped into
+/// the world. Loaded from the same files as [`VoxWorldAsset`](super::VoxWorldAsset)
+/// or built from a [`GridHierarchy`]. The materials are used as they are, the
+/// palette of the world colours them.
+#[derive(Asset, TypePath, Clone, Debug, Default)]
+pub struct VoxelPrefab {
+    size: UVec3,
+    /// Material and flags of every voxel, z is the innermost axis.
+    data: Vec<u8>,
+}
+
+impl From<&GridHierarchy> for VoxelPrefab {
+    fn from(gh: &GridHierarchy) -> Self {
+        let chunk_size = gh.texture_size as i32;
+        let voxels = || {
+            gh.chunks.iter().flat_map(move |(chunk, data)| {
+                data.chunks_exact(2)
+                    .enumerate()
+                    .filter(|(_, voxel)| voxel[0] != 0)
+                    .map(move |(i, voxel)| {
+                        let i = i as i32;
+                        let local = IVec3::new(
+                            i / (chunk_size * chunk_size),
+                            i / chunk_size % chunk_size,
+                            i % chunk_size,
+                        );
+                        (*chunk * chunk_size + local, Voxel::new(voxel[0], voxel[1]))
+                    })
+            })
+        };
+
+        let (min, max) = voxels().fold((IVec3::MAX, IVec3::MIN), |(min, max), (pos, _)| {
+            (min.min(pos), max.max(pos))
+        });
+        if min.cmpgt(max).any() {
+            return Self::default();
+        }
+
+        let size = (max - min + IVec3::ONE).as_uvec3();
+        Self::from_voxels(
+            size,
+            voxels().map(|(pos, voxel)| ((pos - min).as_uvec3(), voxel.material, voxel.flags)),
+        )
+    }
+}
+
+impl VoxelPrefab {
+    /// A prefab of the given size with voxels placed into it, they are
+    /// position, material and flags. Voxels outside of the prefab are left out.
+    pub(crate) fn from_voxels(size: UVec3, voxels: impl Iterator<Item = (UVec3, u8, u8)>) -> Self {
+        let mut prefab = Self {
+            size,
+            data: vec![0; size.element_product() as usize * 2],
+        };
+        for (pos, material, flags) in voxels {
+            if pos.cmpge(size).any() {
+                continue;
+            }
+            let index = prefab.index(pos) * 2;
+            prefab.data[index] = material;
+            prefab.data[index + 1] = flags;
+        }
+        prefab
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    fn index(&self, pos: UVec3) -> usize {
+        ((pos.x * self.size.y + pos.y) * self.size.z + pos.z) as usize
+    }
+
+    pub fn get_voxel(&self, pos: UVec3) -> Option<Voxel> {
+        if pos.cmpge(self.size).any() {
+            return None;
+        }
+        let index = self.index(pos) * 2;
+        Some(Voxel::new(self.data[index], self.data[index + 1]))
+    }
+
+    /// Size of the box the prefab fills once stamped.
+    pub fn stamped_size(&self, stamp: &PrefabStamp) -> UVec3 {
+        rotate(self.size.as_ivec3(), stamp.quarter_turns)
+            .abs()
+            .as_uvec3()
+    }
+
+    /// Every voxel of the prefab, air included, with its position in the
+    /// stamped box.
+    pub fn stamped_voxels<'a>(
+        &'a self,
+        stamp: &'a PrefabStamp,
+    ) -> impl Iterator<Item = (UVec3, Voxel)> + 'a {
+        let size = self.size.as_ivec3();
+        // turning around the origin moves the box to the negative side
+        let offset = (-rotate(size - IVec3::ONE, stamp.quarter_turns)).max(IVec3::ZERO);
+        self.data.chunks_exact(2).enumerate().map(move |(i, voxel)| {
+            let i = i as i32;
+            let pos = IVec3::new(i / (size.y * size.z), i / size.z % size.y, i % size.z);
+            let mirrored = IVec3::select(stamp.mirror, size - IVec3::ONE - pos, pos);
+            let stamped = rotate(mirrored, stamp.quarter_turns) + offset;
+            (stamped.as_uvec3(), Voxel::new(voxel[0], voxel[1]))
+        })
+    }
+}
+
+fn rotate(mut pos: IVec3, quarter_turns: UVec3) -> IVec3 {
+    for _ in 0..quarter_turns.x % 4 {
+        pos = IVec3::new(pos.x, -pos.z, pos.y);
+    }
+    for _ in 0..quarter_turns.y % 4 {
+        pos = IVec3::new(pos.z, pos.y, -pos.x);
+    }
+    for _ in 0..quarter_turns.z % 4 {
+        pos = IVec3::new(-pos.y, pos.x, pos.z);
+    }
+    pos
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::utils::HashMap;
+
+    /// A 3x2x1 prefab with a different material in every voxel.
+    fn prefab() -> VoxelPrefab {
+        let mut gh = GridHierarchy::empty(8);
+        let mut data = vec![0; gh.chunk_length()];
+        for x in 0..3 {
+            for y in 0..2 {
+                // offset from the corner, the prefab is cropped
+                let index = ((x + 2) * 64 + (y + 1) * 8 + 5) * 2;
+                data[index] = (1 + x * 2 + y) as u8;
+                data[index + 1] = 16;
+            }
+        }
+        gh.chunks = HashMap::from([(IVec3::new(-1, 0, 0), data)]);
+        VoxelPrefab::from(&gh)
+    }
+
+    fn stamped(prefab: &VoxelPrefab, stamp: PrefabStamp) -> HashMap<UVec3, u8> {
+        let size = prefab.stamped_size(&stamp);
+        prefab
+            .stamped_voxels(&stamp)
+            .inspect(|(pos, _)| assert!(pos.cmplt(size).all()))
+            .map(|(pos, voxel)| (pos, voxel.material))
+            .collect()
+    }
+
+    #[test]
+    fn prefab_is_cropped() {
+        let prefab = prefab();
+        assert_eq!(prefab.size(), UVec3::new(3, 2, 1));
+        assert_eq!(prefab.get_voxel(UVec3::new(2, 1, 0)), Some(Voxel::new(6, 16)));
+        assert_eq!(prefab.get_voxel(UVec3::new(3, 0, 0)), None);
+        assert_eq!(VoxelPrefab::from(&GridHierarchy::empty(8)).size(), UVec3::ZERO);
+    }
+
+    #[test]
+    fn stamp_turns_and_mirrors() {
+        let prefab = prefab();
+
+        let voxels = stamped(&prefab, PrefabStamp::default());
+        assert_eq!(voxels[&UVec3::new(2, 1, 0)], 6);
+
+        // a quarter turn around y takes x to -z
+        let stamp = PrefabStamp {
+            quarter_turns: UVec3::Y,
+            ..default()
+        };
+        assert_eq!(prefab.stamped_size(&stamp), UVec3::new(1, 2, 3));
+        let voxels = stamped(&prefab, stamp);
+        assert_eq!(voxels.len(), 6);
+        assert_eq!(voxels[&UVec3::new(0, 0, 2)], 1);
+        assert_eq!(voxels[&UVec3::new(0, 1, 0)], 6);
+
+        let stamp = PrefabStamp {
+            mirror: BVec3::new(true, false, false),
+            ..default()
+        };
+        let voxels = stamped(&prefab, stamp);
+        assert_eq!(voxels[&UVec3::new(0, 1, 0)], 6);
+        assert_eq!(voxels[&UVec3::new(2, 0, 0)], 1);
+
+        // four turns are none
+        let stamp = PrefabStamp {
+            quarter_turns: UVec3::new(4, 4, 4),
+            ..default()
+        };
+        assert_eq!(stamped(&prefab, stamp), stamped(&prefab, PrefabStamp::default()));
+    }
+}
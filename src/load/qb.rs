@@ -0,0 +1,308 @@
+use super::{ByteReader, GridHierarchy, MAX_VOX_EXTENT};
+use crate::Flags;
+use bevy::{prelude::*, utils::HashMap};
+
+/// Followed by a count and a colour that is repeated count times.
+const CODE_FLAG: u32 = 2;
+/// Ends a z slice of a compressed matrix.
+const NEXT_SLICE_FLAG: u32 = 6;
+
+impl GridHierarchy {
+    /// Loads a Qubicle .qb file. Every matrix is placed at its stored
+    /// position and the colours are quantised into the palette. Scenes larger
+    /// than `chunk_size` are split into chunks of that size.
+    pub fn from_qb(file: &[u8], chunk_size: u32) -> Result<GridHierarchy, String> {
+        let mut reader = ByteReader { data: file };
+        let _version = reader.u32()?;
+        let bgra = match reader.u32()? {
+            0 => false,
+            1 => true,
+            format => return Err(format!("Unknown colour format {}", format)),
+        };
+        let right_handed = reader.u32()? == 1;
+        let compressed = reader.u32()? != 0;
+        let _visibility_mask_encoded = reader.u32()?;
+        let matrix_count = reader.u32()?;
+
+        let mut voxels = Vec::new();
+        let mut colour_counts = HashMap::new();
+        for _ in 0..matrix_count {
+            let name_length = reader.u8()? as usize;
+            reader.bytes(name_length)?;
+            let size = UVec3::new(reader.u32()?, reader.u32()?, reader.u32()?);
+            let position = IVec3::new(reader.i32()?, reader.i32()?, reader.i32()?);
+
+            if size.max_element() > MAX_VOX_EXTENT as u32 {
+                return Err(format!(
+                    "Matrix is {}x{}x{} voxels. Max dimension is {}",
+                    size.x, size.y, size.z, MAX_VOX_EXTENT
+                ));
+            }
+
+            // colours are stored x fastest, then y, then z
+            let matrix = if compressed {
+                Self::read_qb_compressed(&mut reader, size)?
+            } else {
+                let length = size.x as usize * size.y as usize * size.z as usize * 4;
+                Self::solid_qb_voxels(reader.bytes(length)?.chunks_exact(4).enumerate()).collect()
+            };
+
+            for (index, colour) in matrix {
+                let colour = if bgra {
+                    [colour[2], colour[1], colour[0]]
+                } else {
+                    [colour[0], colour[1], colour[2]]
+                };
+
+                let (width, height) = (size.x as usize, size.y as usize);
+                let local = IVec3::new(
+                    (index % width) as i32,
+                    (index / width % height) as i32,
+                    (index / (width * height)) as i32,
+                );
+                let mut pos = position + local;
+                if !right_handed {
+                    pos.z = -pos.z;
+                }
+
+                voxels.push((pos, colour));
+                *colour_counts.entry(colour).or_insert(0u32) += 1;
+            }
+        }
+
+        // material zero is empty so only 255 colours are available
+        let (palette, lookup) = quantise(&colour_counts, 255);
+        let placed = voxels
+            .into_iter()
+            .map(|(pos, colour)| (pos, lookup[&colour], Flags::COLLISION_FLAG))
+            .collect();
+
+        let mut gh = Self::from_placed(placed, chunk_size)?;
+        for (i, colour) in palette.iter().enumerate() {
+            let colour = Vec4::new(
+                colour[0] as f32 / 255.0,
+                colour[1] as f32 / 255.0,
+                colour[2] as f32 / 255.0,
+                0.0,
+            );
+            gh.pallete[i + 1] = colour.powf(2.2).to_array();
+        }
+
+        Ok(gh)
+    }
+
+    /// Each z slice is run length encoded and ends with [`NEXT_SLICE_FLAG`].
+    /// Only the solid voxels are kept, so memory grows with the voxels that
+    /// were decoded instead of the size in the header.
+    fn read_qb_compressed(
+        reader: &mut ByteReader,
+        size: UVec3,
+    ) -> Result<Vec<(usize, [u8; 4])>, String> {
+        let slice_length = size.x as usize * size.y as usize;
+        let mut voxels = Vec::new();
+        for z in 0..size.z as usize {
+            let mut index = 0;
+            loop {
+                let value = reader.bytes(4)?;
+                let (count, colour) = match u32::from_le_bytes(value.try_into().unwrap()) {
+                    NEXT_SLICE_FLAG => break,
+                    CODE_FLAG => (reader.u32()? as usize, reader.bytes(4)?),
+                    _ => (1, value),
+                };
+
+                if count > slice_length - index {
+                    return Err("Compressed matrix slice is too long".to_string());
+                }
+                let start = z * slice_length + index;
+                let run = (start..start + count).map(|i| (i, colour));
+                voxels.extend(Self::solid_qb_voxels(run));
+                index += count;
+            }
+        }
+        Ok(voxels)
+    }
+
+    /// Alpha is zero for empty voxels, otherwise it can hold a visibility mask.
+    fn solid_qb_voxels<'a>(
+        voxels: impl Iterator<Item = (usize, &'a [u8])> + 'a,
+    ) -> impl Iterator<Item = (usize, [u8; 4])> + 'a {
+        voxels
+            .filter(|(_, colour)| colour[3] != 0)
+            .map(|(index, colour)| (index, colour.try_into().unwrap()))
+    }
+}
+
+/// Median cut quantisation. Returns the palette and the palette index of
+/// every colour, indices start at one.
+fn quantise(
+    colour_counts: &HashMap<[u8; 3], u32>,
+    max_colours: usize,
+) -> (Vec<[u8; 3]>, HashMap<[u8; 3], u8>) {
+    let mut colours: Vec<_> = colour_counts.iter().map(|(c, n)| (*c, *n)).collect();
+    colours.sort();
+
+    let mut boxes = Vec::new();
+    if !colours.is_empty() {
+        boxes.push(colours);
+    }
+
+    // split the box with the widest channel until there are enough colours
+    while boxes.len() < max_colours {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, colours)| colours.len() > 1)
+            .map(|(i, colours)| (i, widest_channel(colours)))
+            .max_by_key(|(_, (_, range))| *range);
+        let Some((index, (channel, _))) = widest else {
+            break;
+        };
+
+        let mut colours = boxes.swap_remove(index);
+        colours.sort_by_key(|(colour, _)| colour[channel]);
+
+        // split at the median voxel, not the median colour
+        let total: u64 = colours.iter().map(|(_, n)| *n as u64).sum();
+        let mut seen = 0;
+        let mut split = colours.len() / 2;
+        for (i, (_, n)) in colours.iter().enumerate() {
+            seen += *n as u64;
+            if seen * 2 >= total {
+                split = (i + 1).clamp(1, colours.len() - 1);
+                break;
+            }
+        }
+
+        let upper = colours.split_off(split);
+        boxes.push(colours);
+        boxes.push(upper);
+    }
+
+    let mut palette = Vec::with_capacity(boxes.len());
+    let mut lookup = HashMap::new();
+    for colours in boxes {
+        let total: u64 = colours.iter().map(|(_, n)| *n as u64).sum();
+        let average = [0, 1, 2].map(|channel| {
+            let sum: u64 = colours
+                .iter()
+                .map(|(colour, n)| colour[channel] as u64 * *n as u64)
+                .sum();
+            (sum / total) as u8
+        });
+
+        for (colour, _) in colours {
+            lookup.insert(colour, palette.len() as u8 + 1);
+        }
+        palette.push(average);
+    }
+    (palette, lookup)
+}
+
+/// Returns the channel with the largest range and that range.
+fn widest_channel(colours: &[([u8; 3], u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let min = colours.iter().map(|(c, _)| c[channel]).min().unwrap();
+            let max = colours.iter().map(|(c, _)| c[channel]).max().unwrap();
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::MAX_CHUNK_SIZE;
+
+    /// A .qb file with a single 2x1x2 matrix at (1, 0, 0), in rgba with a
+    /// right handed z axis.
+    fn qb_file(compressed: bool, data: &[u32]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for value in [257, 0, 1, compressed as u32, 0, 1] {
+            file.extend_from_slice(&u32::to_le_bytes(value));
+        }
+        file.push(1);
+        file.push(b'm');
+        for value in [2, 1, 2, 1, 0, 0] {
+            file.extend_from_slice(&u32::to_le_bytes(value));
+        }
+        for value in data {
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+        file
+    }
+
+    const RED: u32 = 0xFF0000FF;
+    const BLUE: u32 = 0xFFFF0000;
+
+    /// Materials of the solid voxels by position.
+    fn voxels(gh: &GridHierarchy) -> Vec<(IVec3, u8)> {
+        let size = gh.texture_size as i32;
+        let data = &gh.chunks[&IVec3::ZERO];
+        let mut voxels: Vec<_> = data
+            .chunks_exact(2)
+            .enumerate()
+            .filter(|(_, voxel)| voxel[0] != 0)
+            .map(|(i, voxel)| {
+                let i = i as i32;
+                let pos = IVec3::new(i / (size * size), i / size % size, i % size);
+                (pos, voxel[0])
+            })
+            .collect();
+        voxels.sort_by_key(|(pos, _)| pos.to_array());
+        voxels
+    }
+
+    #[test]
+    fn uncompressed_qb() {
+        // x fastest, then z, the last voxel is empty
+        let gh =
+            GridHierarchy::from_qb(&qb_file(false, &[RED, BLUE, RED, 0]), MAX_CHUNK_SIZE).unwrap();
+
+        let voxels = voxels(&gh);
+        assert_eq!(voxels.len(), 3);
+        let red = voxels[0].1;
+        let blue = voxels[2].1;
+        assert_ne!(red, blue);
+        assert_eq!(
+            voxels,
+            vec![
+                (IVec3::new(0, 0, 0), red),
+                (IVec3::new(0, 0, 1), red),
+                (IVec3::new(1, 0, 0), blue),
+            ]
+        );
+        assert_eq!(gh.pallete[red as usize], [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(gh.pallete[blue as usize], [0.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn compressed_qb() {
+        let first_slice = [RED, BLUE, NEXT_SLICE_FLAG];
+        // a run of one red and nothing after it
+        let second_slice = [CODE_FLAG, 1, RED, NEXT_SLICE_FLAG];
+        let data = [first_slice.as_slice(), &second_slice].concat();
+        let compressed = GridHierarchy::from_qb(&qb_file(true, &data), MAX_CHUNK_SIZE).unwrap();
+        let uncompressed =
+            GridHierarchy::from_qb(&qb_file(false, &[RED, BLUE, RED, 0]), MAX_CHUNK_SIZE).unwrap();
+        assert_eq!(voxels(&compressed), voxels(&uncompressed));
+
+        // runs past the end of a slice and missing data are errors
+        let data = [CODE_FLAG, 3, RED, NEXT_SLICE_FLAG, NEXT_SLICE_FLAG];
+        assert!(GridHierarchy::from_qb(&qb_file(true, &data), MAX_CHUNK_SIZE).is_err());
+        assert!(GridHierarchy::from_qb(&qb_file(true, &[RED, BLUE]), MAX_CHUNK_SIZE).is_err());
+    }
+
+    #[test]
+    fn huge_compressed_header_fails_without_allocating() {
+        let mut file = qb_file(true, &[]);
+        // 768³ voxels with no data after the header
+        let size_offset = 24 + 2;
+        for i in 0..3 {
+            let start = size_offset + i * 4;
+            file[start..start + 4].copy_from_slice(&768u32.to_le_bytes());
+        }
+        assert!(GridHierarchy::from_qb(&file, MAX_CHUNK_SIZE).is_err());
+    }
+}
@@ -0,0 +1,730 @@
+use super::{ByteReader, GridHierarchy, MAX_CHUNK_SIZE};
+use bevy::{
+    prelude::*,
+    render::extract_resource::ExtractResource,
+    utils::{HashMap, HashSet},
+};
+use std::{
+    fs::File,
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    task::{Poll, Waker},
+};
+
+const REGION_MAGIC: &[u8; 4] = b"BVXR";
+
+/// Bump when the layout of region files changes.
+/// Version 2 added the chunk offset table.
+const REGION_VERSION: u32 = 2;
+
+/// Regions are this many chunks along each axis.
+const REGION_SIZE: i32 = 8;
+
+/// Magic, version, chunk size and an offset and run count for every chunk.
+const HEADER_LENGTH: usize = 12 + (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize * 12;
+
+/// A session cache that keeps chunks that leave the active chunks in region
+/// files, so changes made to them survive streaming. Every file holds a cube
+/// of 8x8x8 chunks and is named after the region coordinate. Chunks that come
+/// back into the active chunks are loaded from here before the world they
+/// were streamed from.
+///
+/// A table at the start of a file points at every chunk, so only the chunk
+/// that is loaded or saved is read or written. A chunk that grows is moved to
+/// the end of the file, the space it leaves is not reused.
+///
+/// Evicted chunks are read back from the gpu over the next frames and saved on
+/// the io task pool. Loading a chunk waits until it is read back and takes it
+/// from memory until it is saved.
+///
+/// The cache only lasts as long as the app and its world, changes are not
+/// kept across runs or world loads. The files go into a session directory
+/// that is made inside of the given directory and removed with the last clone
+/// of this resource, anything else in the given directory is left alone. Every
+/// loaded world gets its own subdirectory of the session, so chunks of an
+/// earlier world never come back in a later one. A subdirectory is removed
+/// once its world is no longer loaded and its last chunks are saved. To keep
+/// the changes, save the world with
+/// [`save_world_native`](crate::save_world_native), which takes the chunks in
+/// the cache along with the active ones.
+///
+/// Without this resource streamed chunks are reloaded from the world.
+#[derive(Resource, ExtractResource, Clone)]
+pub struct RegionCache {
+    session: Arc<Session>,
+    world: u32,
+    shared: Arc<Shared>,
+}
+
+/// The directory of this run.
+struct Session(PathBuf);
+
+/// The files of one world.
+struct Shared {
+    directory: PathBuf,
+    // chunks can be evicted and activated on different threads
+    files: Mutex<()>,
+    /// Chunks with saves still queued.
+    queued: Mutex<HashMap<IVec3, QueuedChunk>>,
+}
+
+/// The saves of a chunk that are still to be written.
+#[derive(Default)]
+struct QueuedChunk {
+    count: u32,
+    /// Saves queued so far, the last one has the current chunk.
+    newest: u64,
+    /// The chunk of the newest save once it is read back.
+    data: Option<Arc<Vec<u8>>>,
+    /// Loads waiting for the data or for the saves to be done.
+    wakers: Vec<Waker>,
+}
+
+/// A save of a chunk that is still to be written. Loading the chunk waits for
+/// the data of the newest save, or until every save is dropped whether the
+/// chunk was saved or not.
+pub(crate) struct QueuedSave {
+    region_cache: RegionCache,
+    pub chunk: IVec3,
+    index: u64,
+}
+
+impl Session {
+    /// Makes a directory no one else uses, the session is the only one that
+    /// removes it.
+    fn new(root: &Path) -> Result<Self, String> {
+        let error = |path: &Path, e: std::io::Error| format!("{}: {}", path.display(), e);
+        std::fs::create_dir_all(root).map_err(|e| error(root, e))?;
+        let mut i = 0;
+        loop {
+            let directory = root.join(format!("session{}-{}", std::process::id(), i));
+            match std::fs::create_dir(&directory) {
+                Ok(()) => return Ok(Self(directory)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => i += 1,
+                Err(e) => return Err(error(&directory, e)),
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+impl QueuedSave {
+    /// Loads take the read back chunk from here until it is saved. Data of a
+    /// save that was queued before the newest one is only saved.
+    pub(crate) fn set_data(&self, data: Vec<u8>) -> Arc<Vec<u8>> {
+        let data = Arc::new(data);
+        let mut queued = self.region_cache.shared.queued.lock().unwrap();
+        let Some(chunk) = queued.get_mut(&self.chunk) else {
+            return data;
+        };
+        if chunk.newest != self.index {
+            return data;
+        }
+        chunk.data = Some(data.clone());
+        let wakers = std::mem::take(&mut chunk.wakers);
+        drop(queued);
+        wakers.into_iter().for_each(Waker::wake);
+        data
+    }
+
+    pub(crate) fn save(&self, chunk_size: u32, data: &[u8]) -> Result<(), String> {
+        self.region_cache.save_chunk(self.chunk, chunk_size, data)
+    }
+}
+
+impl Drop for QueuedSave {
+    fn drop(&mut self) {
+        let mut queued = self.region_cache.shared.queued.lock().unwrap();
+        let Some(chunk) = queued.get_mut(&self.chunk) else {
+            return;
+        };
+        chunk.count -= 1;
+        if chunk.count == 0 {
+            let wakers = queued.remove(&self.chunk).unwrap().wakers;
+            drop(queued);
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+impl RegionCache {
+    /// Makes the session directory inside of the given one.
+    pub fn new(directory: impl AsRef<Path>) -> Result<Self, String> {
+        let session = Session::new(directory.as_ref())?;
+        Ok(Self::for_world(Arc::new(session), 0))
+    }
+
+    fn for_world(session: Arc<Session>, world: u32) -> Self {
+        let directory = session.0.join(format!("world{}", world));
+        Self {
+            session,
+            world,
+            shared: Arc::new(Shared {
+                directory,
+                files: default(),
+                queued: default(),
+            }),
+        }
+    }
+
+    /// Starts an empty subdirectory for a newly loaded world. Saves of the
+    /// last world still go to its own subdirectory.
+    pub(crate) fn load_world(&mut self) {
+        *self = Self::for_world(self.session.clone(), self.world + 1);
+    }
+
+    /// Marks the chunk as being saved until the returned save is dropped.
+    /// Loads wait for the data of this save, earlier saves have an older chunk.
+    pub(crate) fn queue_save(&self, chunk: IVec3) -> QueuedSave {
+        let mut queued = self.shared.queued.lock().unwrap();
+        let queued = queued.entry(chunk).or_default();
+        queued.count += 1;
+        queued.newest += 1;
+        queued.data = None;
+        QueuedSave {
+            region_cache: self.clone(),
+            chunk,
+            index: queued.newest,
+        }
+    }
+
+    /// Every chunk that is saved or queued to be saved for the current world.
+    pub(crate) fn cached_chunks(&self, chunk_size: u32) -> Result<HashSet<IVec3>, String> {
+        let mut chunks: HashSet<IVec3> =
+            self.shared.queued.lock().unwrap().keys().copied().collect();
+        let _lock = self.shared.files.lock().unwrap();
+        let directory = &self.shared.directory;
+        let error = |path: &Path, e: std::io::Error| format!("{}: {}", path.display(), e);
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(chunks),
+            Err(e) => return Err(error(directory, e)),
+        };
+        for entry in entries {
+            let path = entry.map_err(|e| error(directory, e))?.path();
+            let Some(region) = region_of(&path) else {
+                continue;
+            };
+            let mut file = File::open(&path).map_err(|e| error(&path, e))?;
+            let header = RegionHeader::read(&mut file, chunk_size)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            for (index, (offset, _)) in header.table.iter().enumerate() {
+                let index = index as i32;
+                let local = IVec3::new(
+                    index % REGION_SIZE,
+                    index / REGION_SIZE % REGION_SIZE,
+                    index / REGION_SIZE / REGION_SIZE,
+                );
+                if *offset != 0 {
+                    chunks.insert(region * REGION_SIZE + local);
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    fn path(&self, chunk: IVec3) -> PathBuf {
+        let region = chunk.div_euclid(IVec3::splat(REGION_SIZE));
+        self.shared
+            .directory
+            .join(format!("{}.{}.{}.bvr", region.x, region.y, region.z))
+    }
+
+    /// None if the chunk was never saved. A chunk with queued saves comes from
+    /// memory once it is read back, so a chunk that comes straight back isn't
+    /// loaded from an older file. Waiting doesn't block the thread.
+    pub(crate) async fn load_chunk(
+        &self,
+        chunk: IVec3,
+        chunk_size: u32,
+    ) -> Result<Option<Vec<u8>>, String> {
+        let read_back = std::future::poll_fn(|cx| {
+            let mut queued = self.shared.queued.lock().unwrap();
+            let Some(queued) = queued.get_mut(&chunk) else {
+                return Poll::Ready(None);
+            };
+            if let Some(data) = &queued.data {
+                return Poll::Ready(Some(data.clone()));
+            }
+            if !queued
+                .wakers
+                .iter()
+                .any(|waker| waker.will_wake(cx.waker()))
+            {
+                queued.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await;
+        if let Some(data) = read_back {
+            return Ok(Some(data.to_vec()));
+        }
+        self.read_chunk(chunk, chunk_size)
+    }
+
+    fn read_chunk(&self, chunk: IVec3, chunk_size: u32) -> Result<Option<Vec<u8>>, String> {
+        let _lock = self.shared.files.lock().unwrap();
+        let path = self.path(chunk);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut file = File::open(&path).map_err(error)?;
+        let header = RegionHeader::read(&mut file, chunk_size)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let (offset, run_count) = header.table[table_index(chunk)];
+        if offset == 0 {
+            return Ok(None);
+        }
+
+        let mut runs = vec![0; run_count as usize * 6];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut runs))
+            .map_err(error)?;
+        GridHierarchy::decode_runs(&runs, chunk_length(chunk_size)?)
+            .map(Some)
+            .ok_or_else(|| format!("Chunk {} has the wrong length", chunk))
+    }
+
+    /// Replaces the chunk in its region file, the other chunks are kept.
+    pub(crate) fn save_chunk(
+        &self,
+        chunk: IVec3,
+        chunk_size: u32,
+        data: &[u8],
+    ) -> Result<(), String> {
+        let _lock = self.shared.files.lock().unwrap();
+        let path = self.path(chunk);
+        let error = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        if !path.exists() {
+            let header = RegionHeader {
+                chunk_size,
+                table: vec![(0, 0); REGION_SIZE.pow(3) as usize],
+            };
+            std::fs::create_dir_all(&self.shared.directory)
+                .and_then(|()| std::fs::write(&path, header.to_bytes()))
+                .map_err(error)?;
+        }
+
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .map_err(error)?;
+        let header = RegionHeader::read(&mut file, chunk_size)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let runs = GridHierarchy::encode_runs(data);
+        let run_count = runs.len() as u32 / 6;
+        let index = table_index(chunk);
+        let (offset, old_run_count) = header.table[index];
+        let offset = if offset != 0 && run_count <= old_run_count {
+            file.seek(SeekFrom::Start(offset))
+        } else {
+            file.seek(SeekFrom::End(0))
+        }
+        .map_err(error)?;
+
+        let mut entry = offset.to_le_bytes().to_vec();
+        entry.extend_from_slice(&run_count.to_le_bytes());
+        file.write_all(&runs)
+            .and_then(|()| file.seek(SeekFrom::Start(12 + index as u64 * 12)))
+            .and_then(|_| file.write_all(&entry))
+            .map_err(error)
+    }
+}
+
+/// Position of the chunk in the table of its region file.
+fn table_index(chunk: IVec3) -> usize {
+    let local = chunk.rem_euclid(IVec3::splat(REGION_SIZE));
+    ((local.z * REGION_SIZE + local.y) * REGION_SIZE + local.x) as usize
+}
+
+/// The region coordinate a region file is named after.
+fn region_of(path: &Path) -> Option<IVec3> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".bvr")?;
+    let mut coordinates = name.split('.').map(|c| c.parse().ok());
+    let region = IVec3::new(
+        coordinates.next()??,
+        coordinates.next()??,
+        coordinates.next()??,
+    );
+    coordinates.next().is_none().then_some(region)
+}
+
+/// Bytes of a chunk, fails for chunk sizes no world can have.
+fn chunk_length(chunk_size: u32) -> Result<usize, String> {
+    if chunk_size > MAX_CHUNK_SIZE {
+        return Err(format!("Invalid chunk size {}", chunk_size));
+    }
+    Ok((chunk_size as usize).pow(3) * 2)
+}
+
+/// The start of a region file, every chunk is run length encoded like in
+/// native worlds at the offset of its table entry. Chunks that were never
+/// saved have offset 0.
+struct RegionHeader {
+    chunk_size: u32,
+    table: Vec<(u64, u32)>,
+}
+
+impl RegionHeader {
+    /// Fails unless the region has chunks of the given size.
+    fn read(file: &mut File, chunk_size: u32) -> Result<Self, String> {
+        let mut bytes = vec![0; HEADER_LENGTH];
+        file.read_exact(&mut bytes).map_err(|e| e.to_string())?;
+        let header = Self::from_bytes(&bytes)?;
+        if header.chunk_size != chunk_size {
+            return Err(format!(
+                "The region has chunks of size {}, the world has {}",
+                header.chunk_size, chunk_size
+            ));
+        }
+        Ok(header)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut file = Vec::with_capacity(HEADER_LENGTH);
+        file.extend_from_slice(REGION_MAGIC);
+        file.extend_from_slice(&REGION_VERSION.to_le_bytes());
+        file.extend_from_slice(&self.chunk_size.to_le_bytes());
+        for (offset, run_count) in &self.table {
+            file.extend_from_slice(&offset.to_le_bytes());
+            file.extend_from_slice(&run_count.to_le_bytes());
+        }
+        file
+    }
+
+    fn from_bytes(file: &[u8]) -> Result<Self, String> {
+        let mut reader = ByteReader { data: file };
+        if reader.bytes(4)? != REGION_MAGIC {
+            return Err("Not a region file".to_string());
+        }
+        let version = reader.u32()?;
+        if version != REGION_VERSION {
+            return Err(format!(
+                "Region file version {} is not supported, only {} is",
+                version, REGION_VERSION
+            ));
+        }
+
+        let chunk_size = reader.u32()?;
+        let chunk_length = chunk_length(chunk_size)?;
+        let mut table = Vec::with_capacity(REGION_SIZE.pow(3) as usize);
+        for _ in 0..REGION_SIZE.pow(3) {
+            let offset = reader.u64()?;
+            let run_count = reader.u32()?;
+            // every run is at least one voxel
+            if run_count as usize > chunk_length / 2 {
+                return Err(format!("A chunk has {} runs", run_count));
+            }
+            table.push((offset, run_count));
+        }
+
+        Ok(Self { chunk_size, table })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::tasks::{block_on, poll_once};
+
+    fn chunk(size: u32, seed: u8) -> Vec<u8> {
+        let mut data = vec![0; (size * size * size * 2) as usize];
+        for (i, voxel) in data.chunks_exact_mut(2).enumerate() {
+            if i % 7 < 3 {
+                voxel[0] = seed.wrapping_add(i as u8 / 64);
+                voxel[1] = 16;
+            }
+        }
+        data
+    }
+
+    fn directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("bevy_voxel_engine_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut table = vec![(0, 0); 512];
+        table[table_index(IVec3::new(7, -3, 2))] = (HEADER_LENGTH as u64, 12);
+        let header = RegionHeader {
+            chunk_size: 8,
+            table,
+        };
+
+        let loaded = RegionHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert_eq!(loaded.chunk_size, 8);
+        assert_eq!(loaded.table, header.table);
+
+        let mut bytes = header.to_bytes();
+        bytes.truncate(bytes.len() - 1);
+        assert!(RegionHeader::from_bytes(&bytes).is_err());
+        assert!(RegionHeader::from_bytes(b"BVXW").is_err());
+    }
+
+    #[test]
+    fn rejects_huge_chunk_sizes() {
+        let header = RegionHeader {
+            chunk_size: 1 << 30,
+            table: vec![(0, 0); 512],
+        };
+        assert!(RegionHeader::from_bytes(&header.to_bytes()).is_err());
+
+        let header = RegionHeader {
+            chunk_size: 8,
+            table: vec![(HEADER_LENGTH as u64, u32::MAX); 512],
+        };
+        assert!(RegionHeader::from_bytes(&header.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn chunks_round_trip_through_files() {
+        let directory = directory("region_cache");
+        let regions = RegionCache::new(&directory).unwrap();
+
+        // same region, a neighbouring region and one across the origin
+        let saved = [
+            (IVec3::new(0, 0, 0), chunk(16, 1)),
+            (IVec3::new(3, 7, 5), chunk(16, 2)),
+            (IVec3::new(8, 0, 0), chunk(16, 3)),
+            (IVec3::new(-1, -9, 0), chunk(16, 4)),
+        ];
+        assert_eq!(block_on(regions.load_chunk(saved[0].0, 16)), Ok(None));
+        for (position, data) in &saved {
+            regions.save_chunk(*position, 16, data).unwrap();
+        }
+        for (position, data) in &saved {
+            assert_eq!(
+                block_on(regions.load_chunk(*position, 16))
+                    .unwrap()
+                    .as_ref(),
+                Some(data)
+            );
+        }
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::new(1, 0, 0), 16)),
+            Ok(None)
+        );
+
+        // saving again replaces the chunk and keeps the rest of the region
+        regions.save_chunk(saved[0].0, 16, &chunk(16, 9)).unwrap();
+        assert_eq!(
+            block_on(regions.load_chunk(saved[0].0, 16)),
+            Ok(Some(chunk(16, 9)))
+        );
+        assert_eq!(
+            block_on(regions.load_chunk(saved[1].0, 16)),
+            Ok(Some(saved[1].1.clone()))
+        );
+
+        // a chunk that grows moves to the end of the file
+        let grown: Vec<u8> = (0..16 * 16 * 16 * 2).map(|i| (i / 2 % 251) as u8).collect();
+        regions.save_chunk(saved[1].0, 16, &grown).unwrap();
+        assert_eq!(
+            block_on(regions.load_chunk(saved[1].0, 16)),
+            Ok(Some(grown))
+        );
+        assert_eq!(
+            block_on(regions.load_chunk(saved[0].0, 16)),
+            Ok(Some(chunk(16, 9)))
+        );
+
+        assert!(block_on(regions.load_chunk(saved[0].0, 32)).is_err());
+        assert!(regions.save_chunk(saved[0].0, 32, &chunk(32, 1)).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reads_only_the_requested_chunk() {
+        let directory = directory("region_single_chunk");
+        let regions = RegionCache::new(&directory).unwrap();
+        regions.save_chunk(IVec3::ZERO, 8, &chunk(8, 1)).unwrap();
+        regions.save_chunk(IVec3::X, 8, &chunk(8, 2)).unwrap();
+
+        // breaking the runs of one chunk leaves the other readable
+        let path = regions.path(IVec3::ZERO);
+        let mut file = std::fs::read(&path).unwrap();
+        let length = file.len();
+        file[length - 6..].fill(0xff);
+        std::fs::write(&path, file).unwrap();
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::ZERO, 8)),
+            Ok(Some(chunk(8, 1)))
+        );
+        assert!(block_on(regions.load_chunk(IVec3::X, 8)).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn loading_waits_for_queued_saves() {
+        let directory = directory("region_queued_saves");
+        let regions = RegionCache::new(&directory).unwrap();
+        regions.save_chunk(IVec3::ZERO, 8, &chunk(8, 1)).unwrap();
+
+        let queued = regions.queue_save(IVec3::ZERO);
+        let saving = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            queued.save(8, &chunk(8, 2)).unwrap();
+        });
+        // other chunks don't wait
+        assert_eq!(block_on(regions.load_chunk(IVec3::X, 8)), Ok(None));
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::ZERO, 8)),
+            Ok(Some(chunk(8, 2)))
+        );
+        saving.join().unwrap();
+
+        // a save that is dropped without saving lets loads through too
+        drop(regions.queue_save(IVec3::ZERO));
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::ZERO, 8)),
+            Ok(Some(chunk(8, 2)))
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn read_back_chunks_load_before_they_are_saved() {
+        let directory = directory("region_read_back");
+        let regions = RegionCache::new(&directory).unwrap();
+        regions.save_chunk(IVec3::ZERO, 8, &chunk(8, 1)).unwrap();
+
+        // evicted twice before the first read back arrives
+        let first = regions.queue_save(IVec3::ZERO);
+        let second = regions.queue_save(IVec3::ZERO);
+        first.set_data(chunk(8, 2));
+        assert_eq!(
+            block_on(poll_once(regions.load_chunk(IVec3::ZERO, 8))),
+            None
+        );
+
+        // the newest chunk loads without waiting for the files
+        second.set_data(chunk(8, 3));
+        assert_eq!(
+            block_on(poll_once(regions.load_chunk(IVec3::ZERO, 8))),
+            Some(Ok(Some(chunk(8, 3))))
+        );
+
+        // evicting it again waits for the new read back
+        let third = regions.queue_save(IVec3::ZERO);
+        assert_eq!(
+            block_on(poll_once(regions.load_chunk(IVec3::ZERO, 8))),
+            None
+        );
+        drop((first, second, third));
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::ZERO, 8)),
+            Ok(Some(chunk(8, 1)))
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn worlds_keep_their_own_chunks() {
+        let directory = directory("region_worlds");
+        let mut regions = RegionCache::new(&directory).unwrap();
+        regions.save_chunk(IVec3::ZERO, 8, &chunk(8, 1)).unwrap();
+        let first_world = regions.path(IVec3::ZERO);
+
+        // a save still queued for the first world finishes there
+        let queued = regions.queue_save(IVec3::X);
+        regions.load_world();
+        assert_eq!(block_on(regions.load_chunk(IVec3::ZERO, 8)), Ok(None));
+        assert_eq!(block_on(regions.load_chunk(IVec3::X, 8)), Ok(None));
+        queued.save(8, &chunk(8, 2)).unwrap();
+        assert_eq!(block_on(regions.load_chunk(IVec3::X, 8)), Ok(None));
+
+        // the first world's files go once nothing uses them
+        assert!(first_world.exists());
+        drop(queued);
+        assert!(!first_world.exists());
+
+        regions.save_chunk(IVec3::ZERO, 8, &chunk(8, 3)).unwrap();
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::ZERO, 8)),
+            Ok(Some(chunk(8, 3)))
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn only_the_session_is_removed() {
+        let directory = directory("region_session");
+        std::fs::create_dir_all(directory.join("world0")).unwrap();
+        std::fs::write(directory.join("world0").join("0.0.0.bvr"), b"kept").unwrap();
+
+        let regions = RegionCache::new(&directory).unwrap();
+        let other = RegionCache::new(&directory).unwrap();
+        regions.save_chunk(IVec3::ZERO, 8, &chunk(8, 1)).unwrap();
+        other.save_chunk(IVec3::ZERO, 8, &chunk(8, 2)).unwrap();
+        assert_ne!(regions.path(IVec3::ZERO), other.path(IVec3::ZERO));
+        assert_eq!(
+            block_on(regions.load_chunk(IVec3::ZERO, 8)),
+            Ok(Some(chunk(8, 1)))
+        );
+
+        let session = regions.path(IVec3::ZERO);
+        drop(regions);
+        assert!(!session.exists());
+        assert!(other.path(IVec3::ZERO).exists());
+        drop(other);
+        assert_eq!(
+            std::fs::read(directory.join("world0").join("0.0.0.bvr")).unwrap(),
+            b"kept"
+        );
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn lists_cached_chunks() {
+        let directory = directory("region_cached_chunks");
+        let regions = RegionCache::new(&directory).unwrap();
+        assert_eq!(regions.cached_chunks(8), Ok(HashSet::new()));
+
+        regions
+            .save_chunk(IVec3::new(1, 2, 3), 8, &chunk(8, 1))
+            .unwrap();
+        regions
+            .save_chunk(IVec3::new(-9, 0, 8), 8, &chunk(8, 2))
+            .unwrap();
+        let queued = regions.queue_save(IVec3::new(1, 2, 3));
+        let other = regions.queue_save(IVec3::Y);
+        assert_eq!(
+            regions.cached_chunks(8),
+            Ok(HashSet::from_iter([
+                IVec3::new(1, 2, 3),
+                IVec3::new(-9, 0, 8),
+                IVec3::Y
+            ]))
+        );
+        assert!(regions.cached_chunks(16).is_err());
+
+        drop((queued, other));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -53,7 +53,7 @@ fn main() {
             ..default()
         }),
         ObjPlugin,
-        BevyVoxelEnginePlugin,
+        BevyVoxelEnginePlugin::default(),
         character::Character,
         ui::UiPlugin,
         fps_counter::FpsCounter,
@@ -78,10 +78,17 @@ fn main() {
 fn setup(
     mut commands: Commands,
     mut load_voxel_world: ResMut<LoadVoxelWorld>,
+    mut materials: ResMut<MaterialRegistry>,
     asset_server: Res<AssetServer>,
 ) {
     // load a voxel world
-    *load_voxel_world = LoadVoxelWorld::File("assets/monu9.vox".to_string());
+    *load_voxel_world = LoadVoxelWorld::Asset(asset_server.load("monu9.vox"));
+
+    // portal frame colours in the palette of the world
+    materials
+        .register("orange_portal", 120)
+        .register("blue_portal", 121);
+    let portal_frames = ["orange_portal", "blue_portal"];
 
     // character portals
     let mut character_portals = vec![None; 2];
@@ -108,7 +115,7 @@ fn setup(
                     parent.spawn(VoxelizationBundle {
                         mesh_handle: asset_server.load("models/portal_frame.obj"),
                         voxelization_material: VoxelizationMaterial {
-                            material: VoxelizationMaterialType::Material(120 + i as u8),
+                            material: VoxelizationMaterialType::Named(portal_frames[i].to_string()),
                             flags: Flags::ANIMATION_FLAG | Flags::COLLISION_FLAG,
                         },
                         ..default()
@@ -197,7 +204,7 @@ fn setup(
                 parent.spawn(VoxelizationBundle {
                     mesh_handle: asset_server.load("models/portal_frame.obj"),
                     voxelization_material: VoxelizationMaterial {
-                        material: VoxelizationMaterialType::Material(120 + i as u8),
+                        material: VoxelizationMaterialType::Named(portal_frames[i].to_string()),
                         flags: Flags::ANIMATION_FLAG | Flags::COLLISION_FLAG,
                     },
                     ..default()
@@ -239,15 +246,23 @@ fn update_suzanne(time: Res<Time>, mut cube: Query<&mut Transform, With<Suzanne>
 fn shoot(
     mut commands: Commands,
     keyboard: Res<ButtonInput<KeyCode>>,
+    materials: Res<MaterialRegistry>,
     mut character: Query<(&Transform, &mut CharacterEntity)>,
 ) {
     let (transform, mut character_entity) = character.single_mut();
+    // registered in setup
+    let Some(orange_portal) = materials.get("orange_portal") else {
+        return;
+    };
+    let Some(blue_portal) = materials.get("blue_portal") else {
+        return;
+    };
 
     if keyboard.just_pressed(KeyCode::Digit1) {
         commands.spawn((
             Transform::from_translation(transform.translation),
             Particle {
-                material: 120,
+                material: orange_portal,
                 flags: Flags::ANIMATION_FLAG,
             },
             VoxelPhysics::new(
@@ -262,7 +277,7 @@ fn shoot(
         commands.spawn((
             Transform::from_translation(transform.translation),
             Particle {
-                material: 121,
+                material: blue_portal,
                 flags: Flags::ANIMATION_FLAG,
             },
             VoxelPhysics::new(
@@ -280,7 +295,7 @@ fn shoot(
         commands.spawn((
             Transform::from_translation(transform.translation),
             Particle {
-                material: 120,
+                material: orange_portal,
                 flags: Flags::ANIMATION_FLAG,
             },
             VoxelPhysics::new(
@@ -320,12 +335,19 @@ fn shoot(
     }
 }
 
-fn update_fire(mut particle_query: Query<(Entity, &mut Particle)>, mut commands: Commands) {
+fn update_fire(
+    mut particle_query: Query<(Entity, &mut Particle)>,
+    materials: Res<MaterialRegistry>,
+    mut commands: Commands,
+) {
+    let Some(fire) = materials.range("fire") else {
+        return;
+    };
     let mut rand = rand::thread_rng();
     for (entity, mut particle) in particle_query.iter_mut() {
-        if particle.material >= 9 && particle.material <= 13 {
+        if fire.contains(&particle.material) {
             particle.material += rand.gen_range(0.0..1.02) as u8;
-            if particle.material == 11 {
+            if particle.material == fire.start() + 2 {
                 commands.entity(entity).despawn();
             }
         }
@@ -337,7 +359,9 @@ fn spawn_stuff(
     bullet_query: Query<(&Transform, &VoxelPhysics, &Bullet, Entity)>,
     character_query: Query<&CharacterPortals>,
     mut portal_query: Query<&mut Transform, (With<Portal>, Without<Bullet>)>,
+    settings: Res<VoxelEngineSettings>,
 ) {
+    let voxels_per_meter = settings.voxels_per_meter;
     for (transform, velocity, bullet, entity) in bullet_query.iter() {
         if velocity.hit_normal != Vec3::splat(0.0) {
             commands.entity(entity).despawn();
@@ -347,7 +371,7 @@ fn spawn_stuff(
 
                 let plane = 1.0 - normal.abs();
                 let pos =
-                    (transform.translation * plane * VOXELS_PER_METER).floor() / VOXELS_PER_METER;
+                    (transform.translation * plane * voxels_per_meter).floor() / voxels_per_meter;
                 let pos = pos + transform.translation * normal.abs();
 
                 let character_portals = character_query.single();
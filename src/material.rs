@@ -0,0 +1,117 @@
+use crate::load::Material;
+use bevy::{prelude::*, render::extract_resource::ExtractResource, utils::HashMap};
+use std::ops::RangeInclusive;
+
+// slots of the default magica voxel palette the built in materials start at
+pub(crate) const GRASS: u8 = 44;
+pub(crate) const DIRT: u8 = 43;
+pub(crate) const ROCK: u8 = 129;
+pub(crate) const WATER: u8 = 8;
+pub(crate) const GRASS_BLADES: RangeInclusive<u8> = 1..=5;
+pub(crate) const FIRE: RangeInclusive<u8> = 9..=13;
+
+/// Names for palette slots, so game code and the simulation don't depend on
+/// where a material happens to be in the palette. A name can cover a range of
+/// slots, fire for example burns through its slots from first to last.
+///
+/// The engine reads `grass`, `grass_blades`, `water` and `fire`, moving them
+/// makes the automata follow. Meshes voxelize with `default` unless they set
+/// a material.
+#[derive(Resource, ExtractResource, Clone, Debug)]
+pub struct MaterialRegistry {
+    slots: HashMap<String, RangeInclusive<u8>>,
+    properties: HashMap<u8, Material>,
+}
+
+impl Default for MaterialRegistry {
+    fn default() -> Self {
+        let mut registry = Self {
+            slots: HashMap::new(),
+            properties: HashMap::new(),
+        };
+        registry
+            .register("grass", GRASS)
+            .register_range("grass_blades", GRASS_BLADES)
+            .register("dirt", DIRT)
+            .register("rock", ROCK)
+            .register("water", WATER)
+            .register_range("fire", FIRE)
+            .register("default", 10);
+        registry
+    }
+}
+
+impl MaterialRegistry {
+    /// Replaces the slot if the name is already registered.
+    pub fn register(&mut self, name: impl Into<String>, id: u8) -> &mut Self {
+        self.register_range(name, id..=id)
+    }
+
+    pub fn register_range(&mut self, name: impl Into<String>, ids: RangeInclusive<u8>) -> &mut Self {
+        self.slots.insert(name.into(), ids);
+        self
+    }
+
+    /// Overrides the surface properties of every slot of a material. They are
+    /// applied on top of the materials of the loaded world. Unknown names are
+    /// logged and left out.
+    pub fn set_properties(&mut self, name: &str, material: Material) -> &mut Self {
+        let Some(ids) = self.range(name) else {
+            warn!("Material {} is not registered", name);
+            return self;
+        };
+        for id in ids {
+            self.properties.insert(id, material);
+        }
+        self
+    }
+
+    /// First slot of a material.
+    pub fn get(&self, name: &str) -> Option<u8> {
+        self.slots.get(name).map(|ids| *ids.start())
+    }
+
+    /// Every slot of a material.
+    pub fn range(&self, name: &str) -> Option<RangeInclusive<u8>> {
+        self.slots.get(name).cloned()
+    }
+
+    pub fn properties(&self, id: u8) -> Option<Material> {
+        self.properties.get(&id).copied()
+    }
+
+    pub(crate) fn overrides(&self) -> impl Iterator<Item = (u8, Material)> + '_ {
+        self.properties.iter().map(|(id, material)| (*id, *material))
+    }
+}
+
+/// Colours and surface properties of the materials above 255, which only
+/// [`VoxelFormat::Wide`](crate::VoxelFormat::Wide) voxels can hold. The
+/// materials below come from the palette as usual. Materials that were never
+/// set are black.
+#[derive(Resource, ExtractResource, Clone, Debug, Default)]
+pub struct WideMaterials {
+    entries: HashMap<u16, (Color, Material)>,
+}
+
+impl WideMaterials {
+    /// Materials go from 256 to 4095, others are logged and left out.
+    pub fn set(&mut self, id: u16, colour: Color, material: Material) -> &mut Self {
+        if !(256..4096).contains(&id) {
+            warn!("Material {} is not a wide material", id);
+            return self;
+        }
+        self.entries.insert(id, (colour, material));
+        self
+    }
+
+    pub fn get(&self, id: u16) -> Option<(Color, Material)> {
+        self.entries.get(&id).copied()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u16, Color, Material)> + '_ {
+        self.entries
+            .iter()
+            .map(|(id, (colour, material))| (*id, *colour, *material))
+    }
+}
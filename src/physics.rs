@@ -1,18 +1,21 @@
 use crate::{
     voxel_pipeline::{
-        compute::{AnimationData, PhysicsData},
+        compute::{AnimationData, PhysicsData, MAX_TYPE_BUFFER_DATA},
         voxel_world::{ExtractedPortal, VoxelUniforms},
     },
-    Box, BoxCollider, Edges, Particle, Portal, RenderGraphSettings, VoxelPhysics,
+    AnimatedPrefab, AnimatedVoxelModel, Box, BoxCollider, Edges, Flags, Particle, Portal,
+    PrefabStamp, RenderGraphSettings, VoxelAnimation, VoxelPhysics, VoxelPrefab,
     VoxelizationMaterial, VoxelizationMaterialType,
 };
 use bevy::{
+    ecs::system::SystemParam,
     prelude::*,
     render::render_resource::MapMode,
     render::renderer::{RenderDevice, RenderQueue},
     utils::HashMap,
 };
 
+#[deprecated(note = "set VoxelEngineSettings::voxels_per_meter instead")]
 pub const VOXELS_PER_METER: f32 = 4.0;
 
 pub struct PhysicsPlugin;
@@ -21,6 +24,7 @@ impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(PreUpdate, insert_physics_data)
             .add_systems(PostUpdate, extract_physics_data)
+            .add_systems(Update, play_voxel_models)
             .add_systems(PostUpdate, extract_animation_data);
     }
 }
@@ -147,15 +151,35 @@ pub fn insert_physics_data(
     }
 }
 
-#[allow(unused)]
+#[allow(unused, deprecated)]
+#[deprecated(note = "use world_to_voxel_scaled with VoxelEngineSettings::voxels_per_meter")]
 pub fn world_to_voxel(world_pos: Vec3, voxel_world_size: u32) -> IVec3 {
-    let world_pos = world_pos * VOXELS_PER_METER;
+    world_to_voxel_scaled(world_pos, voxel_world_size, VOXELS_PER_METER)
+}
+
+#[allow(unused, deprecated)]
+#[deprecated(note = "use world_to_render_scaled with VoxelEngineSettings::voxels_per_meter")]
+pub fn world_to_render(world_pos: Vec3, voxel_world_size: u32) -> Vec3 {
+    world_to_render_scaled(world_pos, voxel_world_size, VOXELS_PER_METER)
+}
+
+#[allow(unused)]
+pub fn world_to_voxel_scaled(
+    world_pos: Vec3,
+    voxel_world_size: u32,
+    voxels_per_meter: f32,
+) -> IVec3 {
+    let world_pos = world_pos * voxels_per_meter;
     world_pos.as_ivec3() + IVec3::splat(voxel_world_size as i32 / 2)
 }
 
 #[allow(unused)]
-pub fn world_to_render(world_pos: Vec3, voxel_world_size: u32) -> Vec3 {
-    2.0 * world_pos * VOXELS_PER_METER / voxel_world_size as f32
+pub fn world_to_render_scaled(
+    world_pos: Vec3,
+    voxel_world_size: u32,
+    voxels_per_meter: f32,
+) -> Vec3 {
+    2.0 * world_pos * voxels_per_meter / voxel_world_size as f32
 }
 
 #[derive(Clone)]
@@ -226,22 +250,75 @@ impl TypeBuffer {
     }
 }
 
+#[derive(SystemParam)]
+pub struct AnimatedStamps<'w, 's> {
+    prefab_query: Query<'w, 's, (&'static Transform, &'static AnimatedPrefab)>,
+    model_query: Query<'w, 's, (&'static Transform, &'static AnimatedVoxelModel)>,
+    prefabs: Res<'w, Assets<VoxelPrefab>>,
+    animations: Res<'w, Assets<VoxelAnimation>>,
+}
+
+impl<'w, 's> AnimatedStamps<'w, 's> {
+    /// Translation, stamped size, voxels and flags of every loaded prefab and
+    /// the current frame of every loaded model. The voxels are material and
+    /// flags, ordered like the chunks.
+    fn stamps(&self) -> impl Iterator<Item = (Vec3, UVec3, Vec<u32>, u8)> + '_ {
+        let prefabs = self.prefab_query.iter().filter_map(|(transform, animated)| {
+            let prefab = self.prefabs.get(&animated.prefab)?;
+            let (size, voxels) = stamp_voxels(prefab, &animated.stamp);
+            Some((transform.translation, size, voxels, animated.flags))
+        });
+        let models = self.model_query.iter().filter_map(|(transform, model)| {
+            let animation = self.animations.get(&model.animation)?;
+            let frame = animation.frame(model.elapsed, model.frame_rate)?;
+            let (size, voxels) = stamp_voxels(frame, &model.stamp);
+            let flags = model.flags | Flags::ANIMATION_FLAG;
+            Some((transform.translation, size, voxels, flags))
+        });
+        prefabs.chain(models)
+    }
+}
+
+fn stamp_voxels(prefab: &VoxelPrefab, stamp: &PrefabStamp) -> (UVec3, Vec<u32>) {
+    let size = prefab.stamped_size(stamp);
+    let mut voxels = vec![0u32; size.element_product() as usize];
+    for (pos, voxel) in prefab.stamped_voxels(stamp) {
+        let index = (pos.x * size.y + pos.y) * size.z + pos.z;
+        voxels[index as usize] = voxel.material as u32 | (voxel.flags as u32) << 8;
+    }
+    (size, voxels)
+}
+
+fn play_voxel_models(mut models: Query<&mut AnimatedVoxelModel>, time: Res<Time>) {
+    for mut model in &mut models {
+        if model.playing {
+            model.elapsed += time.delta_seconds();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn extract_animation_data(
     mut animation_data: ResMut<AnimationData>,
     particle_query: Query<(&Transform, &Particle)>,
     mut portal_query: Query<(&Transform, &Portal, &mut VoxelizationMaterial)>,
     edges_query: Query<(&Transform, &Edges)>,
     boxes_query: Query<(&Transform, &Box)>,
+    animated_stamps: AnimatedStamps,
     mut voxel_uniforms: ResMut<VoxelUniforms>,
     render_queue: Res<RenderQueue>,
 ) {
     let mut type_buffer = TypeBuffer::new();
 
     let voxel_world_size = voxel_uniforms.texture_size;
+    let voxels_per_meter = voxel_uniforms.voxels_per_meter;
+    // the animation pass works relative to the origin chunk
+    let origin = voxel_uniforms.origin_chunk * voxel_uniforms.chunk_size as i32;
 
     // Add particles
     for (transform, particle) in particle_query.iter() {
-        let pos = world_to_voxel(transform.translation, voxel_world_size);
+        let pos = world_to_voxel_scaled(transform.translation, voxel_world_size, voxels_per_meter)
+            - origin;
         type_buffer.push_object(0, |type_buffer| {
             type_buffer.push_ivec3(pos);
             type_buffer.push_u32(particle.material as u32);
@@ -251,7 +328,8 @@ pub fn extract_animation_data(
 
     // Add edges
     for (transform, edges) in edges_query.iter() {
-        let pos = world_to_voxel(transform.translation, voxel_world_size);
+        let pos = world_to_voxel_scaled(transform.translation, voxel_world_size, voxels_per_meter)
+            - origin;
         type_buffer.push_object(1, |type_buffer| {
             type_buffer.push_ivec3(pos);
             type_buffer.push_u32(edges.material as u32);
@@ -262,7 +340,8 @@ pub fn extract_animation_data(
 
     // Add boxes
     for (transform, boxes) in boxes_query.iter() {
-        let pos = world_to_voxel(transform.translation, voxel_world_size);
+        let pos = world_to_voxel_scaled(transform.translation, voxel_world_size, voxels_per_meter)
+            - origin;
         type_buffer.push_object(2, |type_buffer| {
             type_buffer.push_ivec3(pos);
             type_buffer.push_u32(boxes.material as u32);
@@ -271,6 +350,25 @@ pub fn extract_animation_data(
         });
     }
 
+    // Add prefabs and models, the voxels of the stamped box follow its size
+    for (translation, size, voxels, flags) in animated_stamps.stamps() {
+        let length = type_buffer.header.len() + type_buffer.data.len() + voxels.len() + 10;
+        if length > MAX_TYPE_BUFFER_DATA {
+            warn!("Too much animation data, an animated prefab was left out");
+            continue;
+        }
+
+        let center =
+            world_to_voxel_scaled(translation, voxel_world_size, voxels_per_meter) - origin;
+        type_buffer.push_object(3, |type_buffer| {
+            type_buffer.push_ivec3(center - (size / 2).as_ivec3());
+            type_buffer.push_u32(0); // the materials are in the voxels
+            type_buffer.push_u32(flags as u32);
+            type_buffer.push_ivec3(size.as_ivec3());
+            type_buffer.data.extend_from_slice(&voxels);
+        });
+    }
+
     // Grab all the portails in pairs
     voxel_uniforms.portals = [ExtractedPortal::default(); 32];
 
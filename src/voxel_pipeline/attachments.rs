@@ -22,7 +22,11 @@ impl Plugin for AttachmentsPlugin {
 pub struct RenderAttachments {
     current_size: UVec2,
     pub normal: Handle<Image>,
-    pub position: Handle<Image>,
+    /// Hit positions of this frame and the last one, swapped every frame.
+    pub position: [Handle<Image>; 2],
+    /// Accumulated indirect light and its sample count, swapped like the
+    /// positions.
+    pub history: [Handle<Image>; 2],
 }
 
 fn add_render_attachments(
@@ -31,40 +35,36 @@ fn add_render_attachments(
     mut query: Query<Entity, (With<TraceSettings>, Without<RenderAttachments>)>,
 ) {
     for entity in query.iter_mut() {
-        let size = Extent3d {
-            width: 1,
-            height: 1,
-            depth_or_array_layers: 1,
-        };
-        let mut image = Image::new_fill(
-            size,
-            TextureDimension::D2,
-            &[0; 8],
-            TextureFormat::Rgba16Float,
-            RenderAssetUsages::default(),
-        );
-        image.texture_descriptor.usage = TextureUsages::COPY_DST
-            | TextureUsages::STORAGE_BINDING
-            | TextureUsages::TEXTURE_BINDING;
-        let mut highp_image = Image::new_fill(
-            size,
-            TextureDimension::D2,
-            &[0; 16],
-            TextureFormat::Rgba32Float,
-            RenderAssetUsages::default(),
-        );
-        highp_image.texture_descriptor.usage = TextureUsages::COPY_DST
-            | TextureUsages::STORAGE_BINDING
-            | TextureUsages::TEXTURE_BINDING;
+        let image = attachment(TextureFormat::Rgba16Float, 8);
+        let highp_image = attachment(TextureFormat::Rgba32Float, 16);
 
         commands.entity(entity).insert(RenderAttachments {
             current_size: UVec2::new(1, 1),
             normal: images.add(image.clone()),
-            position: images.add(highp_image),
+            position: [images.add(highp_image.clone()), images.add(highp_image)],
+            history: [images.add(image.clone()), images.add(image)],
         });
     }
 }
 
+fn attachment(format: TextureFormat, pixel_size: usize) -> Image {
+    let size = Extent3d {
+        width: 1,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+    let mut image = Image::new_fill(
+        size,
+        TextureDimension::D2,
+        &vec![0; pixel_size],
+        format,
+        RenderAssetUsages::default(),
+    );
+    image.texture_descriptor.usage =
+        TextureUsages::COPY_DST | TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING;
+    image
+}
+
 fn resize_attachments(
     mut images: ResMut<Assets<Image>>,
     mut query: Query<(&mut RenderAttachments, &Camera)>,
@@ -85,11 +85,12 @@ fn resize_attachments(
                 depth_or_array_layers: 1,
             };
 
-            let normal_image = images.get_mut(&render_attachments.normal).unwrap();
-            normal_image.resize(size);
-
-            let position_image = images.get_mut(&render_attachments.position).unwrap();
-            position_image.resize(size);
+            let handles = std::iter::once(&render_attachments.normal)
+                .chain(&render_attachments.position)
+                .chain(&render_attachments.history);
+            for handle in handles {
+                images.get_mut(handle).unwrap().resize(size);
+            }
         }
     }
 }
\ No newline at end of file
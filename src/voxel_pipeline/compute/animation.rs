@@ -1,5 +1,5 @@
 use super::{AnimationData, ComputeData};
-use crate::{voxel_pipeline::voxel_world::VoxelData, RenderGraphSettings};
+use crate::{voxel_pipeline::voxel_world::VoxelData, RenderGraphSettings, VoxelEngineSettings};
 use bevy::{
     prelude::*,
     render::{
@@ -18,6 +18,7 @@ pub struct Pipeline(CachedComputePipelineId);
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
         let voxel_bind_group_layout = world.resource::<VoxelData>().bind_group_layout.clone();
+        let shader_defs = world.resource::<VoxelEngineSettings>().shader_defs();
         let compute_bind_group_layout = world.resource::<ComputeData>().bind_group_layout.clone();
 
         let asset_server = world.resource_mut::<AssetServer>();
@@ -29,7 +30,7 @@ impl FromWorld for Pipeline {
             label: Some(Cow::from("animation pipeline")),
             layout: vec![voxel_bind_group_layout, compute_bind_group_layout],
             shader,
-            shader_defs: vec![],
+            shader_defs,
             entry_point: Cow::from("animation"),
             push_constant_ranges: vec![],
         });
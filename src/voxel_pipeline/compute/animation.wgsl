@@ -1,9 +1,18 @@
-#import bevy_voxel_engine::common::VoxelUniforms
+#import bevy_voxel_engine::common::{
+    VoxelUniforms,
+    voxel_material,
+    voxel_flags,
+    encode_voxel,
+    decode_narrow_voxel,
+    chunk_position,
+}
 
 #import bevy_voxel_engine::bindings::{
-    voxel_worlds,
     voxel_uniforms,
-    gh
+    gh,
+    get_chunk_index,
+    load_voxel,
+    store_voxel,
 }
 
 struct ComputeUniforms {
@@ -16,22 +25,13 @@ var<uniform> compute_uniforms: ComputeUniforms;
 @group(1) @binding(2)
 var<storage, read> animation_data: array<u32>;
 
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
-        }
-    }
-    return -1; // Out of bounds
-}
 
 fn get_texture_value(pos: vec3<i32>, chunk_index: i32) -> vec2<u32> {
-    let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
-    let texture_value = textureLoad(voxel_worlds[chunk_index], chunk_pos.zyx).r;
+    let chunk_pos = chunk_position(pos, voxel_uniforms.chunk_size);
+    let texture_value = load_voxel(chunk_index, chunk_pos);
     return vec2(
-        texture_value & 0xFFu,
-        texture_value >> 8u,
+        voxel_material(texture_value),
+        voxel_flags(texture_value),
     );
 }
 
@@ -41,10 +41,10 @@ fn write_pos(pos: vec3<i32>, material: u32, flags: u32) {
         return; // Skip if out of bounds
     }
     
-    let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
+    let chunk_pos = chunk_position(pos, voxel_uniforms.chunk_size);
     let voxel_type = get_texture_value(pos, chunk_index);
     if (voxel_type.x == 0u) {
-        textureStore(voxel_worlds[chunk_index], chunk_pos.zyx, vec4<u32>(material | (flags << 8u)));
+        store_voxel(chunk_index, chunk_pos, encode_voxel(material, flags));
     }
 }
 
@@ -110,6 +110,24 @@ fn animation(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                     }
                 }
             }
+        } else if (data_type == 3) {
+            // Prefabs, texture_pos is the corner and the voxels follow the size
+            let size = vec3(
+                bitcast<i32>(animation_data[data_index + 5]),
+                bitcast<i32>(animation_data[data_index + 6]),
+                bitcast<i32>(animation_data[data_index + 7]),
+            );
+
+            for (var x = 0; x < size.x; x++) {
+                for (var y = 0; y < size.y; y++) {
+                    for (var z = 0; z < size.z; z++) {
+                        let voxel = decode_narrow_voxel(animation_data[data_index + 8 + (x * size.y + y) * size.z + z]);
+                        if (voxel_material(voxel) != 0u) {
+                            write_pos(texture_pos + vec3(x, y, z), voxel_material(voxel), voxel_flags(voxel) | flags);
+                        }
+                    }
+                }
+            }
         }
     }
 }
@@ -1,7 +1,7 @@
 use super::ComputeData;
 use crate::{
-    voxel_pipeline::voxel_world::{VoxelData, VoxelUniforms},
-    RenderGraphSettings,
+    voxel_pipeline::voxel_world::{ExtraVoxelData, VoxelData},
+    RenderGraphSettings, VoxelEngineSettings,
 };
 use bevy::{
     prelude::*,
@@ -21,6 +21,7 @@ pub struct Pipeline(CachedComputePipelineId);
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
         let voxel_bind_group_layout = world.resource::<VoxelData>().bind_group_layout.clone();
+        let shader_defs = world.resource::<VoxelEngineSettings>().shader_defs();
         let compute_bind_group_layout = world.resource::<ComputeData>().bind_group_layout.clone();
 
         let asset_server = world.resource_mut::<AssetServer>();
@@ -32,7 +33,7 @@ impl FromWorld for Pipeline {
             label: Some(Cow::from("automata pipeline")),
             layout: vec![voxel_bind_group_layout, compute_bind_group_layout],
             shader,
-            shader_defs: vec![],
+            shader_defs,
             entry_point: Cow::from("automata"),
             push_constant_ranges: vec![],
         });
@@ -49,10 +50,9 @@ impl render_graph::Node for AutomataNode {
         world: &World,
     ) -> Result<(), NodeRunError> {
         let voxel_data = world.resource::<VoxelData>();
+        let extra_voxel_data = world.resource::<ExtraVoxelData>();
         let compute_data = world.resource::<ComputeData>();
-        let voxel_uniforms = world.resource::<VoxelUniforms>();
         let pipeline_cache = world.resource::<PipelineCache>();
-        let dispatch_size = voxel_uniforms.texture_size / 4;
         let render_graph_settings = world.resource::<RenderGraphSettings>();
 
         if !render_graph_settings.automata {
@@ -68,11 +68,15 @@ impl render_graph::Node for AutomataNode {
             .command_encoder()
             .begin_compute_pass(&ComputePassDescriptor::default());
 
-        pass.set_bind_group(0, &voxel_data.bind_group, &[]);
         pass.set_bind_group(1, &compute_data.bind_group, &[]);
-
         pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+
+        // the extra worlds run their automata too
+        for voxel_data in std::iter::once(voxel_data).chain(extra_voxel_data.values()) {
+            let dispatch_size = voxel_data.uniform_buffer.get().grid_dispatch_size();
+            pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+            pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
+        }
 
         Ok(())
     }
@@ -4,14 +4,21 @@
     AUTOMATA_FLAG,
     ANIMATION_FLAG,
     COLLISION_FLAG,
+    voxel_material,
+    voxel_flags,
+    encode_voxel,
     hash,
-    snoise
+    snoise,
+    grid_texture_position,
+    chunk_position,
 }
 
 #import bevy_voxel_engine::bindings::{
-    voxel_worlds,
     voxel_uniforms,
-    gh
+    gh,
+    get_chunk_index,
+    load_voxel,
+    store_voxel,
 }
 
 struct ComputeUniforms {
@@ -24,15 +31,6 @@ var<uniform> compute_uniforms: ComputeUniforms;
 @group(1) @binding(1)
 var<storage, read_write> physics_data: array<u32>;
 
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
-        }
-    }
-    return -1; // Out of bounds
-}
 
 
 fn in_texture_bounds(pos: vec3<i32>) -> bool {
@@ -41,46 +39,50 @@ fn in_texture_bounds(pos: vec3<i32>) -> bool {
 
 
 fn get_texture_value(pos: vec3<i32>, chunk_index: i32) -> vec2<u32> {
-    let texture_value = textureLoad(voxel_worlds[chunk_index], pos.zyx).r;
+    let texture_value = load_voxel(chunk_index, pos);
     return vec2(
-        texture_value & 0xFFu,
-        texture_value >> 8u,
+        voxel_material(texture_value),
+        voxel_flags(texture_value),
     );
 }
 
 fn write_pos(pos: vec3<i32>, material: u32, flags: u32, chunk_index: i32) {
     let voxel_type = get_texture_value(pos, chunk_index);
     if (voxel_type.x == 0u) {
-        textureStore(voxel_worlds[chunk_index], pos.zyx, vec4(material | (flags << 8u)));
+        store_voxel(chunk_index, pos, encode_voxel(material, flags));
     }
 }
 @compute @workgroup_size(4, 4, 4)
 fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let chunk_index = get_chunk_index(vec3<f32>(invocation_id));
+    let texture_pos = grid_texture_position(invocation_id, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid);
+    let chunk_index = get_chunk_index(vec3<f32>(texture_pos));
     if (chunk_index == -1) {
         return; // Skip if out of bounds
     }
 
-    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z)) % vec3(i32(voxel_uniforms.chunk_size));
-    let pos_seed = vec3<u32>(pos);
-    let pos_time_seed = vec3<u32>(vec3<f32>(pos) + compute_uniforms.time * 240.0);
+    let pos = vec3<i32>(invocation_id % voxel_uniforms.chunk_size);
+    // seeded by the position in the grid so neighbouring chunks differ
+    let pos_seed = vec3<u32>(texture_pos);
+    let pos_time_seed = vec3<u32>(vec3<f32>(texture_pos) + compute_uniforms.time * 240.0);
 
     let material = get_texture_value(pos, chunk_index);
+    let ids = voxel_uniforms.material_ids;
 
     // grass
     let pos_rand = hash(pos_seed + 100u);
 
-    if (material.x == 44u && (material.y & ANIMATION_FLAG) == 0u && hash(pos_seed + 50u).x >= 0.5) {
+    if (material.x == ids.grass && (material.y & ANIMATION_FLAG) == 0u && hash(pos_seed + 50u).x >= 0.5) {
         for (var i = 1; i < 4 + i32(pos_rand.y * 3.0 - 0.5); i += 1) {
             let i = f32(i);
 
             let offset = vec3(
-                3.0 * snoise(vec3<f32>(pos) / 50.0 + compute_uniforms.time * 0.3) - 0.5, 
+                3.0 * snoise(vec3<f32>(texture_pos) / 50.0 + compute_uniforms.time * 0.3) - 0.5, 
                 i, 
-                3.0 * snoise(vec3<f32>(pos) / 50.0 + compute_uniforms.time * 0.3) - 0.5
+                3.0 * snoise(vec3<f32>(texture_pos) / 50.0 + compute_uniforms.time * 0.3) - 0.5
             );
 
-            let new_pos = vec3<f32>(pos) + vec3(
+            // blades can grow into the chunk above or next to this one
+            let new_pos = vec3<f32>(texture_pos) + vec3(
                 ((i - 1.0) / 4.0) * offset.x, 
                 offset.y, 
                 ((i - 1.0) / 4.0) * offset.z
@@ -88,7 +90,8 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
             let new_chunk_index = get_chunk_index(new_pos);
             if (new_chunk_index != -1) {
-                write_pos(vec3<i32>(new_pos) % vec3(i32(voxel_uniforms.chunk_size)), u32(i), ANIMATION_FLAG, new_chunk_index);
+                let blade = min(ids.grass_blades.x + u32(i) - 1u, ids.grass_blades.y);
+                write_pos(chunk_position(vec3<i32>(floor(new_pos)), voxel_uniforms.chunk_size), blade, ANIMATION_FLAG, new_chunk_index);
             }
         }
     }
@@ -145,46 +148,44 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     // sand
     if (material.x != 0u && (material.y & SAND_FLAG) > 0u) {
+        // sand stays in its chunk like water
         let new_pos = pos + vec3(0, -1, 0);
-        let new_chunk_index = get_chunk_index(vec3<f32>(new_pos));
-        if (new_chunk_index != -1) {
-            let new_mat = get_texture_value(new_pos % vec3(i32(voxel_uniforms.chunk_size)), new_chunk_index);
-
-            if (in_texture_bounds(new_pos) && new_mat.x == 0u) {
-                textureStore(voxel_worlds[new_chunk_index], new_pos.zyx, vec4(material.x | (material.y << 8u)));
-                textureStore(voxel_worlds[new_chunk_index], pos.zyx, vec4(0u));
-            } else {
-                let rand = hash(pos_time_seed);
-                for (var i = 0; i < 4; i += 1) {
-                    // start in a random direction
-                    i = (i + i32(4.0 * rand.x)) % 4;
-
-                    var offset: vec3<i32>;
-                    if (i == 0) {
-                        offset = vec3(1, -1, 0);
-                    } else if (i == 1) {
-                        offset = vec3(-1, -1, 0);
-                    } else if (i == 2) {
-                        offset = vec3(0, -1, 1);
-                    } else if (i == 3) {
-                        offset = vec3(0, -1, -1);
-                    }
+        let new_mat = get_texture_value(new_pos, chunk_index);
 
-                    let new_pos = pos + offset;
-                    let new_mat = get_texture_value(new_pos, new_chunk_index);
+        if (in_texture_bounds(new_pos) && new_mat.x == 0u) {
+            store_voxel(chunk_index, new_pos, encode_voxel(material.x, material.y));
+            store_voxel(chunk_index, pos, 0u);
+        } else {
+            let rand = hash(pos_time_seed);
+            for (var i = 0; i < 4; i += 1) {
+                // start in a random direction
+                i = (i + i32(4.0 * rand.x)) % 4;
 
-                    if (in_texture_bounds(new_pos) && new_mat.x == 0u) {
-                        textureStore(voxel_worlds[new_chunk_index], new_pos.zyx, vec4(material.x | (material.y << 8u)));
-                        textureStore(voxel_worlds[new_chunk_index], pos.zyx, vec4(0u));
-                        break;
-                    }
+                var offset: vec3<i32>;
+                if (i == 0) {
+                    offset = vec3(1, -1, 0);
+                } else if (i == 1) {
+                    offset = vec3(-1, -1, 0);
+                } else if (i == 2) {
+                    offset = vec3(0, -1, 1);
+                } else if (i == 3) {
+                    offset = vec3(0, -1, -1);
+                }
+
+                let new_pos = pos + offset;
+                let new_mat = get_texture_value(new_pos, chunk_index);
+
+                if (in_texture_bounds(new_pos) && new_mat.x == 0u) {
+                    store_voxel(chunk_index, new_pos, encode_voxel(material.x, material.y));
+                    store_voxel(chunk_index, pos, 0u);
+                    break;
                 }
             }
         }
     }
 
     // fire
-    if (material.x >= 9u && material.x <= 13u) {
+    if (material.x >= ids.fire.x && material.x <= ids.fire.y) {
         let rand = hash(pos_time_seed + 1u);
         let i = i32(5.0 * rand.x);
 
@@ -204,12 +205,14 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         let new_pos = pos + offset;
         let new_mat = get_texture_value(new_pos,  chunk_index);
         if (in_texture_bounds(new_pos) && new_mat.x == 0u && rand.z > 0.08) {
-            let new_material = min(material.x + u32(rand.y * 1.3), 13u);
-            textureStore(voxel_worlds[chunk_index], new_pos.zyx, vec4(new_material | (AUTOMATA_FLAG << 8u)));
+            let new_material = min(material.x + u32(rand.y * 1.3), ids.fire.y);
+            store_voxel(chunk_index, new_pos, encode_voxel(new_material, AUTOMATA_FLAG));
         }
 
-        if (rand.y < (f32(material.x) + 7.0) / 20.0 && (material.y & AUTOMATA_FLAG) > 0u) {
-            textureStore(voxel_worlds[chunk_index], pos.zyx, vec4(0u));
+        // later stages burn out sooner
+        let stage = f32(material.x - ids.fire.x);
+        if (rand.y < (stage + 16.0) / 20.0 && (material.y & AUTOMATA_FLAG) > 0u) {
+            store_voxel(chunk_index, pos, 0u);
         }
     }
 
@@ -252,13 +255,13 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     */
 
     // water
-    if (material.x == 8u && (material.y & ANIMATION_FLAG) == 0u) {
+    if (material.x == ids.water && (material.y & ANIMATION_FLAG) == 0u) {
         let new_pos = pos + vec3(0, -1, 0);
         let new_mat = get_texture_value(new_pos, chunk_index);
 
         if (in_texture_bounds(new_pos) && new_mat.x == 0u) {
-            textureStore(voxel_worlds[chunk_index], new_pos.zyx, vec4(material.x | (material.y << 8u)));
-            textureStore(voxel_worlds[chunk_index], pos.zyx, vec4(0u));
+            store_voxel(chunk_index, new_pos, encode_voxel(material.x, material.y));
+            store_voxel(chunk_index, pos, 0u);
         } else {
             let rand = hash(pos_time_seed);
             for (var i = 0; i < 4; i += 1) {
@@ -293,7 +296,7 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                         let check_pos = pos + offset + check;
                         let check_mat = get_texture_value(new_pos,  chunk_index);
 
-                        if (in_texture_bounds(check_pos) && check_mat.x == 8u) {
+                        if (in_texture_bounds(check_pos) && check_mat.x == ids.water) {
                             safe = false;
                             break;
                         }
@@ -305,8 +308,8 @@ fn automata(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                     let new_mat = get_texture_value(new_pos,  chunk_index);
 
                     if (in_texture_bounds(new_pos) && new_mat.x == 0u) {
-                        textureStore(voxel_worlds[chunk_index], new_pos.zyx, vec4(material.x | (material.y << 8u)));
-                        textureStore(voxel_worlds[chunk_index], pos.zyx, vec4(0u));
+                        store_voxel(chunk_index, new_pos, encode_voxel(material.x, material.y));
+                        store_voxel(chunk_index, pos, 0u);
                     }
 
                     break;
@@ -0,0 +1,498 @@
+use crate::{
+    voxel_pipeline::{
+        save::{padded_row_length, MapResult},
+        voxel_world::{BrickCopy, ChunkWrite, ExtraVoxelData, VoxelData, BRICK_SIZE},
+    },
+    VoxelEngineSettings,
+};
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource,
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+    },
+};
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+/// Counts of `BrickAllocator` in common.wgsl before the free list.
+const ALLOCATOR_HEADER: usize = 5;
+
+/// Byte offset of the dropped writes in the allocator.
+const DROPPED_OFFSET: u64 = 12;
+
+/// Voxel writes into empty space that [`ChunkStorage::Bricks`] dropped
+/// because the brick pool was full, summed over every world. Read back from
+/// the gpu a few frames after the writes, a warning is logged when it grows.
+/// Raise [`VoxelEngineSettings::brick_capacity`] if it does.
+///
+/// [`ChunkStorage::Bricks`]: crate::ChunkStorage::Bricks
+#[derive(Resource, ExtractResource, Clone, Default)]
+pub struct BrickPoolOverflow(Arc<AtomicU32>);
+
+impl BrickPoolOverflow {
+    pub fn dropped_writes(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Adds what a world dropped since its last total, returns how much that
+    /// was.
+    fn record(&self, last_total: &mut u32, total: u32) -> u32 {
+        let dropped = total.wrapping_sub(*last_total);
+        *last_total = total;
+        self.0.fetch_add(dropped, Ordering::Relaxed);
+        dropped
+    }
+}
+
+/// Only exists with [`ChunkStorage::Bricks`](crate::ChunkStorage::Bricks).
+#[derive(Resource)]
+pub struct Pipelines {
+    release: CachedComputePipelineId,
+    refill: CachedComputePipelineId,
+    pack: CachedComputePipelineId,
+    unpack: CachedComputePipelineId,
+}
+
+impl FromWorld for Pipelines {
+    fn from_world(world: &mut World) -> Self {
+        let voxel_data = world.resource::<VoxelData>();
+        let voxel_bind_group_layout = voxel_data.bind_group_layout.clone();
+        let copy_bind_group_layout = voxel_data
+            .bricks
+            .as_ref()
+            .unwrap()
+            .copy_bind_group_layout
+            .clone();
+        let shader_defs = world.resource::<VoxelEngineSettings>().shader_defs();
+
+        let asset_server = world.resource_mut::<AssetServer>();
+        let shader = asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/compute/bricks.wgsl");
+
+        let pipeline_cache = world.resource_mut::<PipelineCache>();
+
+        let queue_pipeline = |entry_point: &'static str, copy: bool| {
+            let mut layout = vec![voxel_bind_group_layout.clone()];
+            if copy {
+                layout.push(copy_bind_group_layout.clone());
+            }
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some(Cow::from(format!("{} pipeline", entry_point))),
+                layout,
+                shader: shader.clone(),
+                shader_defs: shader_defs.clone(),
+                entry_point: Cow::from(entry_point),
+                push_constant_ranges: vec![],
+            })
+        };
+
+        Pipelines {
+            release: queue_pipeline("release_bricks", false),
+            refill: queue_pipeline("refill_free_list", false),
+            pack: queue_pipeline("pack_bricks", true),
+            unpack: queue_pipeline("unpack_chunk", true),
+        }
+    }
+}
+
+impl Pipelines {
+    pub fn unpack<'a>(&self, pipeline_cache: &'a PipelineCache) -> Option<&'a ComputePipeline> {
+        pipeline_cache.get_compute_pipeline(self.unpack)
+    }
+}
+
+/// Hands the empty bricks of every active chunk back to the pool, right after
+/// the grid hierarchies of the active chunks are rebuilt.
+pub(crate) fn release_bricks<'a>(
+    pass: &mut ComputePass<'a>,
+    voxel_data: &'a VoxelData,
+    pipelines: &Pipelines,
+    pipeline_cache: &'a PipelineCache,
+) {
+    let (Some(release), Some(refill)) = (
+        pipeline_cache.get_compute_pipeline(pipelines.release),
+        pipeline_cache.get_compute_pipeline(pipelines.refill),
+    ) else {
+        return;
+    };
+
+    let dispatch_size = release_dispatch_size(voxel_data.chunk_size, voxel_data.chunk_grid);
+    pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+    pass.set_pipeline(release);
+    pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
+    pass.set_pipeline(refill);
+    pass.dispatch_workgroups(1, 1, 1);
+}
+
+/// Copies the chunk writes of this frame into the bricks once the pipelines
+/// are ready, all in one submit per world, and reads back how many writes the
+/// full pool dropped.
+pub(crate) fn flush_chunk_writes(
+    mut voxel_data: ResMut<VoxelData>,
+    mut extra_voxel_data: ResMut<ExtraVoxelData>,
+    (pipelines, pipeline_cache): (Res<Pipelines>, Res<PipelineCache>),
+    overflow: Res<BrickPoolOverflow>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let (Some(pack), Some(refill)) = (
+        pipeline_cache.get_compute_pipeline(pipelines.pack),
+        pipeline_cache.get_compute_pipeline(pipelines.refill),
+    ) else {
+        return;
+    };
+
+    for voxel_data in std::iter::once(voxel_data.as_mut()).chain(extra_voxel_data.values_mut()) {
+        flush_world_writes(voxel_data, (pack, refill), &render_device, &render_queue);
+        read_back_dropped(voxel_data, &overflow, &render_device);
+    }
+}
+
+fn flush_world_writes(
+    voxel_data: &mut VoxelData,
+    (pack, refill): (&ComputePipeline, &ComputePipeline),
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+) {
+    let Some(bricks) = voxel_data.bricks.as_mut() else {
+        return;
+    };
+    let writes = std::mem::take(&mut bricks.pending);
+    let read_back = bricks.dropped_map.is_none();
+    if writes.is_empty() && !read_back {
+        return;
+    }
+
+    let bricks = voxel_data.bricks.as_ref().unwrap();
+    let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("pack bricks"),
+    });
+    if !writes.is_empty() {
+        let bytes_per_voxel = voxel_data.voxel_format.bytes_per_voxel();
+        let (staging, offsets) = staging_data(&writes, bytes_per_voxel);
+        let staging = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("brick writes"),
+            contents: &staging,
+            usage: BufferUsages::COPY_SRC,
+        });
+        let copy_size = BrickCopy::SHADER_SIZE.get();
+        let copies = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("brick copies"),
+            contents: &copy_data(&writes),
+            usage: BufferUsages::COPY_SRC,
+        });
+
+        // the staging texture and copy uniform are reused, each write is
+        // copied in right before it is packed
+        for (i, (write, offset)) in writes.iter().zip(offsets).enumerate() {
+            command_encoder.copy_buffer_to_texture(
+                ImageCopyBuffer {
+                    buffer: &staging,
+                    layout: ImageDataLayout {
+                        offset,
+                        bytes_per_row: Some(padded_row_length(write.size.z * bytes_per_voxel)),
+                        rows_per_image: Some(write.size.y),
+                    },
+                },
+                ImageCopyTexture {
+                    texture: &bricks.staging,
+                    mip_level: 0,
+                    origin: Origin3d {
+                        x: write.min.z,
+                        y: write.min.y,
+                        z: write.min.x,
+                    },
+                    aspect: TextureAspect::All,
+                },
+                Extent3d {
+                    width: write.size.z,
+                    height: write.size.y,
+                    depth_or_array_layers: write.size.x,
+                },
+            );
+            command_encoder.copy_buffer_to_buffer(
+                &copies,
+                i as u64 * copy_size,
+                &bricks.copy,
+                0,
+                copy_size,
+            );
+
+            let dispatch_size = pack_dispatch_size(write.min, write.size);
+            let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+            pass.set_bind_group(1, &bricks.copy_bind_group, &[]);
+            pass.set_pipeline(pack);
+            pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
+            pass.set_pipeline(refill);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+    }
+
+    if read_back {
+        command_encoder.copy_buffer_to_buffer(
+            &bricks.allocator,
+            DROPPED_OFFSET,
+            &bricks.dropped_read_back,
+            0,
+            4,
+        );
+    }
+    render_queue.submit([command_encoder.finish()]);
+
+    if read_back {
+        let mapped = MapResult::default();
+        let result = mapped.clone();
+        bricks
+            .dropped_read_back
+            .slice(..)
+            .map_async(MapMode::Read, move |r| _ = result.set(r));
+        voxel_data.bricks.as_mut().unwrap().dropped_map = Some(mapped);
+    }
+}
+
+/// Collects the count of dropped writes once it is mapped and warns if it
+/// grew.
+fn read_back_dropped(
+    voxel_data: &mut VoxelData,
+    overflow: &BrickPoolOverflow,
+    render_device: &RenderDevice,
+) {
+    let Some(bricks) = voxel_data.bricks.as_mut() else {
+        return;
+    };
+    let Some(mapped) = &bricks.dropped_map else {
+        return;
+    };
+    render_device.poll(wgpu::Maintain::Poll);
+    let Some(result) = mapped.get() else {
+        return;
+    };
+
+    if result.is_ok() {
+        let slice = bricks.dropped_read_back.slice(..);
+        let total = u32::from_le_bytes(slice.get_mapped_range()[..4].try_into().unwrap());
+        bricks.dropped_read_back.unmap();
+        let dropped = overflow.record(&mut bricks.dropped, total);
+        if dropped > 0 {
+            warn!(
+                "The brick pool is full, {} voxel writes were dropped. Raise the brick capacity",
+                dropped
+            );
+        }
+    }
+    bricks.dropped_map = None;
+}
+
+/// Initial contents of the allocator, every brick is still free.
+pub(crate) fn allocator_contents(capacity: u32) -> Vec<u32> {
+    let mut allocator = vec![0; ALLOCATOR_HEADER + 2 * capacity as usize];
+    allocator[ALLOCATOR_HEADER - 1] = capacity;
+    allocator
+}
+
+/// The writes one after another with their rows padded for a texture copy,
+/// and the offset of every write.
+fn staging_data(writes: &[ChunkWrite], bytes_per_voxel: u32) -> (Vec<u8>, Vec<u64>) {
+    let mut data = Vec::new();
+    let mut offsets = Vec::with_capacity(writes.len());
+    for write in writes {
+        let row_length = (write.size.z * bytes_per_voxel) as usize;
+        let padding = padded_row_length(row_length as u32) as usize - row_length;
+        offsets.push(data.len() as u64);
+        for row in write.data.chunks_exact(row_length) {
+            data.extend_from_slice(row);
+            data.resize(data.len() + padding, 0);
+        }
+    }
+    (data, offsets)
+}
+
+/// The [`BrickCopy`] of every write in the layout of the copy uniform.
+fn copy_data(writes: &[ChunkWrite]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for write in writes {
+        let mut bytes = encase::UniformBuffer::new(Vec::new());
+        bytes
+            .write(&BrickCopy {
+                chunk_index: write.texture_index as u32,
+                min: write.min,
+                size: write.size,
+            })
+            .unwrap();
+        data.extend_from_slice(bytes.as_ref());
+    }
+    data
+}
+
+/// Workgroups of `pack_bricks`, one invocation per brick the box touches.
+fn pack_dispatch_size(min: UVec3, size: UVec3) -> UVec3 {
+    let first = min / BRICK_SIZE;
+    let last = (min + size - 1) / BRICK_SIZE;
+    (last - first + 4) / 4
+}
+
+/// Workgroups of `release_bricks`, one invocation per brick sized cell of
+/// every active chunk with the chunks along z.
+fn release_dispatch_size(chunk_size: u32, chunk_grid: UVec3) -> UVec3 {
+    let cells = chunk_size / BRICK_SIZE;
+    let chunk_count = chunk_grid.x * chunk_grid.y * chunk_grid.z;
+    (UVec3::new(cells, cells, cells * chunk_count) + 3) / 4
+}
+
+/// Copies a chunk out of its bricks into the staging texture, to be read
+/// back from there.
+pub(crate) fn unpack_chunk(
+    command_encoder: &mut CommandEncoder,
+    voxel_data: &VoxelData,
+    unpack: &ComputePipeline,
+    render_queue: &RenderQueue,
+    texture_index: usize,
+) {
+    let bricks = voxel_data.bricks.as_ref().unwrap();
+    bricks.set_copy(
+        render_queue,
+        BrickCopy {
+            chunk_index: texture_index as u32,
+            min: UVec3::ZERO,
+            size: UVec3::splat(voxel_data.chunk_size),
+        },
+    );
+
+    let dispatch_size = voxel_data.chunk_size / 4;
+    let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+    pass.set_bind_group(0, &voxel_data.bind_group, &[]);
+    pass.set_bind_group(1, &bricks.copy_bind_group, &[]);
+    pass.set_pipeline(unpack);
+    pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load::GridHierarchy,
+        voxel_pipeline::voxel_world::{active_chunk_position, chunk_texture_index},
+    };
+
+    fn write(min: UVec3, size: UVec3, bytes_per_voxel: u32) -> ChunkWrite {
+        let length = (size.x * size.y * size.z * bytes_per_voxel) as usize;
+        ChunkWrite {
+            texture_index: 2,
+            min,
+            size,
+            data: (0..length).map(|i| i as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn allocator_matches_the_shader() {
+        let allocator = allocator_contents(8);
+        assert_eq!(allocator.len(), ALLOCATOR_HEADER + 16);
+        assert_eq!(allocator[..ALLOCATOR_HEADER], [0, 0, 0, 0, 8]);
+
+        let common = include_str!("../shaders/common.wgsl");
+        let header = "    free_count: atomic<i32>,\n    next_brick: atomic<u32>,\n    \
+                      freed_count: atomic<u32>,\n    dropped: atomic<u32>,\n    capacity: u32,";
+        assert!(common.contains(header));
+        assert_eq!(DROPPED_OFFSET, 3 * 4);
+    }
+
+    #[test]
+    fn counts_writes_dropped_since_the_last_read_back() {
+        let overflow = BrickPoolOverflow::default();
+        let (mut main, mut extra) = (0, 0);
+        assert_eq!(overflow.record(&mut main, 0), 0);
+        assert_eq!(overflow.record(&mut main, 5), 5);
+        assert_eq!(overflow.record(&mut extra, 2), 2);
+        assert_eq!(overflow.record(&mut main, 5), 0);
+        assert_eq!(overflow.record(&mut main, 9), 4);
+        assert_eq!(overflow.dropped_writes(), 11);
+    }
+
+    #[test]
+    fn batches_writes_with_padded_rows() {
+        let writes = [
+            write(UVec3::ZERO, UVec3::new(2, 3, 4), 2),
+            write(UVec3::new(8, 0, 1), UVec3::new(1, 1, 200), 2),
+        ];
+        let (data, offsets) = staging_data(&writes, 2);
+
+        // 6 rows of 256 bytes, then 1 row of 512
+        assert_eq!(offsets, [0, 6 * 256]);
+        assert_eq!(data.len(), 6 * 256 + 512);
+        assert_eq!(data[..8], writes[0].data[..8]);
+        assert_eq!(data[8..256], [0; 248]);
+        assert_eq!(data[256..264], writes[0].data[8..16]);
+        assert_eq!(data[6 * 256..6 * 256 + 400], writes[1].data[..]);
+
+        let copies = copy_data(&writes);
+        let copy_size = BrickCopy::SHADER_SIZE.get() as usize;
+        assert_eq!(copies.len(), 2 * copy_size);
+        assert_eq!(copies[copy_size..copy_size + 4], 2u32.to_le_bytes());
+    }
+
+    #[test]
+    fn dispatches_cover_every_brick() {
+        // a box across a brick boundary touches two bricks along x
+        let size = pack_dispatch_size(UVec3::new(7, 0, 0), UVec3::new(2, 8, 8));
+        assert_eq!(size, UVec3::ONE);
+        let size = pack_dispatch_size(UVec3::ZERO, UVec3::new(256, 8, 40));
+        assert_eq!(size, UVec3::new(8, 1, 2));
+
+        // every cell of all 27 chunks
+        let size = release_dispatch_size(256, UVec3::splat(3));
+        assert_eq!(size, UVec3::new(8, 8, 27 * 8));
+        let size = release_dispatch_size(8, UVec3::new(5, 3, 1));
+        assert_eq!(size, UVec3::new(1, 1, 4));
+    }
+
+    /// `pyramid_start` in common.wgsl.
+    fn pyramid_start(chunk_index: usize, offsets: &[u32; 8], levels: &[u32; 8]) -> usize {
+        chunk_index * (offsets[7] + levels[7].pow(3)) as usize
+    }
+
+    #[test]
+    fn released_bricks_check_the_hierarchy_of_their_chunk() {
+        let release = include_str!("bricks.wgsl");
+        let release = &release[release.find("fn release_bricks").unwrap()..];
+        assert!(release.contains("let index = start + voxel_uniforms.offsets[level].x"));
+        let rebuild = include_str!("rebuild.wgsl");
+        assert!(rebuild.contains("let index0 = start + voxel_uniforms.offsets[0].x"));
+
+        // stream the origin chunk out of texture 0
+        let chunk_size = 64;
+        let grid = UVec3::splat(3);
+        let origin = IVec3::new(1, 0, -1);
+        let texture_index = chunk_texture_index(origin, grid);
+        assert_ne!(texture_index, 0);
+        assert_eq!(active_chunk_position(texture_index, origin, grid), origin);
+
+        let gh = GridHierarchy::empty(chunk_size);
+        let (levels, offsets) = (gh.levels, gh.get_offsets());
+        let level = levels.iter().position(|&size| size == chunk_size / BRICK_SIZE).unwrap();
+        let cell_bits = |chunk_index: usize, pos: UVec3| {
+            let size = levels[level];
+            let pos = pos * size / chunk_size;
+            pyramid_start(chunk_index, &offsets, &levels)
+                + (offsets[level] + (pos.x * size + pos.y) * size + pos.z) as usize
+        };
+
+        // rebuild_gh sets the cell of a voxel in the origin chunk
+        let mut hierarchies = vec![false; gh.get_buffer_size() * 8 * 27];
+        let voxel = UVec3::new(17, 40, 3);
+        hierarchies[cell_bits(texture_index, voxel)] = true;
+
+        // release_bricks keeps the brick of the voxel and frees the same brick
+        // of texture 0
+        let brick = voxel / BRICK_SIZE * BRICK_SIZE;
+        assert!(hierarchies[cell_bits(texture_index, brick)]);
+        assert!(!hierarchies[cell_bits(0, brick)]);
+    }
+}
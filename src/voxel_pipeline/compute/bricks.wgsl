@@ -0,0 +1,172 @@
+#import bevy_voxel_engine::common::{
+    BRICK_SIZE,
+    voxel_material,
+    in_chunk,
+    brick_cell,
+    brick_position,
+    pyramid_start,
+}
+#import bevy_voxel_engine::bindings::{
+    voxel_uniforms,
+    voxel_worlds,
+    gh,
+    brick_index,
+    brick_allocator,
+    load_voxel,
+    store_voxel,
+    free_brick,
+}
+
+struct BrickCopy {
+    chunk_index: u32,
+    min: vec3<u32>,
+    size: vec3<u32>,
+}
+
+#ifdef VOXEL_WIDE
+alias VoxelTexture = texture_storage_3d<r32uint, read_write>;
+#else
+alias VoxelTexture = texture_storage_3d<r16uint, read_write>;
+#endif
+
+@group(1) @binding(0) var staging: VoxelTexture;
+@group(1) @binding(1) var<uniform> brick_copy: BrickCopy;
+
+fn clear_brick(brick: u32) {
+    for (var x = 0; x < BRICK_SIZE; x++) {
+        for (var y = 0; y < BRICK_SIZE; y++) {
+            for (var z = 0; z < BRICK_SIZE; z++) {
+                textureStore(voxel_worlds, brick_position(brick, vec3(x, y, z)), vec4(0u));
+            }
+        }
+    }
+}
+
+// empty bricks of every active chunk are handed back, chunks are along z of
+// the dispatch. the grid hierarchies were just rebuilt so a brick is empty when
+// its cell in the hierarchy of its chunk isn't set. chunks too small for a
+// level of brick sized cells are checked voxel by voxel
+@compute @workgroup_size(4, 4, 4)
+fn release_bricks(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let cells = voxel_uniforms.chunk_size / u32(BRICK_SIZE);
+    let chunk_index = i32(invocation_id.z / cells);
+    let local_id = vec3(invocation_id.xy, invocation_id.z % cells);
+    if (any(local_id.xy >= vec2(cells)) || chunk_index >= #{ACTIVE_CHUNKS}) {
+        return;
+    }
+
+    let cell_min = vec3<i32>(local_id) * BRICK_SIZE;
+    let cell = brick_cell(chunk_index, cell_min, voxel_uniforms.chunk_size);
+    let brick = atomicLoad(&brick_index[cell]);
+    if (brick == 0u) {
+        return;
+    }
+
+    var empty = true;
+    var level = -1;
+    for (var i = 0; i < 8; i++) {
+        if (voxel_uniforms.levels[i].x == cells) {
+            level = i;
+        }
+    }
+    if (level != -1) {
+        let start = pyramid_start(chunk_index, voxel_uniforms.offsets[7].x, voxel_uniforms.levels[7].x);
+        let index = start + voxel_uniforms.offsets[level].x + (local_id.x * cells + local_id.y) * cells + local_id.z;
+        empty = (gh[index / 32u] & (1u << (index % 32u))) == 0u;
+    } else {
+        for (var i = 0; i < BRICK_SIZE * BRICK_SIZE * BRICK_SIZE && empty; i++) {
+            let pos = vec3(i / (BRICK_SIZE * BRICK_SIZE), i / BRICK_SIZE % BRICK_SIZE, i % BRICK_SIZE);
+            if (voxel_material(load_voxel(chunk_index, cell_min + pos)) != 0u) {
+                empty = false;
+            }
+        }
+    }
+
+    if (empty) {
+        atomicStore(&brick_index[cell], 0u);
+        clear_brick(brick - 1u);
+        free_brick(brick - 1u);
+    }
+}
+
+// moves the bricks freed since the last refill onto the free list, nothing
+// else may touch the allocator while this runs
+var<workgroup> free_count: i32;
+var<workgroup> freed_count: u32;
+
+@compute @workgroup_size(64)
+fn refill_free_list(@builtin(local_invocation_index) local_index: u32) {
+    if (local_index == 0u) {
+        free_count = atomicLoad(&brick_allocator.free_count);
+        freed_count = atomicLoad(&brick_allocator.freed_count);
+    }
+    workgroupBarrier();
+
+    let base = u32(free_count);
+    let freed = freed_count;
+    let capacity = brick_allocator.capacity;
+    for (var i = local_index; i < freed; i += 64u) {
+        brick_allocator.free[base + i] = brick_allocator.free[capacity + i];
+    }
+
+    if (local_index == 0u) {
+        atomicStore(&brick_allocator.free_count, i32(base + freed));
+        atomicStore(&brick_allocator.freed_count, 0u);
+    }
+}
+
+// copies a box of voxels from the staging texture into the bricks of a chunk,
+// one invocation per brick. bricks written over with nothing but air are freed
+@compute @workgroup_size(4, 4, 4)
+fn pack_bricks(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let brick_size = u32(BRICK_SIZE);
+    let cell_min = (brick_copy.min / brick_size + invocation_id) * brick_size;
+    let region_max = brick_copy.min + brick_copy.size;
+    if (any(cell_min >= region_max)) {
+        return;
+    }
+    let lo = max(cell_min, brick_copy.min);
+    let hi = min(cell_min + brick_size, region_max);
+    let chunk_index = i32(brick_copy.chunk_index);
+
+    if (all(lo == cell_min) && all(hi == cell_min + brick_size)) {
+        var empty = true;
+        for (var x = lo.x; x < hi.x; x++) {
+            for (var y = lo.y; y < hi.y; y++) {
+                for (var z = lo.z; z < hi.z; z++) {
+                    if (textureLoad(staging, vec3(z, y, x)).r != 0u) {
+                        empty = false;
+                    }
+                }
+            }
+        }
+
+        if (empty) {
+            let cell = brick_cell(chunk_index, vec3<i32>(cell_min), voxel_uniforms.chunk_size);
+            let brick = atomicExchange(&brick_index[cell], 0u);
+            if (brick != 0u) {
+                clear_brick(brick - 1u);
+                free_brick(brick - 1u);
+            }
+            return;
+        }
+    }
+
+    for (var x = lo.x; x < hi.x; x++) {
+        for (var y = lo.y; y < hi.y; y++) {
+            for (var z = lo.z; z < hi.z; z++) {
+                store_voxel(chunk_index, vec3<i32>(vec3(x, y, z)), textureLoad(staging, vec3(z, y, x)).r);
+            }
+        }
+    }
+}
+
+// copies a whole chunk out of its bricks into the staging texture
+@compute @workgroup_size(4, 4, 4)
+fn unpack_chunk(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let pos = vec3<i32>(invocation_id);
+    if (!in_chunk(pos, voxel_uniforms.chunk_size)) {
+        return;
+    }
+    textureStore(staging, pos.zyx, vec4(load_voxel(i32(brick_copy.chunk_index), pos)));
+}
@@ -1,6 +1,6 @@
 use crate::{
     voxel_pipeline::voxel_world::{VoxelData, VoxelUniforms},
-    RenderGraphSettings,
+    RenderGraphSettings, VoxelEngineSettings,
 };
 use bevy::{
     prelude::*,
@@ -20,6 +20,7 @@ pub struct Pipeline(CachedComputePipelineId);
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
         let voxel_bind_group_layout = world.resource::<VoxelData>().bind_group_layout.clone();
+        let shader_defs = world.resource::<VoxelEngineSettings>().shader_defs();
 
         let asset_server = world.resource_mut::<AssetServer>();
         let shader = asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/compute/clear.wgsl");
@@ -30,7 +31,7 @@ impl FromWorld for Pipeline {
             label: Some(Cow::from("clear pipeline")),
             layout: vec![voxel_bind_group_layout],
             shader,
-            shader_defs: vec![],
+            shader_defs,
             entry_point: Cow::from("clear"),
             push_constant_ranges: vec![],
         });
@@ -49,7 +50,7 @@ impl render_graph::Node for ClearNode {
         let voxel_data = world.resource::<VoxelData>();
         let voxel_uniforms = world.resource::<VoxelUniforms>();
         let pipeline_cache = world.resource::<PipelineCache>();
-        let dispatch_size = voxel_uniforms.texture_size / 4;
+        let dispatch_size = voxel_uniforms.grid_dispatch_size();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
 
         if !render_graph_settings.clear {
@@ -68,7 +69,7 @@ impl render_graph::Node for ClearNode {
         pass.set_bind_group(0, &voxel_data.bind_group, &[]);
 
         pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+        pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
 
         Ok(())
     }
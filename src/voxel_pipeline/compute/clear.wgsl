@@ -1,49 +1,44 @@
 #import bevy_voxel_engine::common::{
     VoxelUniforms,
     ANIMATION_FLAG,
-    PORTAL_FLAG
+    PORTAL_FLAG,
+    voxel_material,
+    voxel_flags,
+    grid_texture_position,
 }
 
 #import bevy_voxel_engine::bindings::{
-    voxel_worlds,
     voxel_uniforms,
-    gh
+    gh,
+    get_chunk_index,
+    load_voxel,
+    store_voxel,
 }
 
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
-        }
-    }
-    return -1; // Out of bounds
-}
 
-fn get_texture_value(pos: vec3<i32>, chunk_index: i32) -> vec2<u32> {
-    let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
-    let texture_value = textureLoad(voxel_worlds[chunk_index], chunk_pos.zyx).r;
+fn get_texture_value(chunk_pos: vec3<i32>, chunk_index: i32) -> vec2<u32> {
+    let texture_value = load_voxel(chunk_index, chunk_pos);
     return vec2(
-        texture_value & 0xFFu,
-        texture_value >> 8u,
+        voxel_material(texture_value),
+        voxel_flags(texture_value),
     );
 }
 
 @compute @workgroup_size(4, 4, 4)
 fn clear(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z));
+    let pos = grid_texture_position(invocation_id, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid);
     let chunk_index = get_chunk_index(vec3<f32>(pos));
     
     if (chunk_index == -1) {
         return; // Skip if out of bounds
     }
 
-    let material = get_texture_value(pos, chunk_index);
+    let chunk_pos = vec3<i32>(invocation_id % voxel_uniforms.chunk_size);
+    let material = get_texture_value(chunk_pos, chunk_index);
 
     // Delete old animation data
     if ((material.y & (ANIMATION_FLAG | PORTAL_FLAG)) > 0u) {
-        let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
-        textureStore(voxel_worlds[chunk_index], chunk_pos.zyx, vec4<u32>(0u));
+        store_voxel(chunk_index, chunk_pos, 0u);
         return;
     }
 }
@@ -1,3 +1,10 @@
+use crate::{
+    voxel_pipeline::{
+        edit::upload_chunk_edits,
+        voxel_world::{load_voxel_world_prepare, prepare_extra_worlds},
+    },
+    ChunkStorage, VoxelEngineSettings,
+};
 use bevy::{
     asset::embedded_asset,
     prelude::*,
@@ -12,11 +19,12 @@ use bevy::{
 
 pub mod animation;
 pub mod automata;
+pub mod bricks;
 pub mod clear;
 pub mod physics;
 pub mod rebuild;
 
-const MAX_TYPE_BUFFER_DATA: usize = 1000000; // 4mb
+pub(crate) const MAX_TYPE_BUFFER_DATA: usize = 1000000; // 4mb
 
 pub struct ComputeResourcesPlugin;
 
@@ -24,6 +32,7 @@ impl Plugin for ComputeResourcesPlugin {
     fn build(&self, app: &mut App) {
         embedded_asset!(app, "src/", "animation.wgsl");
         embedded_asset!(app, "src/", "automata.wgsl");
+        embedded_asset!(app, "src/", "bricks.wgsl");
         embedded_asset!(app, "src/", "clear.wgsl");
         embedded_asset!(app, "src/", "physics.wgsl");
         embedded_asset!(app, "src/", "rebuild.wgsl");
@@ -122,8 +131,10 @@ impl Plugin for ComputeResourcesPlugin {
             dispatch_size: 0,
             animation_buffer,
         })
+        .init_resource::<bricks::BrickPoolOverflow>()
         .add_plugins(ExtractResourcePlugin::<PhysicsData>::default())
-        .add_plugins(ExtractResourcePlugin::<AnimationData>::default());
+        .add_plugins(ExtractResourcePlugin::<AnimationData>::default())
+        .add_plugins(ExtractResourcePlugin::<bricks::BrickPoolOverflow>::default());
 
         let render_app = app.sub_app_mut(RenderApp);
 
@@ -139,6 +150,19 @@ impl Plugin for ComputeResourcesPlugin {
             .init_resource::<physics::Pipeline>()
             .init_resource::<animation::Pipeline>()
             .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare));
+
+        let settings = render_app.world().resource::<VoxelEngineSettings>();
+        if settings.chunk_storage == ChunkStorage::Bricks {
+            // in the frame the writes are made
+            render_app.init_resource::<bricks::Pipelines>().add_systems(
+                Render,
+                bricks::flush_chunk_writes
+                    .in_set(RenderSet::Prepare)
+                    .after(load_voxel_world_prepare)
+                    .after(prepare_extra_worlds)
+                    .after(upload_chunk_edits),
+            );
+        }
     }
 }
 
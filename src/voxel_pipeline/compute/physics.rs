@@ -1,5 +1,8 @@
 use super::{ComputeData, PhysicsData};
-use crate::{voxel_pipeline::voxel_world::VoxelData, RenderGraphSettings};
+use crate::{
+    voxel_pipeline::voxel_world::{TargetVoxelWorld, VoxelData},
+    RenderGraphSettings, VoxelEngineSettings,
+};
 use bevy::{
     prelude::*,
     render::{
@@ -18,6 +21,7 @@ pub struct Pipeline(CachedComputePipelineId);
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
         let voxel_bind_group_layout = world.resource::<VoxelData>().bind_group_layout.clone();
+        let shader_defs = world.resource::<VoxelEngineSettings>().shader_defs();
         let compute_bind_group_layout = world.resource::<ComputeData>().bind_group_layout.clone();
 
         let asset_server = world.resource::<AssetServer>();
@@ -29,7 +33,7 @@ impl FromWorld for Pipeline {
             label: Some(Cow::from("physics pipeline")),
             layout: vec![voxel_bind_group_layout, compute_bind_group_layout],
             shader,
-            shader_defs: vec![],
+            shader_defs,
             entry_point: Cow::from("physics"),
             push_constant_ranges: vec![],
         });
@@ -42,10 +46,15 @@ impl render_graph::Node for PhysicsNode {
 
     fn run(
         &self,
-        _graph: &mut RenderGraphContext,
+        graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
+        // the physics objects are in the main world
+        if world.get::<TargetVoxelWorld>(graph.view_entity()).is_some() {
+            return Ok(());
+        }
+
         let voxel_data = world.resource::<VoxelData>();
         let compute_data = world.resource::<ComputeData>();
         let pipeline_cache = world.resource::<PipelineCache>();
@@ -1,18 +1,23 @@
 #import bevy_voxel_engine::common::{
-    VOXELS_PER_METER,
     VoxelUniforms,
     Ray,
     COLLISION_FLAG,
+    voxel_flags,
+    with_voxel_flags,
+    decode_narrow_voxel,
+    chunk_position,
 }
 #import bevy_voxel_engine::raytracing::{
     IDENTITY,
     shoot_ray,
-    get_chunk_index,
+    world_origin,
 }
 #import bevy_voxel_engine::bindings::{
-    voxel_worlds,
     voxel_uniforms,
-    gh
+    gh,
+    get_chunk_index,
+    load_voxel,
+    store_voxel,
 }
 
 struct ComputeUniforms {
@@ -86,40 +91,41 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                     // Collision effects
 
                     let texture_coords = 
-                        vec3<i32>(world_pos * VOXELS_PER_METER + vec3(f32(voxel_uniforms.texture_size) / 2.0));
+                        vec3<i32>(floor((world_pos - world_origin()) * voxel_uniforms.voxels_per_meter + vec3(f32(voxel_uniforms.texture_size) / 2.0)));
 
                     if collision_effect.x != 0.0 {
                         let radius = collision_effect.y;
-                        let range = i32(ceil(radius * VOXELS_PER_METER));
+                        let range = i32(ceil(radius * voxel_uniforms.voxels_per_meter));
                         for (var x = -range; x <= range; x++) {
                             for (var y = -range; y <= range; y++) {
                                 for (var z = -range; z <= range; z++) {
                                     let offset = vec3(x, y, z);
                                     let texture_coords = texture_coords + offset;
-                                    if (length(vec3<f32>(offset) / VOXELS_PER_METER) >= radius) {
+                                    if (length(vec3<f32>(offset) / voxel_uniforms.voxels_per_meter) >= radius) {
                                         continue;
                                     }
 
-                                    let chunk_index = get_chunk_index(world_pos + vec3<f32>(offset) / VOXELS_PER_METER);
+                                    let chunk_index = get_chunk_index(vec3<f32>(texture_coords));
                                     if (chunk_index == -1) {
                                         continue; // Skip if outside active chunks
                                     }
+                                    let chunk_pos = chunk_position(texture_coords, voxel_uniforms.chunk_size);
 
                                     // Destroy
                                     if (collision_effect.x == 1.0) {
-                                    textureStore(voxel_worlds[chunk_index], texture_coords.zyx, vec4(0u));
+                                    store_voxel(chunk_index, chunk_pos, 0u);
                                     }
                                     // Place
                                     if (collision_effect.x == 2.0) {
-                                        let material = bitcast<u32>(collision_effect.z);
-                                        textureStore(voxel_worlds[chunk_index], texture_coords.zyx, vec4(material));
+                                        let voxel = decode_narrow_voxel(bitcast<u32>(collision_effect.z));
+                                        store_voxel(chunk_index, chunk_pos, voxel);
                                     }
                                     // Set Flags
                                     if (collision_effect.x == 3.0) {
                                         let flags = bitcast<u32>(collision_effect.z);
-                                        var voxel = textureLoad(voxel_worlds[chunk_index], texture_coords.zyx).r;
-                                        voxel |= flags << 8u;
-                                        textureStore(voxel_worlds[chunk_index], texture_coords.zyx, vec4(voxel));
+                                        var voxel = load_voxel(chunk_index, chunk_pos);
+                                        voxel = with_voxel_flags(voxel, voxel_flags(voxel) | flags);
+                                        store_voxel(chunk_index, chunk_pos, voxel);
                                     }
                                 }
                             }
@@ -143,7 +149,7 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                 // x face
                 for (var y = -size.y; y <= size.y; y++) {
                     for (var z = -size.z; z <= size.z; z++) {
-                        let offset = vec3(f32(size.x) * v_sign.x, f32(y), f32(z)) / (VOXELS_PER_METER * 1.0001);
+                        let offset = vec3(f32(size.x) * v_sign.x, f32(y), f32(z)) / (voxel_uniforms.voxels_per_meter * 1.0001);
                         let hit = shoot_ray(Ray((world_pos + offset), direction), distance, COLLISION_FLAG);
                         
                         let plane_normal = vec3(1.0, 0.0, 0.0);
@@ -157,7 +163,7 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                 // y face
                 for (var x = -size.x; x <= size.x; x++) {
                     for (var z = -size.z; z <= size.z; z++) {
-                        let offset = vec3(f32(x), f32(size.y) * v_sign.y, f32(z)) / (VOXELS_PER_METER * 1.001);
+                        let offset = vec3(f32(x), f32(size.y) * v_sign.y, f32(z)) / (voxel_uniforms.voxels_per_meter * 1.001);
                         let hit = shoot_ray(Ray((world_pos + offset), direction), distance, COLLISION_FLAG);
                         
                         let plane_normal = vec3(0.0, 1.0, 0.0);
@@ -171,7 +177,7 @@ fn physics(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
                 // z face
                 for (var x = -size.x; x <= size.x; x++) {
                     for (var y = -size.y; y <= size.y; y++) {
-                        let offset = vec3(f32(x), f32(y), f32(size.z) * v_sign.z) / (VOXELS_PER_METER * 1.0001);
+                        let offset = vec3(f32(x), f32(y), f32(size.z) * v_sign.z) / (voxel_uniforms.voxels_per_meter * 1.0001);
                         let hit = shoot_ray(Ray((world_pos + offset), direction), distance, COLLISION_FLAG);
                         
                         let plane_normal = vec3(0.0, 0.0, 1.0);
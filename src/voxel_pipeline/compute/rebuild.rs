@@ -1,7 +1,11 @@
+use super::bricks;
 use crate::{
     load::GridHierarchy,
-    voxel_pipeline::voxel_world::{VoxelData, VoxelUniforms},
-    RenderGraphSettings,
+    voxel_pipeline::{
+        edit::ChunkUploads,
+        voxel_world::{view_voxel_data, TargetVoxelWorld, VoxelData},
+    },
+    RenderGraphSettings, VoxelEngineSettings,
 };
 use bevy::{
     prelude::*,
@@ -21,6 +25,7 @@ pub struct Pipeline(CachedComputePipelineId);
 impl FromWorld for Pipeline {
     fn from_world(world: &mut World) -> Self {
         let voxel_bind_group_layout = world.resource::<VoxelData>().bind_group_layout.clone();
+        let shader_defs = world.resource::<VoxelEngineSettings>().shader_defs();
 
         let asset_server = world.resource_mut::<AssetServer>();
         let shader = asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/compute/rebuild.wgsl");
@@ -31,7 +36,7 @@ impl FromWorld for Pipeline {
             label: Some(Cow::from("rebuild pipeline")),
             layout: vec![voxel_bind_group_layout],
             shader,
-            shader_defs: vec![],
+            shader_defs,
             entry_point: Cow::from("rebuild_gh"),
             push_constant_ranges: vec![],
         });
@@ -43,18 +48,26 @@ impl FromWorld for Pipeline {
 impl render_graph::Node for RebuildNode {
     fn run(
         &self,
-        _graph: &mut RenderGraphContext,
+        graph: &mut RenderGraphContext,
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
-        let voxel_data = world.resource::<VoxelData>();
-        let voxel_uniforms = world.resource::<VoxelUniforms>();
+        // the world the view traces
+        let target = world.get::<TargetVoxelWorld>(graph.view_entity());
+        let Some(voxel_data) = view_voxel_data(world, target) else {
+            return Ok(());
+        };
+        let voxel_uniforms = voxel_data.uniform_buffer.get();
         let pipeline_cache = world.resource::<PipelineCache>();
         let render_queue = world.resource::<RenderQueue>();
-        let dispatch_size = voxel_uniforms.texture_size / 4;
+        let dispatch_size = voxel_uniforms.grid_dispatch_size();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
 
-        if !render_graph_settings.rebuild {
+        // without the rebuild every frame, the grid hierarchies of the main
+        // world are still rebuilt when chunks are streamed in or edited, so
+        // newly activated textures don't stay empty
+        let uploaded = target.is_none() && world.resource::<ChunkUploads>().any();
+        if !render_graph_settings.rebuild && !uploaded {
             return Ok(());
         }
 
@@ -62,7 +75,9 @@ impl render_graph::Node for RebuildNode {
         for i in 0..8 {
             levels[i] = voxel_uniforms.levels[i].x;
         }
-        let gh_size = GridHierarchy::get_buffer_size_from_levels(&levels);
+        // a grid hierarchy for every active chunk
+        let gh_size =
+            GridHierarchy::get_buffer_size_from_levels(&levels) * voxel_uniforms.chunks().len();
 
         let pipeline = match pipeline_cache.get_compute_pipeline(world.resource::<Pipeline>().0) {
             Some(pipeline) => pipeline,
@@ -83,7 +98,11 @@ impl render_graph::Node for RebuildNode {
         pass.set_bind_group(0, &voxel_data.bind_group, &[]);
 
         pass.set_pipeline(pipeline);
-        pass.dispatch_workgroups(dispatch_size, dispatch_size, dispatch_size);
+        pass.dispatch_workgroups(dispatch_size.x, dispatch_size.y, dispatch_size.z);
+
+        if let Some(brick_pipelines) = world.get_resource::<bricks::Pipelines>() {
+            bricks::release_bricks(&mut pass, voxel_data, brick_pipelines, pipeline_cache);
+        }
 
         Ok(())
     }
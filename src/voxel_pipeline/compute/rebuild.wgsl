@@ -1,29 +1,62 @@
 #import bevy_voxel_engine::common::{
     VoxelUniforms,
-    PORTAL_FLAG
+    PORTAL_FLAG,
+    voxel_material,
+    voxel_flags,
+    chunk_texture_index,
+    grid_texture_position,
+    pyramid_start,
+    atlas_position,
+    in_chunk,
+    brick_cell,
+    brick_position,
 }
 
+#ifdef VOXEL_WIDE
+alias VoxelTexture = texture_storage_3d<r32uint, read_write>;
+#else
+alias VoxelTexture = texture_storage_3d<r16uint, read_write>;
+#endif
+
 @group(0) @binding(0)
 var<uniform> voxel_uniforms: VoxelUniforms;
 @group(0) @binding(1)
-var voxel_worlds: binding_array<texture_storage_3d<r16uint, read_write>, 27>;
+#ifdef CHUNK_ATLAS
+var voxel_worlds: VoxelTexture;
+#else ifdef CHUNK_BRICKS
+var voxel_worlds: VoxelTexture;
+@group(0) @binding(4)
+var<storage, read_write> brick_index: array<u32>;
+#else
+var voxel_worlds: binding_array<VoxelTexture, #{ACTIVE_CHUNKS}>;
+#endif
 @group(0) @binding(2)
 var<storage, read_write> gh: array<atomic<u32>>;
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
-        }
+fn get_chunk_index(voxel_pos: vec3<f32>) -> i32 {
+    return chunk_texture_index(voxel_pos, voxel_uniforms.chunk_size, voxel_uniforms.origin_chunk, voxel_uniforms.chunk_grid);
+}
+fn load_voxel(chunk_index: i32, pos: vec3<i32>) -> u32 {
+#ifdef CHUNK_ATLAS
+    return textureLoad(voxel_worlds, atlas_position(chunk_index, pos, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid)).r;
+#else ifdef CHUNK_BRICKS
+    if (!in_chunk(pos, voxel_uniforms.chunk_size)) {
+        return 0u;
     }
-    return -1; // Out of bounds
+    let brick = brick_index[brick_cell(chunk_index, pos, voxel_uniforms.chunk_size)];
+    if (brick == 0u) {
+        return 0u;
+    }
+    return textureLoad(voxel_worlds, brick_position(brick - 1u, pos)).r;
+#else
+    return textureLoad(voxel_worlds[chunk_index], pos.zyx).r;
+#endif
 }
 fn get_texture_value(pos: vec3<i32>, chunk_index: i32) -> vec2<u32> {
     let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
-    let texture_value = textureLoad(voxel_worlds[chunk_index], chunk_pos.zyx).r;
+    let texture_value = load_voxel(chunk_index, chunk_pos);
     return vec2(
-        texture_value & 0xFFu,
-        texture_value >> 8u,
+        voxel_material(texture_value),
+        voxel_flags(texture_value),
     );
 }
 
@@ -33,18 +66,19 @@ fn set_value_index(index: u32) {
 
 @compute @workgroup_size(4, 4, 4)
 fn rebuild_gh(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let chunk_index = get_chunk_index(vec3<f32>(invocation_id));
+    let texture_pos = grid_texture_position(invocation_id, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid);
+    let chunk_index = get_chunk_index(vec3<f32>(texture_pos));
     if (chunk_index == -1) {
         return; // Skip if out of bounds
     }
 
-    let pos = vec3(i32(invocation_id.x), i32(invocation_id.y), i32(invocation_id.z)) % vec3(i32(voxel_uniforms.chunk_size));
+    let pos = vec3<i32>(invocation_id % voxel_uniforms.chunk_size);
     
     let material = get_texture_value(pos, chunk_index);
     if (material.x != 0u || (material.y & PORTAL_FLAG) > 0u) {
-        // set bits in grid hierarchy
-        let world_pos = vec3<u32>(invocation_id);
-        
+        // set bits in the grid hierarchy of the chunk
+        let start = pyramid_start(chunk_index, voxel_uniforms.offsets[7].x, voxel_uniforms.levels[7].x);
+
         let size0 = voxel_uniforms.levels[0].x;
         let size1 = voxel_uniforms.levels[1].x;
         let size2 = voxel_uniforms.levels[2].x;
@@ -63,14 +97,14 @@ fn rebuild_gh(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         let pos6 = (vec3<u32>(pos) * size6) / voxel_uniforms.texture_size;
         let pos7 = (vec3<u32>(pos) * size7) / voxel_uniforms.texture_size;
 
-        let index0 = voxel_uniforms.offsets[0].x + pos0.x * size0 * size0 + pos0.y * size0 + pos0.z;
-        let index1 = voxel_uniforms.offsets[1].x + pos1.x * size1 * size1 + pos1.y * size1 + pos1.z;
-        let index2 = voxel_uniforms.offsets[2].x + pos2.x * size2 * size2 + pos2.y * size2 + pos2.z;
-        let index3 = voxel_uniforms.offsets[3].x + pos3.x * size3 * size3 + pos3.y * size3 + pos3.z;
-        let index4 = voxel_uniforms.offsets[4].x + pos4.x * size4 * size4 + pos4.y * size4 + pos4.z;
-        let index5 = voxel_uniforms.offsets[5].x + pos5.x * size5 * size5 + pos5.y * size5 + pos5.z;
-        let index6 = voxel_uniforms.offsets[6].x + pos6.x * size6 * size6 + pos6.y * size6 + pos6.z;
-        let index7 = voxel_uniforms.offsets[7].x + pos7.x * size7 * size7 + pos7.y * size7 + pos7.z;
+        let index0 = start + voxel_uniforms.offsets[0].x + pos0.x * size0 * size0 + pos0.y * size0 + pos0.z;
+        let index1 = start + voxel_uniforms.offsets[1].x + pos1.x * size1 * size1 + pos1.y * size1 + pos1.z;
+        let index2 = start + voxel_uniforms.offsets[2].x + pos2.x * size2 * size2 + pos2.y * size2 + pos2.z;
+        let index3 = start + voxel_uniforms.offsets[3].x + pos3.x * size3 * size3 + pos3.y * size3 + pos3.z;
+        let index4 = start + voxel_uniforms.offsets[4].x + pos4.x * size4 * size4 + pos4.y * size4 + pos4.z;
+        let index5 = start + voxel_uniforms.offsets[5].x + pos5.x * size5 * size5 + pos5.y * size5 + pos5.z;
+        let index6 = start + voxel_uniforms.offsets[6].x + pos6.x * size6 * size6 + pos6.y * size6 + pos6.z;
+        let index7 = start + voxel_uniforms.offsets[7].x + pos7.x * size7 * size7 + pos7.y * size7 + pos7.z;
 
         if (size0 != 0u) {
             set_value_index(index0);
@@ -0,0 +1,700 @@
+use super::{
+    streaming::{persist_evicted_chunks, stream_chunks},
+    voxel_world::{
+        active_chunk_position, chunk_texture_index, load_voxel_world_prepare, VoxelData,
+        VoxelUniforms,
+    },
+};
+use crate::{
+    load::{GridHierarchy, PrefabStamp, StampMode, VoxelPrefab},
+    physics::world_to_voxel_scaled,
+};
+use bevy::{
+    asset::LoadState,
+    ecs::{system::SystemParam, world::Command},
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        renderer::RenderQueue,
+        Render, RenderApp, RenderSet,
+    },
+    utils::HashMap,
+};
+use std::sync::Arc;
+
+pub struct EditPlugin;
+
+impl Plugin for EditPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ChunkUploads::default())
+            .init_resource::<PendingStamps>()
+            .add_plugins(ExtractResourcePlugin::<ChunkUploads>::default())
+            .add_systems(PostUpdate, apply_prefab_stamps.before(queue_chunk_uploads))
+            .add_systems(PostUpdate, queue_chunk_uploads.after(stream_chunks));
+
+        app.sub_app_mut(RenderApp).add_systems(
+            Render,
+            upload_chunk_edits
+                .in_set(RenderSet::Prepare)
+                .after(load_voxel_world_prepare)
+                .after(persist_evicted_chunks),
+        );
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Voxel {
+    pub material: u8,
+    pub flags: u8,
+}
+
+impl Voxel {
+    pub const EMPTY: Voxel = Voxel {
+        material: 0,
+        flags: 0,
+    };
+
+    pub fn new(material: u8, flags: u8) -> Self {
+        Self { material, flags }
+    }
+}
+
+/// Cpu side view of the chunk textures. Only edits made through [`VoxelWorld`]
+/// and loaded or streamed chunks are mirrored, changes made by the gpu passes are not.
+/// That's why only the edited voxels are uploaded, the rest of the mirror may
+/// be out of date.
+///
+/// Chunks of the loaded world are read from the world itself and streamed
+/// chunks are kept as runs of equal voxels, a chunk only gets a full copy of
+/// its voxels once it is edited.
+#[derive(Resource)]
+pub struct VoxelMirror {
+    chunk_size: u32,
+    chunk_grid: UVec3,
+    chunks: Vec<MirroredChunk>,
+    dirty: Vec<Dirty>,
+}
+
+/// Where the voxels of an active chunk are read from.
+enum MirroredChunk {
+    Empty,
+    World(Arc<GridHierarchy>, IVec3),
+    Streamed(ChunkRuns),
+    Edited(Vec<u8>),
+}
+
+/// A chunk as runs of equal voxels, run i ends before voxel `ends[i]`.
+struct ChunkRuns {
+    ends: Vec<u32>,
+    voxels: Vec<[u8; 2]>,
+}
+
+impl ChunkRuns {
+    fn new(data: &[u8]) -> Self {
+        let mut runs = Self {
+            ends: Vec::new(),
+            voxels: Vec::new(),
+        };
+        for (i, voxel) in data.chunks_exact(2).enumerate() {
+            let voxel = [voxel[0], voxel[1]];
+            match runs.voxels.last() {
+                Some(last) if *last == voxel => *runs.ends.last_mut().unwrap() += 1,
+                _ => {
+                    runs.ends.push(i as u32 + 1);
+                    runs.voxels.push(voxel);
+                }
+            }
+        }
+        runs
+    }
+
+    fn get(&self, voxel: usize) -> [u8; 2] {
+        self.voxels[self.ends.partition_point(|&end| end as usize <= voxel)]
+    }
+
+    fn decode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(*self.ends.last().unwrap_or(&0) as usize * 2);
+        let mut start = 0;
+        for (&end, voxel) in self.ends.iter().zip(&self.voxels) {
+            for _ in start..end {
+                data.extend_from_slice(voxel);
+            }
+            start = end;
+        }
+        data
+    }
+}
+
+/// What of a chunk has to be uploaded.
+#[derive(Default)]
+enum Dirty {
+    #[default]
+    Clean,
+    /// One bit per voxel of the chunk, set for the edited ones.
+    Voxels(Vec<u64>),
+    /// A streamed chunk replaced the last one in the texture, so everything
+    /// is uploaded. Holds the data until then, none for an empty chunk.
+    Chunk(Option<Vec<u8>>),
+}
+
+impl VoxelMirror {
+    pub fn new(gh: &Arc<GridHierarchy>, chunk_grid: UVec3) -> Self {
+        let count = (chunk_grid.x * chunk_grid.y * chunk_grid.z) as usize;
+        let chunks = (0..count)
+            .map(|i| {
+                let position = active_chunk_position(i, IVec3::ZERO, chunk_grid);
+                match gh.chunks.contains_key(&position) {
+                    true => MirroredChunk::World(gh.clone(), position),
+                    false => MirroredChunk::Empty,
+                }
+            })
+            .collect();
+
+        Self {
+            chunk_size: gh.texture_size,
+            chunk_grid,
+            chunks,
+            dirty: (0..count).map(|_| Dirty::Clean).collect(),
+        }
+    }
+
+    pub fn reset(&mut self, gh: &Arc<GridHierarchy>) {
+        *self = Self::new(gh, self.chunk_grid);
+    }
+
+    /// Swaps in the data of a streamed chunk, none for an empty chunk. The
+    /// whole texture is uploaded, it still holds the chunk that left.
+    pub(crate) fn replace_chunk(&mut self, texture_index: usize, data: Option<Vec<u8>>) {
+        self.chunks[texture_index] = match &data {
+            Some(data) => MirroredChunk::Streamed(ChunkRuns::new(data)),
+            None => MirroredChunk::Empty,
+        };
+        self.dirty[texture_index] = Dirty::Chunk(data);
+    }
+
+    fn chunk_length(&self) -> usize {
+        self.chunk_size.pow(3) as usize * 2
+    }
+
+    fn index(&self, local: UVec3) -> usize {
+        let size = self.chunk_size as usize;
+        2 * (local.x as usize * size * size + local.y as usize * size + local.z as usize)
+    }
+
+    fn get(&self, texture_index: usize, local: UVec3) -> Voxel {
+        let index = self.index(local);
+        let [material, flags] = match &self.chunks[texture_index] {
+            MirroredChunk::Empty => [0, 0],
+            MirroredChunk::World(gh, position) => {
+                let data = &gh.chunks[position];
+                [data[index], data[index + 1]]
+            }
+            MirroredChunk::Streamed(runs) => runs.get(index / 2),
+            MirroredChunk::Edited(data) => [data[index], data[index + 1]],
+        };
+        Voxel::new(material, flags)
+    }
+
+    /// The voxels of a chunk, copied out of where they are kept.
+    fn chunk_data(&self, texture_index: usize) -> Vec<u8> {
+        match &self.chunks[texture_index] {
+            MirroredChunk::Empty => vec![0; self.chunk_length()],
+            MirroredChunk::World(gh, position) => gh.chunks[position].clone(),
+            MirroredChunk::Streamed(runs) => runs.decode(),
+            MirroredChunk::Edited(data) => data.clone(),
+        }
+    }
+
+    fn set(&mut self, texture_index: usize, local: UVec3, voxel: Voxel) {
+        if self.get(texture_index, local) == voxel {
+            return;
+        }
+
+        if !matches!(self.chunks[texture_index], MirroredChunk::Edited(_)) {
+            self.chunks[texture_index] = MirroredChunk::Edited(self.chunk_data(texture_index));
+        }
+        let index = self.index(local);
+        let MirroredChunk::Edited(data) = &mut self.chunks[texture_index] else {
+            unreachable!();
+        };
+        data[index] = voxel.material;
+        data[index + 1] = voxel.flags;
+
+        let words = (self.chunk_size.pow(3) as usize).div_ceil(64);
+        let dirty = &mut self.dirty[texture_index];
+        if let Dirty::Clean = dirty {
+            *dirty = Dirty::Voxels(vec![0; words]);
+        }
+        if let Dirty::Voxels(bits) = dirty {
+            let voxel = index / 2;
+            bits[voxel / 64] |= 1 << (voxel % 64);
+        }
+    }
+
+    /// Copies every box of edited voxels out of the mirror, or the whole
+    /// chunk if it was replaced, and marks them clean.
+    fn take_uploads(&mut self) -> Vec<ChunkUpload> {
+        let mut uploads = Vec::new();
+        for texture_index in 0..self.dirty.len() {
+            let boxes = match std::mem::take(&mut self.dirty[texture_index]) {
+                Dirty::Clean => continue,
+                Dirty::Chunk(data) => {
+                    // edits made since the chunk arrived are in the mirror
+                    let data = match (&self.chunks[texture_index], data) {
+                        (MirroredChunk::Edited(data), _) => data.clone(),
+                        (_, Some(data)) => data,
+                        (_, None) => vec![0; self.chunk_length()],
+                    };
+                    uploads.push(ChunkUpload {
+                        texture_index,
+                        min: UVec3::ZERO,
+                        size: UVec3::splat(self.chunk_size),
+                        data,
+                    });
+                    continue;
+                }
+                Dirty::Voxels(bits) => edited_boxes(&bits, self.chunk_size),
+            };
+
+            let MirroredChunk::Edited(chunk) = &self.chunks[texture_index] else {
+                unreachable!("edited chunks own their voxels");
+            };
+            for (min, size) in boxes {
+                let mut data = Vec::with_capacity((size.x * size.y * size.z * 2) as usize);
+                for x in min.x..min.x + size.x {
+                    for y in min.y..min.y + size.y {
+                        let start = self.index(UVec3::new(x, y, min.z));
+                        let end = start + size.z as usize * 2;
+                        data.extend_from_slice(&chunk[start..end]);
+                    }
+                }
+
+                uploads.push(ChunkUpload {
+                    texture_index,
+                    min,
+                    size,
+                    data,
+                });
+            }
+        }
+        uploads
+    }
+}
+
+/// Splits the edited voxels of a chunk into boxes that cover nothing else.
+/// Runs along z are joined into rectangles along y, and those into boxes along
+/// x, so a filled box is uploaded as one.
+fn edited_boxes(bits: &[u64], chunk_size: u32) -> Vec<(UVec3, UVec3)> {
+    let size = chunk_size as usize;
+    let edited = |x: usize, y: usize, z: usize| {
+        let voxel = (x * size + y) * size + z;
+        bits[voxel / 64] & (1 << (voxel % 64)) != 0
+    };
+
+    let mut boxes: Vec<(UVec3, UVec3)> = Vec::new();
+    // boxes that reached the last slice along x, by their extent along y and z
+    let mut open_boxes: HashMap<(u32, u32, u32, u32), usize> = HashMap::new();
+    for x in 0..size {
+        let row_start = x * size * size;
+        if bits[row_start / 64..(row_start + size * size).div_ceil(64)]
+            .iter()
+            .all(|&word| word == 0)
+        {
+            open_boxes.clear();
+            continue;
+        }
+
+        // rectangles of this slice, by the extent of their runs along z
+        let mut rectangles: Vec<(u32, u32, u32, u32)> = Vec::new();
+        let mut open_rectangles: HashMap<(u32, u32), usize> = HashMap::new();
+        for y in 0..size {
+            let mut extended = Vec::new();
+            let mut z = 0;
+            while z < size {
+                if !edited(x, y, z) {
+                    z += 1;
+                    continue;
+                }
+                let start = z;
+                while z < size && edited(x, y, z) {
+                    z += 1;
+                }
+
+                let run = (start as u32, z as u32);
+                match open_rectangles.get(&run) {
+                    Some(&i) => rectangles[i].1 += 1,
+                    None => {
+                        rectangles.push((y as u32, 1, run.0, run.1));
+                        open_rectangles.insert(run, rectangles.len() - 1);
+                    }
+                }
+                extended.push(run);
+            }
+            open_rectangles.retain(|run, _| extended.contains(run));
+        }
+
+        let mut extended = Vec::new();
+        for (y, height, z_start, z_end) in rectangles {
+            let key = (y, height, z_start, z_end);
+            match open_boxes.get(&key) {
+                Some(&i) => boxes[i].1.x += 1,
+                None => {
+                    boxes.push((
+                        UVec3::new(x as u32, y, z_start),
+                        UVec3::new(1, height, z_end - z_start),
+                    ));
+                    open_boxes.insert(key, boxes.len() - 1);
+                }
+            }
+            extended.push(key);
+        }
+        open_boxes.retain(|key, _| extended.contains(key));
+    }
+    boxes
+}
+
+/// Edit voxels from the cpu. Positions are in voxel space, the same space
+/// [`VoxelWorld::world_to_voxel`] maps into. Changes are uploaded to the gpu
+/// at the end of the frame.
+///
+/// Voxels are read from the cpu mirror, which only knows the loaded chunks and
+/// the edits made here. What the automata, animation and voxelization passes
+/// write on the gpu isn't seen by [`VoxelWorld::get_voxel`] or
+/// [`VoxelWorld::replace`], and is overwritten where the mirror is uploaded.
+#[derive(SystemParam)]
+pub struct VoxelWorld<'w> {
+    mirror: ResMut<'w, VoxelMirror>,
+    voxel_uniforms: Res<'w, VoxelUniforms>,
+}
+
+impl<'w> VoxelWorld<'w> {
+    pub fn world_to_voxel(&self, world_pos: Vec3) -> IVec3 {
+        world_to_voxel_scaled(
+            world_pos,
+            self.voxel_uniforms.texture_size,
+            self.voxel_uniforms.voxels_per_meter,
+        )
+    }
+
+    /// Finds the texture and the position inside of it for a voxel, none if
+    /// the voxel is outside of the active chunks.
+    fn locate(&self, pos: IVec3) -> Option<(usize, UVec3)> {
+        let size = IVec3::splat(self.mirror.chunk_size as i32);
+        let chunk_position = pos.div_euclid(size);
+        let texture_index = chunk_texture_index(chunk_position, self.voxel_uniforms.chunk_grid);
+        if self.voxel_uniforms.active_chunks[texture_index].position != chunk_position {
+            return None;
+        }
+        Some((texture_index, pos.rem_euclid(size).as_uvec3()))
+    }
+
+    pub fn get_voxel(&self, pos: IVec3) -> Option<Voxel> {
+        let (texture_index, local) = self.locate(pos)?;
+        Some(self.mirror.get(texture_index, local))
+    }
+
+    /// Returns false if the position is outside of the active chunks.
+    pub fn set_voxel(&mut self, pos: IVec3, voxel: Voxel) -> bool {
+        match self.locate(pos) {
+            Some((texture_index, local)) => {
+                self.mirror.set(texture_index, local, voxel);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fills every voxel between min and max inclusive.
+    pub fn fill_box(&mut self, min: IVec3, max: IVec3, voxel: Voxel) {
+        self.for_each_in_box(min, max, |_, _| Some(voxel));
+    }
+
+    /// Radius is in voxels.
+    pub fn fill_sphere(&mut self, center: IVec3, radius: f32, voxel: Voxel) {
+        let range = IVec3::splat(radius.ceil() as i32);
+        self.for_each_in_box(center - range, center + range, |pos, _| {
+            if (pos - center).as_vec3().length() < radius {
+                Some(voxel)
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Replaces every voxel of the material `from` between min and max
+    /// inclusive, returns the number of voxels replaced.
+    pub fn replace(&mut self, min: IVec3, max: IVec3, from: u8, to: Voxel) -> u32 {
+        let mut count = 0;
+        self.for_each_in_box(min, max, |_, voxel| {
+            if voxel.material == from {
+                count += 1;
+                Some(to)
+            } else {
+                None
+            }
+        });
+        count
+    }
+
+    /// Writes a prefab into the world with the smallest corner of the stamped
+    /// box at min. Voxels outside of the active chunks are left out.
+    pub fn stamp(&mut self, prefab: &VoxelPrefab, min: IVec3, stamp: &PrefabStamp) {
+        for (pos, voxel) in prefab.stamped_voxels(stamp) {
+            if stamp.mode == StampMode::Merge && voxel.material == 0 {
+                continue;
+            }
+            self.set_voxel(min + pos.as_ivec3(), voxel);
+        }
+    }
+
+    fn for_each_in_box<F>(&mut self, min: IVec3, max: IVec3, mut function: F)
+    where
+        F: FnMut(IVec3, Voxel) -> Option<Voxel>,
+    {
+        let (min, max) = active_box(
+            min,
+            max,
+            self.voxel_uniforms.origin_chunk,
+            self.voxel_uniforms.chunk_grid,
+            self.mirror.chunk_size,
+        );
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let pos = IVec3::new(x, y, z);
+                    let Some((texture_index, local)) = self.locate(pos) else {
+                        continue;
+                    };
+                    let voxel = self.mirror.get(texture_index, local);
+                    if let Some(new_voxel) = function(pos, voxel) {
+                        self.mirror.set(texture_index, local, new_voxel);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Stamps a prefab into the world once it is loaded, see [`VoxelWorld::stamp`].
+/// Queue it with `commands.add`.
+pub struct StampPrefab {
+    pub prefab: Handle<VoxelPrefab>,
+    pub min: IVec3,
+    pub stamp: PrefabStamp,
+}
+
+impl Command for StampPrefab {
+    fn apply(self, world: &mut World) {
+        world.resource_mut::<PendingStamps>().0.push(self);
+    }
+}
+
+#[derive(Resource, Default)]
+struct PendingStamps(Vec<StampPrefab>);
+
+/// The part of an inclusive box inside the active chunks, empty when min ends
+/// up above max.
+fn active_box(
+    min: IVec3,
+    max: IVec3,
+    origin_chunk: IVec3,
+    chunk_grid: UVec3,
+    chunk_size: u32,
+) -> (IVec3, IVec3) {
+    let size = chunk_size as i32;
+    let grid = chunk_grid.as_ivec3();
+    let first_chunk = origin_chunk - grid / 2;
+    let (grid_min, grid_max) = (first_chunk * size, (first_chunk + grid) * size - 1);
+    (min.min(max).max(grid_min), min.max(max).min(grid_max))
+}
+
+/// Stamps are applied in the order they were queued, ones waiting for their
+/// prefab hold back the ones after them.
+fn apply_prefab_stamps(
+    mut pending: ResMut<PendingStamps>,
+    prefabs: Res<Assets<VoxelPrefab>>,
+    asset_server: Res<AssetServer>,
+    mut voxel_world: VoxelWorld,
+) {
+    while let Some(stamp) = pending.0.first() {
+        if let Some(prefab) = prefabs.get(&stamp.prefab) {
+            voxel_world.stamp(prefab, stamp.min, &stamp.stamp);
+        } else if let Some(LoadState::Failed(e)) = asset_server.get_load_state(&stamp.prefab) {
+            error!("Failed to stamp prefab: {}", e);
+        } else {
+            // still loading
+            return;
+        }
+        pending.0.remove(0);
+    }
+}
+
+struct ChunkUpload {
+    texture_index: usize,
+    min: UVec3,
+    size: UVec3,
+    data: Vec<u8>,
+}
+
+#[derive(Resource, ExtractResource, Clone, Default)]
+pub(super) struct ChunkUploads(Arc<Vec<ChunkUpload>>);
+
+impl ChunkUploads {
+    /// Whether the chunks change this frame, whole chunks are uploaded when
+    /// they become active.
+    pub(super) fn any(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+fn queue_chunk_uploads(mut voxel_mirror: ResMut<VoxelMirror>, mut uploads: ResMut<ChunkUploads>) {
+    uploads.0 = Arc::new(voxel_mirror.take_uploads());
+}
+
+pub(super) fn upload_chunk_edits(
+    mut voxel_data: ResMut<VoxelData>,
+    render_queue: Res<RenderQueue>,
+    uploads: Res<ChunkUploads>,
+) {
+    for upload in uploads.0.iter() {
+        voxel_data.write_chunk(
+            &render_queue,
+            upload.texture_index,
+            upload.min,
+            upload.size,
+            &upload.data,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(size: u32) -> VoxelMirror {
+        VoxelMirror::new(&Arc::new(GridHierarchy::empty(size)), UVec3::ONE)
+    }
+
+    #[test]
+    fn boxes_are_clamped_to_the_active_chunks() {
+        let huge = IVec3::splat(i32::MAX / 2);
+        let (min, max) = active_box(huge, -huge, IVec3::new(1, 0, -1), UVec3::new(3, 1, 3), 8);
+        assert_eq!((min, max), (IVec3::new(0, 0, -16), IVec3::new(23, 7, 7)));
+
+        // boxes outside of the grid visit nothing
+        let (min, max) = active_box(
+            IVec3::splat(100),
+            IVec3::splat(200),
+            IVec3::ZERO,
+            UVec3::ONE,
+            8,
+        );
+        assert!(min.cmpgt(max).any());
+    }
+
+    #[test]
+    fn uploads_only_edited_runs() {
+        let mut mirror = mirror(8);
+        for z in 2..5 {
+            mirror.set(0, UVec3::new(1, 1, z), Voxel::new(3, 0));
+        }
+        // far apart edits don't upload what is between them
+        mirror.set(0, UVec3::new(7, 7, 7), Voxel::new(4, 0));
+        // setting a voxel to what it is isn't an edit
+        mirror.set(0, UVec3::new(0, 0, 0), Voxel::EMPTY);
+
+        let uploads = mirror.take_uploads();
+        let runs: Vec<(UVec3, UVec3)> = uploads.iter().map(|u| (u.min, u.size)).collect();
+        assert_eq!(
+            runs,
+            vec![
+                (UVec3::new(1, 1, 2), UVec3::new(1, 1, 3)),
+                (UVec3::new(7, 7, 7), UVec3::ONE),
+            ]
+        );
+        assert_eq!(uploads[0].data, vec![3, 0, 3, 0, 3, 0]);
+        assert!(mirror.take_uploads().is_empty());
+
+        mirror.replace_chunk(0, None);
+        let uploads = mirror.take_uploads();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(uploads[0].size, UVec3::splat(8));
+    }
+
+    #[test]
+    fn large_edits_upload_only_the_edited_box() {
+        let mut mirror = mirror(16);
+        for x in 2..6 {
+            for y in 0..16 {
+                for z in 3..9 {
+                    mirror.set(0, UVec3::new(x, y, z), Voxel::new(3, 0));
+                }
+            }
+        }
+
+        let uploads = mirror.take_uploads();
+        assert_eq!(uploads.len(), 1);
+        assert_eq!(
+            (uploads[0].min, uploads[0].size),
+            (UVec3::new(2, 0, 3), UVec3::new(4, 16, 6))
+        );
+        assert_eq!(&uploads[0].data[..2], &[3, 0]);
+    }
+
+    #[test]
+    fn edited_boxes_cover_only_edited_voxels() {
+        let size = 8;
+        let mut bits = vec![0u64; 8];
+        let mut set = |pos: UVec3| {
+            let voxel = ((pos.x * size + pos.y) * size + pos.z) as usize;
+            bits[voxel / 64] |= 1 << (voxel % 64);
+        };
+        // an L shape in two slices and a voxel on its own
+        for x in 1..3 {
+            for z in 0..4 {
+                set(UVec3::new(x, 5, z));
+            }
+            set(UVec3::new(x, 6, 0));
+        }
+        set(UVec3::new(7, 0, 7));
+
+        let boxes = edited_boxes(&bits, size);
+        assert_eq!(
+            boxes,
+            vec![
+                (UVec3::new(1, 5, 0), UVec3::new(2, 1, 4)),
+                (UVec3::new(1, 6, 0), UVec3::new(2, 1, 1)),
+                (UVec3::new(7, 0, 7), UVec3::ONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_edited_chunks_are_copied() {
+        let mut gh = GridHierarchy::empty(8);
+        let mut data = vec![0; gh.chunk_length()];
+        data[2 * 9] = 5;
+        gh.chunks.insert(IVec3::ZERO, data.clone());
+        let mut mirror = VoxelMirror::new(&Arc::new(gh), UVec3::new(2, 1, 1));
+        assert!(matches!(mirror.chunks[0], MirroredChunk::World(..)));
+        assert!(matches!(mirror.chunks[1], MirroredChunk::Empty));
+        assert_eq!(mirror.get(0, UVec3::new(0, 1, 1)), Voxel::new(5, 0));
+
+        // streamed chunks are kept as runs
+        data[2 * 20 + 1] = 7;
+        mirror.replace_chunk(1, Some(data.clone()));
+        assert!(matches!(&mirror.chunks[1], MirroredChunk::Streamed(runs) if runs.ends.len() == 5));
+        assert_eq!(mirror.get(1, UVec3::new(0, 1, 1)), Voxel::new(5, 0));
+        assert_eq!(mirror.get(1, UVec3::new(0, 2, 4)), Voxel::new(0, 7));
+        assert_eq!(mirror.get(1, UVec3::new(0, 2, 5)), Voxel::EMPTY);
+        let uploads = mirror.take_uploads();
+        assert_eq!(uploads[0].data, data);
+
+        mirror.set(1, UVec3::new(7, 7, 7), Voxel::new(1, 0));
+        assert!(matches!(mirror.chunks[0], MirroredChunk::World(..)));
+        let MirroredChunk::Edited(edited) = &mirror.chunks[1] else {
+            panic!("the chunk wasn't copied");
+        };
+        assert_eq!(edited[..data.len() - 2], data[..data.len() - 2]);
+    }
+}
@@ -4,6 +4,10 @@ use self::{
         animation::AnimationNode, automata::AutomataNode, clear::ClearNode, physics::PhysicsNode,
         rebuild::RebuildNode, ComputeResourcesPlugin,
     },
+    edit::EditPlugin,
+    objects::VoxelObjectPlugin,
+    save::SavePlugin,
+    streaming::StreamingPlugin,
     trace::{TraceNode, TracePlugin},
     voxel_world::VoxelWorldPlugin,
     voxelization::VoxelizationPlugin,
@@ -22,6 +26,10 @@ use bevy::{
 
 pub mod attachments;
 pub mod compute;
+pub mod edit;
+pub mod objects;
+pub mod save;
+pub mod streaming;
 pub mod trace;
 pub mod voxel_world;
 pub mod voxelization;
@@ -56,6 +64,10 @@ impl Plugin for RenderPlugin {
             .add_plugins(ExtractResourcePlugin::<RenderGraphSettings>::default())
             .add_plugins(AttachmentsPlugin)
             .add_plugins(VoxelWorldPlugin)
+            .add_plugins(EditPlugin)
+            .add_plugins(VoxelObjectPlugin)
+            .add_plugins(SavePlugin)
+            .add_plugins(StreamingPlugin)
             .add_plugins(TracePlugin)
             .add_plugins(VoxelizationPlugin)
             .add_plugins(ComputeResourcesPlugin);
@@ -130,6 +142,9 @@ pub struct RenderGraphSettings {
     pub automata: bool,
     pub animation: bool,
     pub voxelization: bool,
+    /// Rebuilds the grid hierarchies every frame, so rays see what the gpu
+    /// passes changed. When off they are only rebuilt on frames the chunks of
+    /// the main world are uploaded, like when new chunks stream in.
     pub rebuild: bool,
     pub physics: bool,
     pub trace: bool,
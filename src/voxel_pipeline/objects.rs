@@ -0,0 +1,363 @@
+use super::{
+    edit::Voxel,
+    voxel_world::{queue_bind_group, VoxelData, VoxelUniforms},
+};
+use crate::VoxelFormat;
+use bevy::{
+    ecs::query::QueryItem,
+    prelude::*,
+    render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+};
+use std::sync::Arc;
+
+pub struct VoxelObjectPlugin;
+
+impl Plugin for VoxelObjectPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractComponentPlugin::<VoxelObject>::default());
+    }
+
+    fn finish(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp).add_systems(
+            Render,
+            prepare_voxel_objects
+                .in_set(RenderSet::Prepare)
+                .before(queue_bind_group),
+        );
+    }
+}
+
+/// A small voxel volume that moves with its [`GlobalTransform`]. Objects are
+/// traced in their own space instead of being written into the chunks, so
+/// they can be rotated and scaled, and the physics rays hit them too.
+///
+/// The volume is centred on the transform, a voxel is a voxel of the world
+/// across. Clones share their voxels until one of them is edited. Objects are
+/// only traced in the main world and can't be seen through portals.
+#[derive(Component, Clone)]
+pub struct VoxelObject {
+    size: UVec3,
+    voxels: Arc<Vec<Voxel>>,
+}
+
+impl VoxelObject {
+    /// An empty object.
+    pub fn new(size: UVec3) -> Self {
+        Self {
+            size,
+            voxels: Arc::new(vec![Voxel::EMPTY; size.element_product() as usize]),
+        }
+    }
+
+    /// Voxels ordered like the chunks, z is the innermost axis.
+    pub fn from_voxels(size: UVec3, voxels: Vec<Voxel>) -> Result<Self, String> {
+        if voxels.len() != size.element_product() as usize {
+            return Err(format!(
+                "{} voxels don't fill an object of size {}",
+                voxels.len(),
+                size
+            ));
+        }
+        Ok(Self {
+            size,
+            voxels: Arc::new(voxels),
+        })
+    }
+
+    pub fn size(&self) -> UVec3 {
+        self.size
+    }
+
+    fn index(&self, pos: UVec3) -> Option<usize> {
+        pos.cmplt(self.size)
+            .all()
+            .then(|| ((pos.x * self.size.y + pos.y) * self.size.z + pos.z) as usize)
+    }
+
+    pub fn get_voxel(&self, pos: UVec3) -> Option<Voxel> {
+        self.index(pos).map(|index| self.voxels[index])
+    }
+
+    /// Returns false if the position is outside of the object.
+    pub fn set_voxel(&mut self, pos: UVec3, voxel: Voxel) -> bool {
+        match self.index(pos) {
+            Some(index) => {
+                Arc::make_mut(&mut self.voxels)[index] = voxel;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct ExtractedVoxelObject {
+    entity: Entity,
+    world_from_object: Mat4,
+    size: UVec3,
+    voxels: Arc<Vec<Voxel>>,
+}
+
+impl ExtractComponent for VoxelObject {
+    type QueryData = (
+        Entity,
+        &'static VoxelObject,
+        &'static GlobalTransform,
+        Option<&'static InheritedVisibility>,
+    );
+    type QueryFilter = ();
+    type Out = ExtractedVoxelObject;
+
+    fn extract_component(
+        (entity, object, transform, visibility): QueryItem<'_, Self::QueryData>,
+    ) -> Option<Self::Out> {
+        if visibility.is_some_and(|visibility| !visibility.get()) {
+            return None;
+        }
+
+        Some(ExtractedVoxelObject {
+            entity,
+            world_from_object: transform.compute_matrix(),
+            size: object.size,
+            voxels: object.voxels.clone(),
+        })
+    }
+}
+
+/// Maps meters around the transform to voxels from the corner of the volume.
+fn object_from_world(size: UVec3, world_from_object: Mat4, voxels_per_meter: f32) -> Mat4 {
+    Mat4::from_translation(size.as_vec3() / 2.0)
+        * Mat4::from_scale(Vec3::splat(voxels_per_meter))
+        * world_from_object.inverse()
+}
+
+#[derive(Default, Clone, Copy, ShaderType)]
+struct GpuVoxelObject {
+    object_from_world: Mat4,
+    size: UVec3,
+    offset: u32,
+}
+
+#[derive(ShaderType)]
+struct GpuVoxelObjects {
+    count: u32,
+    #[size(runtime)]
+    objects: Vec<GpuVoxelObject>,
+}
+
+/// The objects of a world and their voxels, encoded like the chunks. Objects
+/// sharing their voxels share them here too.
+pub struct VoxelObjectBuffers {
+    objects: Buffer,
+    voxels: Buffer,
+    /// The voxels in the buffer and where they start.
+    packed: Vec<(Arc<Vec<Voxel>>, u32)>,
+}
+
+impl VoxelObjectBuffers {
+    pub(crate) fn new(render_device: &RenderDevice) -> Self {
+        let objects = GpuVoxelObjects {
+            count: 0,
+            objects: vec![GpuVoxelObject::default()],
+        };
+        Self {
+            objects: create_buffer(render_device, "voxel objects", &encode_objects(&objects)),
+            voxels: create_buffer(render_device, "voxel object voxels", &[0; 4]),
+            packed: Vec::new(),
+        }
+    }
+
+    pub(crate) fn layout_entries() -> [BindGroupLayoutEntry; 2] {
+        [
+            BindGroupLayoutEntry {
+                binding: 8,
+                visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: Some(GpuVoxelObjects::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 9,
+                visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: BufferSize::new(4),
+                },
+                count: None,
+            },
+        ]
+    }
+
+    pub(crate) fn bind_group_entries(&self) -> [BindGroupEntry<'_>; 2] {
+        [
+            BindGroupEntry {
+                binding: 8,
+                resource: self.objects.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 9,
+                resource: self.voxels.as_entire_binding(),
+            },
+        ]
+    }
+
+    /// Packs the voxels again when any object got new ones, the objects are
+    /// written every frame.
+    fn write(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        voxel_format: VoxelFormat,
+        voxels_per_meter: f32,
+        objects: &[&ExtractedVoxelObject],
+    ) {
+        let mut unique: Vec<&Arc<Vec<Voxel>>> = Vec::new();
+        for object in objects {
+            if !unique.iter().any(|voxels| Arc::ptr_eq(voxels, &object.voxels)) {
+                unique.push(&object.voxels);
+            }
+        }
+
+        let unchanged = unique.len() == self.packed.len()
+            && unique
+                .iter()
+                .zip(&self.packed)
+                .all(|(voxels, (packed, _))| Arc::ptr_eq(voxels, packed));
+        if !unchanged {
+            // the first voxel keeps the buffer from being empty
+            let mut data = vec![0u32];
+            self.packed.clear();
+            for voxels in unique {
+                self.packed.push((voxels.clone(), data.len() as u32));
+                data.extend(voxels.iter().map(|voxel| {
+                    voxel_format.encode(voxel.material as u16, voxel.flags, 0)
+                }));
+            }
+            write_buffer(
+                render_device,
+                render_queue,
+                &mut self.voxels,
+                "voxel object voxels",
+                bytemuck::cast_slice(&data),
+            );
+        }
+
+        let mut gpu_objects: Vec<GpuVoxelObject> = objects
+            .iter()
+            .map(|object| GpuVoxelObject {
+                object_from_world: object_from_world(
+                    object.size,
+                    object.world_from_object,
+                    voxels_per_meter,
+                ),
+                size: object.size,
+                offset: self
+                    .packed
+                    .iter()
+                    .find(|(voxels, _)| Arc::ptr_eq(voxels, &object.voxels))
+                    .unwrap()
+                    .1,
+            })
+            .collect();
+        let count = gpu_objects.len() as u32;
+        if gpu_objects.is_empty() {
+            gpu_objects.push(GpuVoxelObject::default());
+        }
+        write_buffer(
+            render_device,
+            render_queue,
+            &mut self.objects,
+            "voxel objects",
+            &encode_objects(&GpuVoxelObjects {
+                count,
+                objects: gpu_objects,
+            }),
+        );
+    }
+}
+
+fn encode_objects(objects: &GpuVoxelObjects) -> Vec<u8> {
+    let mut bytes = encase::StorageBuffer::new(Vec::new());
+    bytes.write(objects).unwrap();
+    bytes.into_inner()
+}
+
+fn create_buffer(render_device: &RenderDevice, label: &str, contents: &[u8]) -> Buffer {
+    render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label: Some(label),
+        contents,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+    })
+}
+
+/// Replaces the buffer when the data doesn't fit, the bind group is rebuilt
+/// every frame anyway.
+fn write_buffer(
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+    buffer: &mut Buffer,
+    label: &str,
+    contents: &[u8],
+) {
+    if contents.len() as u64 > buffer.size() {
+        *buffer = create_buffer(render_device, label, contents);
+    } else {
+        render_queue.write_buffer(buffer, 0, contents);
+    }
+}
+
+fn prepare_voxel_objects(
+    objects: Query<&ExtractedVoxelObject>,
+    mut voxel_data: ResMut<VoxelData>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    // the same order every frame, so the packed voxels are reused
+    let mut objects: Vec<&ExtractedVoxelObject> = objects.iter().collect();
+    objects.sort_by_key(|object| object.entity);
+
+    let voxel_format = voxel_data.voxel_format;
+    voxel_data.objects.write(
+        &render_device,
+        &render_queue,
+        voxel_format,
+        voxel_uniforms.voxels_per_meter,
+        &objects,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_voxels_are_voxels_of_the_world() {
+        let size = UVec3::new(4, 8, 2);
+        let transform = Transform::from_xyz(10.0, -2.0, 3.0)
+            .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_2));
+        let object_from_world = object_from_world(size, transform.compute_matrix(), 4.0);
+
+        // the transform is at the centre of the volume
+        let centre = object_from_world.transform_point3(transform.translation);
+        assert!(centre.abs_diff_eq(size.as_vec3() / 2.0, 1e-5));
+
+        // a meter along the local x axis is 4 voxels
+        let meter = transform.translation + transform.rotation * Vec3::X;
+        let pos = object_from_world.transform_point3(meter);
+        assert!(pos.abs_diff_eq(size.as_vec3() / 2.0 + Vec3::X * 4.0, 1e-5));
+
+        // and a voxel along the world's x axis is one along the object's z
+        let pos = object_from_world.transform_point3(transform.translation + Vec3::X * 0.25);
+        assert!(pos.abs_diff_eq(size.as_vec3() / 2.0 + Vec3::Z, 1e-5));
+    }
+}
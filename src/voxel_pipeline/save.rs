@@ -0,0 +1,440 @@
+use super::{
+    compute::bricks::{self, unpack_chunk},
+    voxel_world::{VoxelData, VoxelUniforms, WorldPallete},
+};
+use crate::{load::GridHierarchy, Flags, RegionCache, SaveVoxelWorld, VoxelFormat};
+use bevy::{
+    ecs::world::Command,
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    tasks::IoTaskPool,
+    utils::HashSet,
+};
+use std::sync::{Arc, OnceLock};
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SaveVoxelWorld::None)
+            .insert_resource(WorldSave::None)
+            .add_plugins(ExtractResourcePlugin::<WorldSave>::default())
+            .add_systems(Last, queue_world_save);
+
+        app.sub_app_mut(RenderApp)
+            .init_resource::<SaveInProgress>()
+            .add_systems(Render, save_voxel_world.in_set(RenderSet::Cleanup));
+    }
+}
+
+/// Reads the world back from the gpu over the next frames, a chunk per frame,
+/// and writes it to a .vox file. Animated voxels are left out. Chunks that
+/// were streamed out into the [`RegionCache`] are saved too.
+pub fn save_world_vox(path: impl Into<String>) -> impl Command {
+    let path = path.into();
+    move |world: &mut World| {
+        *world.resource_mut::<SaveVoxelWorld>() = SaveVoxelWorld::Vox(path);
+    }
+}
+
+/// Like [`save_world_vox`] but writes the engine's own format, which keeps
+/// the flags of every voxel.
+pub fn save_world_native(path: impl Into<String>) -> impl Command {
+    let path = path.into();
+    move |world: &mut World| {
+        *world.resource_mut::<SaveVoxelWorld>() = SaveVoxelWorld::Native(path);
+    }
+}
+
+#[derive(Resource, ExtractResource, Clone)]
+enum WorldSave {
+    Vox(String),
+    Native(String),
+    None,
+}
+
+fn queue_world_save(
+    mut save_voxel_world: ResMut<SaveVoxelWorld>,
+    mut world_save: ResMut<WorldSave>,
+) {
+    *world_save = match std::mem::replace(save_voxel_world.as_mut(), SaveVoxelWorld::None) {
+        SaveVoxelWorld::Vox(path) => WorldSave::Vox(path),
+        SaveVoxelWorld::Native(path) => WorldSave::Native(path),
+        SaveVoxelWorld::None => WorldSave::None,
+    };
+}
+
+/// A save in progress. Chunks are read back one at a time over the following
+/// frames, so the render thread never waits for the gpu.
+#[derive(Resource, Default)]
+struct SaveInProgress(Option<WorldReadBack>);
+
+struct WorldReadBack {
+    path: String,
+    native: bool,
+    gh: GridHierarchy,
+    /// Chunks still to be read back and the texture they are in.
+    remaining: Vec<(IVec3, usize)>,
+    /// Chunks that were read back, their copies in the cache are older.
+    read_back: HashSet<IVec3>,
+    region_cache: Option<RegionCache>,
+    buffer: Buffer,
+    /// The chunk being read back, and how mapping the buffer went once it is
+    /// done.
+    in_flight: Option<(IVec3, MapResult)>,
+}
+
+pub(super) type MapResult = Arc<OnceLock<Result<(), BufferAsyncError>>>;
+
+impl WorldReadBack {
+    fn new(
+        path: String,
+        native: bool,
+        voxel_data: &VoxelData,
+        (voxel_uniforms, world_pallete): (&VoxelUniforms, &WorldPallete),
+        region_cache: Option<RegionCache>,
+        render_device: &RenderDevice,
+    ) -> Self {
+        let size = voxel_uniforms.texture_size;
+        let mut gh = GridHierarchy::empty(size);
+        gh.pallete = world_pallete.0.clone();
+        for i in 0..256 {
+            gh.materials[i] = voxel_uniforms.materials[i].into();
+        }
+
+        Self {
+            path,
+            native,
+            gh,
+            remaining: voxel_uniforms
+                .chunks()
+                .iter()
+                .map(|chunk| (chunk.position, chunk.texture_index as usize))
+                .collect(),
+            read_back: default(),
+            region_cache,
+            buffer: create_read_back_buffer(render_device, size, voxel_data.voxel_format),
+            in_flight: None,
+        }
+    }
+
+    /// Collects the chunk in flight once it is mapped and starts copying the
+    /// next one. Returns true once every chunk has been read back.
+    fn step(
+        &mut self,
+        voxel_data: &VoxelData,
+        voxel_uniforms: &VoxelUniforms,
+        unpack: Option<&ComputePipeline>,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) -> Result<bool, String> {
+        if let Some((chunk, mapped)) = &self.in_flight {
+            render_device.poll(wgpu::Maintain::Poll);
+            let Some(result) = mapped.get() else {
+                return Ok(false);
+            };
+            result.clone().map_err(|e| e.to_string())?;
+
+            let format = voxel_data.voxel_format;
+            let data = format.narrow(read_mapped_texture(
+                &self.buffer,
+                voxel_data.chunk_size,
+                format,
+            ))?;
+            if data.chunks_exact(2).any(|voxel| voxel[0] != 0) {
+                self.gh.chunks.insert(*chunk, data);
+            }
+            self.read_back.insert(*chunk);
+            self.in_flight = None;
+        }
+
+        while let Some((chunk, texture_index)) = self.remaining.pop() {
+            // streaming can move chunks while the ones before them are read,
+            // they are taken from the cache if there is one
+            if voxel_uniforms.active_chunks[texture_index].position != chunk {
+                if self.region_cache.is_none() {
+                    warn!("Chunk {} left the active chunks before it was saved", chunk);
+                }
+                continue;
+            }
+
+            copy_chunk(
+                voxel_data,
+                texture_index,
+                unpack,
+                &self.buffer,
+                render_device,
+                render_queue,
+            )?;
+            let mapped = MapResult::default();
+            let result = mapped.clone();
+            self.buffer
+                .slice(..)
+                .map_async(MapMode::Read, move |r| _ = result.set(r));
+            self.in_flight = Some((chunk, mapped));
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+fn save_voxel_world(
+    (world_save, world_pallete, region_cache): (
+        Res<WorldSave>,
+        Res<WorldPallete>,
+        Option<Res<RegionCache>>,
+    ),
+    mut save_in_progress: ResMut<SaveInProgress>,
+    voxel_data: Res<VoxelData>,
+    voxel_uniforms: Res<VoxelUniforms>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    (pipeline_cache, brick_pipelines): (Res<PipelineCache>, Option<Res<bricks::Pipelines>>),
+) {
+    let requested = match world_save.as_ref() {
+        WorldSave::Vox(path) => Some((path.clone(), false)),
+        WorldSave::Native(path) => Some((path.clone(), true)),
+        WorldSave::None => None,
+    };
+    if let Some((path, native)) = requested {
+        if save_in_progress.0.is_some() {
+            warn!("Still saving the last world, {} is not saved", path);
+        } else {
+            save_in_progress.0 = Some(WorldReadBack::new(
+                path,
+                native,
+                &voxel_data,
+                (&voxel_uniforms, &world_pallete),
+                region_cache.as_deref().cloned(),
+                &render_device,
+            ));
+        }
+    }
+
+    let Some(read_back) = &mut save_in_progress.0 else {
+        return;
+    };
+    let unpack = brick_pipelines.and_then(|pipelines| pipelines.unpack(&pipeline_cache));
+    let result = read_back.step(
+        &voxel_data,
+        &voxel_uniforms,
+        unpack,
+        &render_device,
+        &render_queue,
+    );
+    if result == Ok(false) {
+        return;
+    }
+
+    let read_back = save_in_progress.0.take().unwrap();
+    read_back.buffer.destroy();
+    if let Err(e) = result {
+        error!("Failed to save world to {}: {}", read_back.path, e);
+        return;
+    }
+
+    let WorldReadBack {
+        path,
+        native,
+        mut gh,
+        read_back,
+        region_cache,
+        ..
+    } = read_back;
+    IoTaskPool::get()
+        .spawn(async move {
+            let cached = match &region_cache {
+                Some(region_cache) => add_cached_chunks(&mut gh, region_cache, &read_back).await,
+                None => Ok(()),
+            };
+            let file = cached.and_then(|()| {
+                if native {
+                    Ok(gh.to_native())
+                } else {
+                    gh.to_vox()
+                }
+            });
+            match file.and_then(|file| std::fs::write(&path, file).map_err(|e| e.to_string())) {
+                Ok(()) => info!("Saved world to {}", path),
+                Err(e) => error!("Failed to save world to {}: {}", path, e),
+            }
+        })
+        .detach();
+}
+
+/// Adds the chunks that were streamed out into the cache. The ones that were
+/// read back are newer than their copy in the cache.
+async fn add_cached_chunks(
+    gh: &mut GridHierarchy,
+    region_cache: &RegionCache,
+    read_back: &HashSet<IVec3>,
+) -> Result<(), String> {
+    let size = gh.texture_size;
+    for chunk in region_cache.cached_chunks(size)? {
+        if read_back.contains(&chunk) {
+            continue;
+        }
+        if let Some(data) = region_cache.load_chunk(chunk, size).await? {
+            if data.chunks_exact(2).any(|voxel| voxel[0] != 0) {
+                gh.chunks.insert(chunk, data);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Buffer big enough to read back one chunk with [`copy_chunk`].
+pub(super) fn create_read_back_buffer(
+    render_device: &RenderDevice,
+    size: u32,
+    voxel_format: VoxelFormat,
+) -> Buffer {
+    render_device.create_buffer(&BufferDescriptor {
+        label: Some("chunk read back buffer"),
+        size: padded_row_length(size * voxel_format.bytes_per_voxel()) as u64
+            * size as u64
+            * size as u64,
+        usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    })
+}
+
+// rows of a texture copy have to be aligned to 256 bytes
+pub(super) fn padded_row_length(row_length: u32) -> u32 {
+    row_length.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+}
+
+/// Submits the copy of a chunk into a buffer from [`create_read_back_buffer`],
+/// the buffer can be mapped once the copy is done. Bricks are unpacked with
+/// the `unpack` pipeline first.
+pub(super) fn copy_chunk(
+    voxel_data: &VoxelData,
+    texture_index: usize,
+    unpack: Option<&ComputePipeline>,
+    buffer: &Buffer,
+    render_device: &RenderDevice,
+    render_queue: &RenderQueue,
+) -> Result<(), String> {
+    let size = voxel_data.chunk_size;
+    let bytes_per_row = padded_row_length(size * voxel_data.voxel_format.bytes_per_voxel());
+
+    let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("chunk read back"),
+    });
+    if voxel_data.bricks.is_some() {
+        let unpack = unpack.ok_or("The brick pipelines are not ready")?;
+        unpack_chunk(
+            &mut command_encoder,
+            voxel_data,
+            unpack,
+            render_queue,
+            texture_index,
+        );
+    }
+    let (texture, origin) = voxel_data.chunk_texture(texture_index);
+    command_encoder.copy_texture_to_buffer(
+        ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin,
+            aspect: TextureAspect::All,
+        },
+        ImageCopyBuffer {
+            buffer,
+            layout: ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(bytes_per_row),
+                rows_per_image: Some(size),
+            },
+        },
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: size,
+        },
+    );
+    render_queue.submit([command_encoder.finish()]);
+    Ok(())
+}
+
+/// The chunk in a mapped read back buffer in the layout of the textures, the
+/// buffer is unmapped again. Animated voxels and portals are redrawn every
+/// frame so they are left out, like the clear pass does.
+pub(super) fn read_mapped_texture(
+    buffer: &Buffer,
+    size: u32,
+    voxel_format: VoxelFormat,
+) -> Vec<u8> {
+    let row_length = (size * voxel_format.bytes_per_voxel()) as usize;
+    let bytes_per_row = padded_row_length(row_length as u32);
+
+    // the textures are indexed with zyx so the rows are already in the
+    // same order as the chunk data, just without the padding
+    let mapped = buffer.slice(..).get_mapped_range();
+    let mut texture_data = Vec::with_capacity(size as usize * size as usize * row_length);
+    for row in mapped.chunks_exact(bytes_per_row as usize) {
+        texture_data.extend_from_slice(&row[..row_length]);
+    }
+    drop(mapped);
+    buffer.unmap();
+    // before narrowing, animated voxels can have any material
+    clear_redrawn_voxels(&mut texture_data, voxel_format);
+    texture_data
+}
+
+/// Empties the voxels clear.wgsl empties every frame.
+fn clear_redrawn_voxels(texture_data: &mut [u8], voxel_format: VoxelFormat) {
+    let bytes_per_voxel = voxel_format.bytes_per_voxel() as usize;
+    for voxel in texture_data.chunks_exact_mut(bytes_per_voxel) {
+        let mut value = [0; 4];
+        value[..bytes_per_voxel].copy_from_slice(voxel);
+        let (_, flags, _) = voxel_format.decode(u32::from_le_bytes(value));
+        if flags & (Flags::ANIMATION_FLAG | Flags::PORTAL_FLAG) != 0 {
+            voxel.fill(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redrawn_voxels_are_not_saved() {
+        let chunk = [
+            [3, Flags::COLLISION_FLAG],
+            [4, Flags::ANIMATION_FLAG],
+            [5, Flags::PORTAL_FLAG],
+            [0, Flags::ANIMATION_FLAG | Flags::PORTAL_FLAG],
+        ]
+        .concat();
+        let saved = [[3, Flags::COLLISION_FLAG], [0, 0], [0, 0], [0, 0]].concat();
+        for voxel_format in [VoxelFormat::Narrow, VoxelFormat::Wide] {
+            let mut data = voxel_format.widen(&chunk).into_owned();
+            clear_redrawn_voxels(&mut data, voxel_format);
+            assert_eq!(voxel_format.narrow(data), Ok(saved.clone()));
+        }
+    }
+
+    #[test]
+    fn wide_materials_are_counted_when_narrowed() {
+        let format = VoxelFormat::Wide;
+        let data: Vec<u8> = [
+            format.encode(3, Flags::COLLISION_FLAG, 7),
+            format.encode(300, Flags::COLLISION_FLAG, 0),
+            format.encode(4095, 0, 0),
+        ]
+        .into_iter()
+        .flat_map(u32::to_le_bytes)
+        .collect();
+
+        assert!(format.narrow(data.clone()).is_err());
+        let narrowed = [[3, Flags::COLLISION_FLAG], [0, 0], [0, 0]].concat();
+        assert_eq!(format.narrow_lossy(data), (narrowed, 2));
+    }
+}
@@ -1,7 +1,150 @@
 #define_import_path bevy_voxel_engine::bindings
 
-#import bevy_voxel_engine::common::VoxelUniforms
+#import bevy_voxel_engine::common::{
+    VoxelUniforms,
+    Material,
+    WideMaterial,
+    BrickAllocator,
+    VoxelObjects,
+    chunk_texture_index,
+    atlas_position,
+    in_chunk,
+    brick_cell,
+    brick_position,
+}
 
+// the texture type is spelled out, naga_oil duplicates imported globals
+// declared with an alias
 @group(0) @binding(0) var<uniform> voxel_uniforms: VoxelUniforms;
-@group(0) @binding(1) var voxel_worlds: binding_array<texture_storage_3d<r16uint, read_write>, 27>;
-@group(0) @binding(2) var<storage, read_write> gh: array<u32>;
\ No newline at end of file
+#ifdef CHUNK_ATLAS
+#ifdef VOXEL_WIDE
+@group(0) @binding(1) var voxel_worlds: texture_storage_3d<r32uint, read_write>;
+#else
+@group(0) @binding(1) var voxel_worlds: texture_storage_3d<r16uint, read_write>;
+#endif
+#else ifdef CHUNK_BRICKS
+#ifdef VOXEL_WIDE
+@group(0) @binding(1) var voxel_worlds: texture_storage_3d<r32uint, read_write>; // brick pool
+#else
+@group(0) @binding(1) var voxel_worlds: texture_storage_3d<r16uint, read_write>; // brick pool
+#endif
+@group(0) @binding(4) var<storage, read_write> brick_index: array<atomic<u32>>;
+@group(0) @binding(5) var<storage, read_write> brick_allocator: BrickAllocator;
+#else
+#ifdef VOXEL_WIDE
+@group(0) @binding(1) var voxel_worlds: binding_array<texture_storage_3d<r32uint, read_write>, #{ACTIVE_CHUNKS}>;
+#else
+@group(0) @binding(1) var voxel_worlds: binding_array<texture_storage_3d<r16uint, read_write>, #{ACTIVE_CHUNKS}>;
+#endif
+#endif
+@group(0) @binding(2) var<storage, read_write> gh: array<u32>;
+#ifdef VOXEL_WIDE
+@group(0) @binding(7) var<storage, read> wide_materials: array<WideMaterial>;
+#endif
+@group(0) @binding(8) var<storage, read> voxel_objects: VoxelObjects;
+@group(0) @binding(9) var<storage, read> object_voxels: array<u32>;
+
+fn get_chunk_index(voxel_pos: vec3<f32>) -> i32 {
+    return chunk_texture_index(voxel_pos, voxel_uniforms.chunk_size, voxel_uniforms.origin_chunk, voxel_uniforms.chunk_grid);
+}
+
+// the palette only has the first 256 materials
+fn material_colour(material: u32) -> vec4<f32> {
+#ifdef VOXEL_WIDE
+    if (material >= 256u) {
+        return wide_materials[material - 256u].colour;
+    }
+#endif
+    return voxel_uniforms.pallete[material];
+}
+
+fn material_properties(material: u32) -> Material {
+#ifdef VOXEL_WIDE
+    if (material >= 256u) {
+        return wide_materials[material - 256u].material;
+    }
+#endif
+    return voxel_uniforms.materials[material];
+}
+
+#ifdef CHUNK_BRICKS
+// bricks come from the free list first, then from the untouched end of the
+// pool. the cell goes to whoever sets it first, the other bricks are handed
+// back. returns one more than the brick, zero if the pool is full and the
+// write is dropped
+fn allocate_brick(cell: u32) -> u32 {
+    var brick = 0u;
+    let free = atomicSub(&brick_allocator.free_count, 1) - 1;
+    if (free >= 0) {
+        brick = brick_allocator.free[free];
+    } else {
+        atomicAdd(&brick_allocator.free_count, 1);
+        brick = atomicAdd(&brick_allocator.next_brick, 1u);
+        if (brick >= brick_allocator.capacity) {
+            atomicAdd(&brick_allocator.dropped, 1u);
+            return 0u;
+        }
+    }
+
+    loop {
+        let result = atomicCompareExchangeWeak(&brick_index[cell], 0u, brick + 1u);
+        if (result.exchanged) {
+            return brick + 1u;
+        }
+        if (result.old_value != 0u) {
+            free_brick(brick);
+            return result.old_value;
+        }
+    }
+    return 0u;
+}
+
+// the brick has to be empty, it is only reused once the free list is refilled
+fn free_brick(brick: u32) {
+    let freed = atomicAdd(&brick_allocator.freed_count, 1u);
+    brick_allocator.free[brick_allocator.capacity + freed] = brick;
+}
+#endif
+
+// the position is inside of the chunk, these work the same for every chunk storage
+fn load_voxel(chunk_index: i32, pos: vec3<i32>) -> u32 {
+#ifdef CHUNK_ATLAS
+    return textureLoad(voxel_worlds, atlas_position(chunk_index, pos, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid)).r;
+#else ifdef CHUNK_BRICKS
+    if (!in_chunk(pos, voxel_uniforms.chunk_size)) {
+        return 0u;
+    }
+    let brick = atomicLoad(&brick_index[brick_cell(chunk_index, pos, voxel_uniforms.chunk_size)]);
+    if (brick == 0u) {
+        return 0u;
+    }
+    return textureLoad(voxel_worlds, brick_position(brick - 1u, pos)).r;
+#else
+    return textureLoad(voxel_worlds[chunk_index], pos.zyx).r;
+#endif
+}
+
+fn store_voxel(chunk_index: i32, pos: vec3<i32>, value: u32) {
+#ifdef CHUNK_ATLAS
+    textureStore(voxel_worlds, atlas_position(chunk_index, pos, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid), vec4(value));
+#else ifdef CHUNK_BRICKS
+    if (!in_chunk(pos, voxel_uniforms.chunk_size)) {
+        return;
+    }
+    // empty space stays unallocated
+    let cell = brick_cell(chunk_index, pos, voxel_uniforms.chunk_size);
+    var brick = atomicLoad(&brick_index[cell]);
+    if (brick == 0u) {
+        if (value == 0u) {
+            return;
+        }
+        brick = allocate_brick(cell);
+        if (brick == 0u) {
+            return;
+        }
+    }
+    textureStore(voxel_worlds, brick_position(brick - 1u, pos), vec4(value));
+#else
+    textureStore(voxel_worlds[chunk_index], pos.zyx, vec4(value));
+#endif
+}
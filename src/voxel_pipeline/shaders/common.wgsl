@@ -6,7 +6,53 @@ const ANIMATION_FLAG = 32u; // 0b00100000
 const COLLISION_FLAG = 16u; // 0b00010000
 const SAND_FLAG = 8u; // 0b00001000
 
-const VOXELS_PER_METER: f32 = 4.0;
+// the voxel codec, voxels are only read and written through these. the bit
+// layouts are in LAYOUT.md
+#ifdef VOXEL_WIDE
+const MATERIAL_BITS = 12u;
+#else
+const MATERIAL_BITS = 8u;
+#endif
+const STATE_SHIFT = 20u;
+
+fn voxel_material(voxel: u32) -> u32 {
+    return voxel & ((1u << MATERIAL_BITS) - 1u);
+}
+
+fn voxel_flags(voxel: u32) -> u32 {
+    return (voxel >> MATERIAL_BITS) & 0xFFu;
+}
+
+// always 0 with the narrow format
+fn voxel_state(voxel: u32) -> u32 {
+#ifdef VOXEL_WIDE
+    return voxel >> STATE_SHIFT;
+#else
+    return 0u;
+#endif
+}
+
+fn encode_voxel(material: u32, flags: u32) -> u32 {
+    return material | (flags << MATERIAL_BITS);
+}
+
+fn with_voxel_flags(voxel: u32, flags: u32) -> u32 {
+    return (voxel & ~(0xFFu << MATERIAL_BITS)) | (flags << MATERIAL_BITS);
+}
+
+// the narrow format has no room for state, the voxel is returned as it is
+fn with_voxel_state(voxel: u32, state: u32) -> u32 {
+#ifdef VOXEL_WIDE
+    return (voxel & ((1u << STATE_SHIFT) - 1u)) | (state << STATE_SHIFT);
+#else
+    return voxel;
+#endif
+}
+
+// voxels packed by the cpu always have the narrow layout
+fn decode_narrow_voxel(value: u32) -> u32 {
+    return encode_voxel(value & 0xFFu, (value >> 8u) & 0xFFu);
+}
 
 const PI: f32 = 3.14159265358979323846264338327950288;
 
@@ -20,14 +66,44 @@ struct ChunkInfo {
     texture_index: u32,
 }
 
+struct Material {
+    roughness: f32,
+    metalness: f32,
+    specular: f32,
+    transparency: f32,
+    ior: f32,
+    emission: f32,
+    padding: vec2<f32>,
+}
+
+// materials from 256 on, see WideMaterials
+struct WideMaterial {
+    colour: vec4<f32>,
+    material: Material,
+}
+
+// first and last slot for ranges
+struct MaterialIds {
+    grass: u32,
+    water: u32,
+    grass_blades: vec2<u32>,
+    fire: vec2<u32>,
+}
+
 struct VoxelUniforms {
-    materials: array<vec4<f32>, 256>,
+    pallete: array<vec4<f32>, 256>,
+    materials: array<Material, 256>,
+    material_ids: MaterialIds,
     portals: array<Portal, 32>,
     levels: array<vec4<u32>, 8>,
     offsets: array<vec4<u32>, 8>,
     texture_size: u32,
     chunk_size: u32,
-    active_chunks: array<ChunkInfo, 27>, // 3x3x3 chunk grid around player
+    world_size: u32,
+    voxels_per_meter: f32,
+    origin_chunk: vec3<i32>, // chunk the active chunks are centred on
+    chunk_grid: vec3<u32>,
+    active_chunks: array<ChunkInfo, #{ACTIVE_CHUNKS}>, // chunk grid around the origin chunk
 }
 
 struct TraceUniforms {
@@ -39,8 +115,98 @@ struct TraceUniforms {
     show_ray_steps: u32,
     samples: u32,
     shadows: u32,
+    indirect_bounces: u32,
 };
 
+// chunks are kept in the texture of their chunk position modulo the grid, so
+// finding the texture of a voxel doesn't need to search the active chunks.
+// the position is in voxels relative to the origin chunk, -1 if it is outside
+// of the active chunks
+fn chunk_texture_index(voxel_pos: vec3<f32>, chunk_size: u32, origin_chunk: vec3<i32>, chunk_grid: vec3<u32>) -> i32 {
+    let offset = vec3<i32>(floor(voxel_pos / f32(chunk_size)));
+    let grid = vec3<i32>(chunk_grid);
+    if (any(abs(offset) > grid / 2)) {
+        return -1;
+    }
+    let slot = ((origin_chunk + offset) % grid + grid) % grid;
+    return slot.x + (slot.y + slot.z * grid.y) * grid.x;
+}
+
+// the position inside of its chunk of a position relative to the origin chunk,
+// % keeps the sign so chunks below the origin chunk need this
+fn chunk_position(texture_pos: vec3<i32>, chunk_size: u32) -> vec3<i32> {
+    let size = i32(chunk_size);
+    return (texture_pos % size + size) % size;
+}
+
+// the compute passes run over the whole chunk grid, this is the position of an
+// invocation in voxels relative to the origin chunk
+fn grid_texture_position(invocation_id: vec3<u32>, chunk_size: u32, chunk_grid: vec3<u32>) -> vec3<i32> {
+    return vec3<i32>(invocation_id) - vec3<i32>(chunk_grid / 2u) * i32(chunk_size);
+}
+
+// every active chunk has its own grid hierarchy, by texture. the last level
+// ends the hierarchy of a chunk
+fn pyramid_start(chunk_index: i32, last_offset: u32, last_level: u32) -> u32 {
+    return u32(chunk_index) * (last_offset + last_level * last_level * last_level);
+}
+
+// with the chunk atlas every chunk has its own corner in one texture, laid out
+// like the chunk grid. returns the texel of a voxel, textures are indexed zyx
+fn atlas_position(chunk_index: i32, pos: vec3<i32>, chunk_size: u32, chunk_grid: vec3<u32>) -> vec3<i32> {
+    let grid = vec3<i32>(chunk_grid);
+    let slot = vec3(chunk_index % grid.x, (chunk_index / grid.x) % grid.y, chunk_index / (grid.x * grid.y));
+    return (slot * i32(chunk_size) + pos).zyx;
+}
+
+// the brick storage splits chunks into bricks of 8x8x8 voxels, kept in a pool
+// that is 64x64 bricks wide and grows along z
+const BRICK_SIZE: i32 = 8;
+const BRICK_POOL_WIDTH: u32 = 64u;
+
+// the first capacity entries of free are the free list, the bricks freed
+// while bricks are being allocated follow them until the free list is refilled.
+// dropped counts the voxels that weren't written because the pool was full,
+// the cpu reads it back
+struct BrickAllocator {
+    free_count: atomic<i32>,
+    next_brick: atomic<u32>,
+    freed_count: atomic<u32>,
+    dropped: atomic<u32>,
+    capacity: u32,
+    free: array<u32>,
+}
+
+// object_from_world maps world positions to voxels from the corner of the
+// object, its voxels start at offset with z as the innermost axis
+struct VoxelObject {
+    object_from_world: mat4x4<f32>,
+    size: vec3<u32>,
+    offset: u32,
+}
+
+struct VoxelObjects {
+    count: u32,
+    objects: array<VoxelObject>,
+}
+
+fn in_chunk(pos: vec3<i32>, chunk_size: u32) -> bool {
+    return all(pos >= vec3(0)) && all(pos < vec3(i32(chunk_size)));
+}
+
+// entry of the brick index for the brick a voxel of a chunk is in
+fn brick_cell(chunk_index: i32, pos: vec3<i32>, chunk_size: u32) -> u32 {
+    let cells = i32(chunk_size) / BRICK_SIZE;
+    let cell = pos / BRICK_SIZE;
+    return u32(((chunk_index * cells + cell.x) * cells + cell.y) * cells + cell.z);
+}
+
+// texel of a voxel in the brick pool, indexed zyx like the chunk textures
+fn brick_position(brick: u32, pos: vec3<i32>) -> vec3<i32> {
+    let slot = vec3(brick % BRICK_POOL_WIDTH, (brick / BRICK_POOL_WIDTH) % BRICK_POOL_WIDTH, brick / (BRICK_POOL_WIDTH * BRICK_POOL_WIDTH));
+    return (vec3<i32>(slot) * BRICK_SIZE + pos % BRICK_SIZE).zyx;
+}
+
 fn get_clip_space(frag_pos: vec4<f32>, dimensions: vec2<f32>) -> vec2<f32> {
     var clip_space = frag_pos.xy / dimensions * 2.0;
     clip_space = clip_space - 1.0;
@@ -1,18 +1,24 @@
 #define_import_path bevy_voxel_engine::raytracing
 
 #import bevy_voxel_engine::common::{
-    VOXELS_PER_METER,
     PORTAL_FLAG,
     VoxelUniforms,
     Ray,
     ray_plane,
-    in_bounds,
     ray_box_dist,
+    voxel_material,
+    voxel_flags,
+    VoxelObject,
+    pyramid_start,
 }
 #import bevy_voxel_engine::bindings::{
-    voxel_worlds,
     voxel_uniforms,
-    gh
+    gh,
+    get_chunk_index,
+    load_voxel,
+    material_colour,
+    voxel_objects,
+    object_voxels,
 }
 
 fn get_value_index(index: u32) -> bool {
@@ -24,8 +30,55 @@ struct Voxel {
     pos: vec3<f32>,
     grid_size: u32,
 };
+
+// render space spans the chunk grid, it is world_size voxels across, -1 to 1
+// along every axis and centred on the origin chunk. the pyramid of a chunk is
+// looked up in chunk space, -1 to 1 across the chunk
+
+// voxels from the corner of the origin chunk, like get_chunk_index takes them
+fn render_to_texture(pos: vec3<f32>) -> vec3<f32> {
+    let world_size = f32(voxel_uniforms.world_size);
+    let chunk_size = f32(voxel_uniforms.chunk_size);
+    return (pos * 0.5 + 0.5) * world_size - (world_size - chunk_size) * 0.5;
+}
+
+fn texture_to_render(texture_pos: vec3<f32>) -> vec3<f32> {
+    let world_size = f32(voxel_uniforms.world_size);
+    let chunk_size = f32(voxel_uniforms.chunk_size);
+    return (texture_pos + (world_size - chunk_size) * 0.5) / world_size * 2.0 - 1.0;
+}
+
+// corners of the chunk grid in render space, grids that aren't cubes leave
+// some of it empty
+fn grid_min() -> vec3<f32> {
+    return texture_to_render(-vec3<f32>(voxel_uniforms.chunk_grid / 2u) * f32(voxel_uniforms.chunk_size));
+}
+
+fn grid_max() -> vec3<f32> {
+    return texture_to_render(vec3<f32>(voxel_uniforms.chunk_grid / 2u + 1u) * f32(voxel_uniforms.chunk_size));
+}
+
+fn in_grid(pos: vec3<f32>) -> bool {
+    return all(pos >= grid_min()) && all(pos < grid_max());
+}
+
+// the voxel or empty cell around a position in render space, with its centre
+// in render space
 fn get_value(pos: vec3<f32>, chunk_index: i32) -> Voxel {
+    let chunk_size = f32(voxel_uniforms.chunk_size);
+    let texture_pos = render_to_texture(pos);
+    let corner = floor(texture_pos / chunk_size) * chunk_size;
+    let voxel = get_chunk_value((texture_pos - corner) / chunk_size * 2.0 - 1.0, chunk_index);
+
+    let chunks_across = voxel_uniforms.world_size / voxel_uniforms.chunk_size;
+    let voxel_pos = texture_to_render((voxel.pos * 0.5 + 0.5) * chunk_size + corner);
+    return Voxel(voxel.data, voxel_pos, voxel.grid_size * chunks_across);
+}
+
+// like get_value with a position and the centre in chunk space
+fn get_chunk_value(pos: vec3<f32>, chunk_index: i32) -> Voxel {
     let scaled = pos * 0.5 + 0.5;
+    let start = pyramid_start(chunk_index, voxel_uniforms.offsets[7].x, voxel_uniforms.levels[7].x);
 
     let size0 = voxel_uniforms.levels[0].x;
     let size1 = voxel_uniforms.levels[1].x;
@@ -45,14 +98,14 @@ fn get_value(pos: vec3<f32>, chunk_index: i32) -> Voxel {
     let scaled6 = vec3<u32>(scaled * f32(size6));
     let scaled7 = vec3<u32>(scaled * f32(size7));
 
-    let state0 = get_value_index(voxel_uniforms.offsets[0].x + scaled0.x * size0 * size0 + scaled0.y * size0 + scaled0.z);
-    let state1 = get_value_index(voxel_uniforms.offsets[1].x + scaled1.x * size1 * size1 + scaled1.y * size1 + scaled1.z);
-    let state2 = get_value_index(voxel_uniforms.offsets[2].x + scaled2.x * size2 * size2 + scaled2.y * size2 + scaled2.z);
-    let state3 = get_value_index(voxel_uniforms.offsets[3].x + scaled3.x * size3 * size3 + scaled3.y * size3 + scaled3.z);
-    let state4 = get_value_index(voxel_uniforms.offsets[4].x + scaled4.x * size4 * size4 + scaled4.y * size4 + scaled4.z);
-    let state5 = get_value_index(voxel_uniforms.offsets[5].x + scaled5.x * size5 * size5 + scaled5.y * size5 + scaled5.z);
-    let state6 = get_value_index(voxel_uniforms.offsets[6].x + scaled6.x * size6 * size6 + scaled6.y * size6 + scaled6.z);
-    let state7 = get_value_index(voxel_uniforms.offsets[7].x + scaled7.x * size7 * size7 + scaled7.y * size7 + scaled7.z);
+    let state0 = get_value_index(start + voxel_uniforms.offsets[0].x + scaled0.x * size0 * size0 + scaled0.y * size0 + scaled0.z);
+    let state1 = get_value_index(start + voxel_uniforms.offsets[1].x + scaled1.x * size1 * size1 + scaled1.y * size1 + scaled1.z);
+    let state2 = get_value_index(start + voxel_uniforms.offsets[2].x + scaled2.x * size2 * size2 + scaled2.y * size2 + scaled2.z);
+    let state3 = get_value_index(start + voxel_uniforms.offsets[3].x + scaled3.x * size3 * size3 + scaled3.y * size3 + scaled3.z);
+    let state4 = get_value_index(start + voxel_uniforms.offsets[4].x + scaled4.x * size4 * size4 + scaled4.y * size4 + scaled4.z);
+    let state5 = get_value_index(start + voxel_uniforms.offsets[5].x + scaled5.x * size5 * size5 + scaled5.y * size5 + scaled5.z);
+    let state6 = get_value_index(start + voxel_uniforms.offsets[6].x + scaled6.x * size6 * size6 + scaled6.y * size6 + scaled6.z);
+    let state7 = get_value_index(start + voxel_uniforms.offsets[7].x + scaled7.x * size7 * size7 + scaled7.y * size7 + scaled7.z);
 
     if (!state0 && size0 != 0u) {
         let rounded_pos = ((vec3<f32>(scaled0) + 0.5) / f32(size0)) * 2.0 - 1.0;
@@ -87,19 +140,16 @@ fn get_value(pos: vec3<f32>, chunk_index: i32) -> Voxel {
         return Voxel(0u, rounded_pos, size7);
     }
     let rounded_pos = (floor(pos * f32(voxel_uniforms.texture_size) * 0.5) + 0.5) / (f32(voxel_uniforms.texture_size) * 0.5);
-    let data = textureLoad(voxel_worlds[chunk_index], vec3<i32>(scaled * f32(voxel_uniforms.texture_size)).zyx).r;
+    let data = load_voxel(chunk_index, vec3<i32>(scaled * f32(voxel_uniforms.texture_size)));
 
     return Voxel(data, rounded_pos, voxel_uniforms.texture_size);
 }
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
-        }
-    }
-    return -1; // Out of bounds
+
+// world position of the centre of render space, the middle of the origin chunk
+fn world_origin() -> vec3<f32> {
+    return vec3<f32>(voxel_uniforms.origin_chunk * i32(voxel_uniforms.chunk_size)) / voxel_uniforms.voxels_per_meter;
 }
+
 struct HitInfo {
     hit: bool,
     data: u32,
@@ -119,10 +169,10 @@ const IDENTITY = mat4x4<f32>(
 );
 
 fn intersect_scene(r: Ray, steps: u32) -> HitInfo {
-    let rtw = f32(voxel_uniforms.texture_size) / (VOXELS_PER_METER * 2.0); // render to world ratio
+    let rtw = f32(voxel_uniforms.world_size) / (voxel_uniforms.voxels_per_meter * 2.0); // render to world ratio
 
     let normal = vec3(0.0, 1.0, 0.0);
-    let hit = ray_plane(r, vec3(0.0, -1.0, 0.0), normal).xyz;
+    let hit = ray_plane(r, vec3(0.0, grid_min().y, 0.0), normal).xyz;
 
     if (any(hit != vec3(0.0))) {
         let pos = hit + normal * 0.000002;
@@ -130,7 +180,8 @@ fn intersect_scene(r: Ray, steps: u32) -> HitInfo {
         // green floor
         let color = vec3(113.0, 129.0, 44.0) / 255.0;
 
-        return HitInfo(true, 0u, vec4(color, 0.0), pos * rtw, pos * rtw, normal, IDENTITY, steps);
+        let world_pos = pos * rtw + world_origin();
+        return HitInfo(true, 0u, vec4(color, 0.0), world_pos, world_pos, normal, IDENTITY, steps);
     }
 
     let infinity = 1000000000.0 * r.dir;
@@ -142,21 +193,110 @@ fn intersect_scene(r: Ray, steps: u32) -> HitInfo {
 /// ray direction if you want it to be in world cordinates.
 /// only hits voxels that have any of the flags set or hits everything if flags is 0
 fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
-    let wtr = VOXELS_PER_METER * 2.0 / f32(voxel_uniforms.texture_size); // world to render
-    let rtw = f32(voxel_uniforms.texture_size) / (VOXELS_PER_METER * 2.0); // render to world
+    let hit = shoot_world_ray(r, physics_distance, flags);
+
+    // reprojection_pos follows the ray before any portal
+    var max_t = 1000000000.0;
+    if (hit.hit) {
+        max_t = dot(hit.reprojection_pos - r.pos, r.dir) / dot(r.dir, r.dir);
+    } else if (physics_distance > 0.0) {
+        max_t = physics_distance;
+    }
+
+    var object_hit = ObjectHit(false, 0u, max_t, vec3(0.0), 0u);
+    for (var i = 0u; i < voxel_objects.count; i++) {
+        let next = shoot_object(r, voxel_objects.objects[i], object_hit.t, flags);
+        object_hit.steps += next.steps;
+        if (next.hit) {
+            object_hit = ObjectHit(true, next.data, next.t, next.normal, object_hit.steps);
+        }
+    }
+
+    if (!object_hit.hit) {
+        var world_hit = hit;
+        world_hit.steps += object_hit.steps;
+        return world_hit;
+    }
+    let pos = r.pos + r.dir * object_hit.t;
+    return HitInfo(true, object_hit.data, material_colour(voxel_material(object_hit.data)), pos + object_hit.normal * 0.0001, pos, object_hit.normal, IDENTITY, hit.steps + object_hit.steps);
+}
+
+struct ObjectHit {
+    hit: bool,
+    data: u32,
+    t: f32,
+    normal: vec3<f32>,
+    steps: u32,
+};
+
+// walks the voxels of an object in its own space, t is the same along the ray
+// in both spaces as the transform is affine
+fn shoot_object(r: Ray, object: VoxelObject, max_t: f32, flags: u32) -> ObjectHit {
+    let pos = (object.object_from_world * vec4(r.pos, 1.0)).xyz;
+    let object_dir = (object.object_from_world * vec4(r.dir, 0.0)).xyz;
+    let dir = object_dir + vec3<f32>(object_dir == vec3(0.0)) * 0.000001;
+    let size = vec3<f32>(object.size);
+
+    let dist = ray_box_dist(Ray(pos, dir), vec3(0.0), size);
+    if (all(dist == vec2(0.0)) || dist.x >= max_t) {
+        return ObjectHit(false, 0u, max_t, vec3(0.0), 0u);
+    }
+
+    // the face the ray enters through, if it starts outside
+    let entry = min(-pos / dir, (size - pos) / dir);
+    var mask = vec3<f32>(entry >= max(entry.yzx, entry.zxy));
+
+    var t = max(dist.x, 0.0);
+    let r_sign = sign(dir);
+    var voxel = clamp(vec3<i32>(floor(pos + dir * t)), vec3(0), vec3<i32>(object.size) - 1);
+    let t_delta = abs(1.0 / dir);
+    var t_max = (vec3<f32>(voxel) + max(r_sign, vec3(0.0)) - pos) / dir;
+
+    let max_steps = object.size.x + object.size.y + object.size.z;
+    var steps = 0u;
+    while (steps < max_steps && t < max_t) {
+        if (any(voxel < vec3(0)) || any(voxel >= vec3<i32>(object.size))) {
+            break;
+        }
+
+        let index = (u32(voxel.x) * object.size.y + u32(voxel.y)) * object.size.z + u32(voxel.z);
+        let data = object_voxels[object.offset + index];
+        let hit_flags = (voxel_flags(data) & flags) > 0u || flags == 0u;
+        if (voxel_material(data) != 0u && (voxel_flags(data) & PORTAL_FLAG) == 0u && hit_flags) {
+            // normals are transformed with the inverse transpose
+            let normal = normalize((transpose(object.object_from_world) * vec4(mask * -r_sign, 0.0)).xyz);
+            return ObjectHit(true, data, t, normal, steps);
+        }
+
+        mask = vec3<f32>(t_max <= min(t_max.yzx, t_max.zxy));
+        t = dot(t_max, mask);
+        t_max += mask * t_delta;
+        voxel += vec3<i32>(mask * r_sign);
+        steps += 1u;
+    }
+
+    return ObjectHit(false, 0u, max_t, vec3(0.0), steps);
+}
+
+// the voxels of the chunks, without the objects
+fn shoot_world_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
+    let wtr = voxel_uniforms.voxels_per_meter * 2.0 / f32(voxel_uniforms.world_size); // world to render
+    let rtw = f32(voxel_uniforms.world_size) / (voxel_uniforms.voxels_per_meter * 2.0); // render to world
+
+    let origin = world_origin();
 
-    var pos = r.pos * wtr;
+    var pos = (r.pos - origin) * wtr;
     let dir_mask = vec3<f32>(r.dir == vec3(0.0));
     var dir = r.dir + dir_mask * 0.000001;
 
     var distance = 0.0;
-    if (!in_bounds(pos)) {
-        // Get position on surface of the octree
-        let dist = ray_box_dist(Ray(pos, dir), vec3(-1.0), vec3(1.0)).x;
+    if (!in_grid(pos)) {
+        // Get position on surface of the chunk grid
+        let dist = ray_box_dist(Ray(pos, dir), grid_min(), grid_max()).x;
 
         if (dist == 0.0) {
             if (physics_distance * wtr > 0.0) {
-                return HitInfo(false, 0u, vec4(0.0), (pos + dir * physics_distance * wtr) * rtw, vec3(0.0), vec3(0.0), IDENTITY, 1u);
+                return HitInfo(false, 0u, vec4(0.0), (pos + dir * physics_distance * wtr) * rtw + origin, vec3(0.0), vec3(0.0), IDENTITY, 1u);
             }
             return intersect_scene(Ray(pos, dir), 1u);
         }
@@ -168,20 +308,23 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
     var r_sign = sign(dir);
     var tcpotr = pos; // the current position of the ray
     var steps = 0u;
-    var normal = trunc(pos * 1.00001);
+    // the face the ray entered the grid through
+    var normal = vec3<f32>(pos >= grid_max() - 0.00001) - vec3<f32>(pos <= grid_min() + 0.00001);
     var voxel = Voxel(0u, vec3(0.0), 0u);
     var portal_mat = IDENTITY;
     var reprojection_pos = pos;
-    while (steps < 100u) {
-        let chunk_index = get_chunk_index(tcpotr);
+    // longer rays for larger grids
+    let max_steps = 100u * (voxel_uniforms.world_size / voxel_uniforms.chunk_size);
+    while (steps < max_steps) {
+        let chunk_index = get_chunk_index(render_to_texture(tcpotr));
         if (chunk_index == -1) {
             break; // Ray has left the active chunks
         }
 
         voxel = get_value(tcpotr, chunk_index);
 
-        let should_portal_skip = ((voxel.data >> 8u) & PORTAL_FLAG) > 0u;
-        if ((voxel.data & 0xFFu) != 0u && !should_portal_skip && (((voxel.data >> 8u) & flags) > 0u || flags == 0u)) {
+        let should_portal_skip = (voxel_flags(voxel.data) & PORTAL_FLAG) > 0u;
+        if (voxel_material(voxel.data) != 0u && !should_portal_skip && ((voxel_flags(voxel.data) & flags) > 0u || flags == 0u)) {
             break;
         }
 
@@ -198,11 +341,11 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
 
         // portals
         if (should_portal_skip) {
-            let portal = voxel_uniforms.portals[i32(voxel.data & 0xFFu)];
+            let portal = voxel_uniforms.portals[i32(voxel_material(voxel.data))];
 
-            let intersection = ray_plane(Ray(pos * rtw, dir), portal.position + portal.normal * 0.00002, portal.normal);
+            let intersection = ray_plane(Ray(pos * rtw + origin, dir), portal.position + portal.normal * 0.00002, portal.normal);
             if (intersection.w != 0.0 && intersection.w * wtr < t_current) {
-                pos = (portal.transformation * vec4(intersection.xyz - portal.normal * 0.00004, 1.0)).xyz * wtr;
+                pos = ((portal.transformation * vec4(intersection.xyz - portal.normal * 0.00004, 1.0)).xyz - origin) * wtr;
                 dir = (portal.transformation * vec4(dir, 0.0)).xyz;
                 r_sign = sign(dir);
                 tcpotr = pos;
@@ -214,12 +357,12 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
         }
 
         if (t_current + distance > physics_distance * wtr && physics_distance > 0.0) {
-            return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw, vec3(0.0), vec3(0.0), portal_mat, steps);
+            return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw + origin, vec3(0.0), vec3(0.0), portal_mat, steps);
         }
 
-        if (!in_bounds(tcpotr)) {
+        if (!in_grid(tcpotr)) {
             if (physics_distance > 0.0) {
-                return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw, vec3(0.0), vec3(0.0), portal_mat, steps);
+                return HitInfo(false, 0u, vec4(0.0), (pos + dir * (physics_distance * wtr - distance)) * rtw + origin, vec3(0.0), vec3(0.0), portal_mat, steps);
             }
 
             return intersect_scene(Ray(pos, dir), steps);
@@ -228,5 +371,5 @@ fn shoot_ray(r: Ray, physics_distance: f32, flags: u32) -> HitInfo {
         steps = steps + 1u;
     }
 
-    return HitInfo(true, voxel.data, voxel_uniforms.materials[voxel.data & 0xFFu], tcpotr * rtw + normal * 0.0001, reprojection_pos, normal, portal_mat, steps);
+    return HitInfo(true, voxel.data, material_colour(voxel_material(voxel.data)), tcpotr * rtw + origin + normal * 0.0001, reprojection_pos, normal, portal_mat, steps);
 }
\ No newline at end of file
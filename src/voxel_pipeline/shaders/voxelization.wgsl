@@ -6,16 +6,39 @@
 }
 #import bevy_voxel_engine::common::{
     VoxelUniforms,
-    VOXELS_PER_METER,
+    BrickAllocator,
+    voxel_material,
+    voxel_flags,
+    encode_voxel,
+    chunk_texture_index,
+    atlas_position,
+    in_chunk,
+    brick_cell,
+    brick_position,
+    chunk_position,
 }
 
+#ifdef VOXEL_WIDE
+alias VoxelTexture = texture_storage_3d<r32uint, read_write>;
+#else
+alias VoxelTexture = texture_storage_3d<r16uint, read_write>;
+#endif
+
 struct VoxelizationUniforms {
     material: u32,
     flags: u32,
 }
 
 @group(2) @binding(0) var<uniform> voxel_uniforms: VoxelUniforms;
-@group(2) @binding(1) var voxel_worlds: binding_array<texture_storage_3d<r16uint, read_write>, 27>;
+#ifdef CHUNK_ATLAS
+@group(2) @binding(1) var voxel_worlds: VoxelTexture;
+#else ifdef CHUNK_BRICKS
+@group(2) @binding(1) var voxel_worlds: VoxelTexture; // brick pool
+@group(2) @binding(4) var<storage, read_write> brick_index: array<atomic<u32>>;
+@group(2) @binding(5) var<storage, read_write> brick_allocator: BrickAllocator;
+#else
+@group(2) @binding(1) var voxel_worlds: binding_array<VoxelTexture, #{ACTIVE_CHUNKS}>;
+#endif
 @group(2) @binding(2) var<storage, read> gh: array<u32>;
 
 @group(3) @binding(0) var<uniform> voxelization_uniforms: VoxelizationUniforms;
@@ -46,31 +69,96 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     return out;
 }
 
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
+fn get_chunk_index(voxel_pos: vec3<f32>) -> i32 {
+    return chunk_texture_index(voxel_pos, voxel_uniforms.chunk_size, voxel_uniforms.origin_chunk, voxel_uniforms.chunk_grid);
+}
+
+// same as in bindings.wgsl, which binds the voxels to group 0
+#ifdef CHUNK_BRICKS
+fn allocate_brick(cell: u32) -> u32 {
+    var brick = 0u;
+    let free = atomicSub(&brick_allocator.free_count, 1) - 1;
+    if (free >= 0) {
+        brick = brick_allocator.free[free];
+    } else {
+        atomicAdd(&brick_allocator.free_count, 1);
+        brick = atomicAdd(&brick_allocator.next_brick, 1u);
+        if (brick >= brick_allocator.capacity) {
+            return 0u;
+        }
+    }
+
+    loop {
+        let result = atomicCompareExchangeWeak(&brick_index[cell], 0u, brick + 1u);
+        if (result.exchanged) {
+            return brick + 1u;
+        }
+        if (result.old_value != 0u) {
+            let freed = atomicAdd(&brick_allocator.freed_count, 1u);
+            brick_allocator.free[brick_allocator.capacity + freed] = brick;
+            return result.old_value;
+        }
+    }
+    return 0u;
+}
+#endif
+
+fn load_voxel(chunk_index: i32, pos: vec3<i32>) -> u32 {
+#ifdef CHUNK_ATLAS
+    return textureLoad(voxel_worlds, atlas_position(chunk_index, pos, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid)).r;
+#else ifdef CHUNK_BRICKS
+    if (!in_chunk(pos, voxel_uniforms.chunk_size)) {
+        return 0u;
+    }
+    let brick = atomicLoad(&brick_index[brick_cell(chunk_index, pos, voxel_uniforms.chunk_size)]);
+    if (brick == 0u) {
+        return 0u;
+    }
+    return textureLoad(voxel_worlds, brick_position(brick - 1u, pos)).r;
+#else
+    return textureLoad(voxel_worlds[chunk_index], pos.zyx).r;
+#endif
+}
+
+fn store_voxel(chunk_index: i32, pos: vec3<i32>, value: u32) {
+#ifdef CHUNK_ATLAS
+    textureStore(voxel_worlds, atlas_position(chunk_index, pos, voxel_uniforms.chunk_size, voxel_uniforms.chunk_grid), vec4(value));
+#else ifdef CHUNK_BRICKS
+    if (!in_chunk(pos, voxel_uniforms.chunk_size)) {
+        return;
+    }
+    let cell = brick_cell(chunk_index, pos, voxel_uniforms.chunk_size);
+    var brick = atomicLoad(&brick_index[cell]);
+    if (brick == 0u) {
+        if (value == 0u) {
+            return;
+        }
+        brick = allocate_brick(cell);
+        if (brick == 0u) {
+            return;
         }
     }
-    return -1; // Out of bounds
+    textureStore(voxel_worlds, brick_position(brick - 1u, pos), vec4(value));
+#else
+    textureStore(voxel_worlds[chunk_index], pos.zyx, vec4(value));
+#endif
 }
 
 fn get_texture_value(pos: vec3<i32>, chunk_index: i32) -> vec2<u32> {
-    let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
-    let texture_value = textureLoad(voxel_worlds[chunk_index], chunk_pos.zyx).r;
+    let chunk_pos = chunk_position(pos, voxel_uniforms.chunk_size);
+    let texture_value = load_voxel(chunk_index, chunk_pos);
     return vec2(
-        texture_value & 0xFFu,
-        texture_value >> 8u,
+        voxel_material(texture_value),
+        voxel_flags(texture_value),
     );
 }
 
 fn write_pos(pos: vec3<i32>, material: u32, flags: u32, chunk_index: i32) {
-    let chunk_pos = pos % vec3(i32(voxel_uniforms.chunk_size));
+    let chunk_pos = chunk_position(pos, voxel_uniforms.chunk_size);
     let voxel_type = get_texture_value(pos, chunk_index);
 
     if (voxel_type.x == 0u) {
-        textureStore(voxel_worlds[chunk_index], chunk_pos.zyx, vec4<u32>(material | (flags << 8u)));
+        store_voxel(chunk_index, chunk_pos, encode_voxel(material, flags));
     }
 }
 
@@ -79,7 +167,8 @@ fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     let clip_space_xy = vec2(1.0, -1.0) * (2.0 * in.pos.xy / f32(voxel_uniforms.texture_size) - 1.0);
     let clip_space = vec4(clip_space_xy, in.pos.z, 1.0);
     let world = position_clip_to_world(clip_space);
-    let texture_pos = VOXELS_PER_METER * world + vec3(f32(voxel_uniforms.texture_size) / 2.0);
+    let origin = vec3<f32>(voxel_uniforms.origin_chunk * i32(voxel_uniforms.chunk_size)) / voxel_uniforms.voxels_per_meter;
+    let texture_pos = voxel_uniforms.voxels_per_meter * (world - origin) + vec3(f32(voxel_uniforms.texture_size) / 2.0);
     let texture_value = textureSample(material_texture, material_sampler, vec2(in.uv.xy));
 
     var material = 0u;
@@ -89,12 +178,13 @@ fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
         material = voxelization_uniforms.material;
     }
     
-    let chunk_index = get_chunk_index(world);
+    let chunk_index = get_chunk_index(texture_pos);
     if (chunk_index != -1) {
         write_pos(vec3<i32>(texture_pos), material, voxelization_uniforms.flags, chunk_index);
     }
     
-    let color = voxel_uniforms.materials[material].rgb;
+    // the wide materials aren't bound here
+    let color = voxel_uniforms.pallete[min(material, 255u)].rgb;
     
     return vec4<f32>(color, 1.0);
 }
@@ -0,0 +1,366 @@
+use super::{
+    compute::bricks,
+    edit::VoxelMirror,
+    save::{copy_chunk, create_read_back_buffer, read_mapped_texture, MapResult},
+    voxel_world::{active_chunk_position, queue_bind_group, VoxelData, VoxelUniforms},
+};
+use crate::{
+    load::{GridHierarchy, QueuedSave},
+    RegionCache, VoxelFormat, WorldGenerator,
+};
+use bevy::{
+    ecs::query::QuerySingleError,
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::{Buffer, MapMode, PipelineCache},
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    tasks::{block_on, poll_once, AsyncComputeTaskPool, IoTaskPool, Task},
+    transform::TransformSystem,
+    utils::HashMap,
+};
+use std::sync::Arc;
+
+pub struct StreamingPlugin;
+
+impl Plugin for StreamingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChunkSource>()
+            .init_resource::<ChunkEvictions>()
+            .add_plugins(ExtractResourcePlugin::<ChunkEvictions>::default())
+            .add_plugins(ExtractResourcePlugin::<RegionCache>::default())
+            .add_systems(
+                PostUpdate,
+                stream_chunks.after(TransformSystem::TransformPropagate),
+            );
+
+        app.sub_app_mut(RenderApp)
+            .init_resource::<ChunkReadBacks>()
+            .add_systems(
+                Render,
+                persist_evicted_chunks
+                    .in_set(RenderSet::Prepare)
+                    .after(queue_bind_group),
+            );
+    }
+}
+
+/// The active chunks follow the entity with this component, usually the
+/// camera. When it crosses into another chunk the chunks that fall out of the
+/// grid of active chunks are dropped and the newly exposed ones are loaded from
+/// the world, or built by the generator for generated worlds. There should
+/// only be one, with more the chunks stop moving and a warning is logged.
+/// Without it worlds have to fit in the active chunks.
+///
+/// New chunks are loaded on the task pool and stay empty until they arrive,
+/// edits made to them before that are lost. Changes made to a chunk are lost
+/// once it leaves the active chunks, unless they are kept in [`RegionCache`].
+#[derive(Component, Default, Debug, Clone, Copy)]
+pub struct ChunkAnchor;
+
+/// Where chunks that become active come from, set when a world is loaded.
+#[derive(Resource, Default)]
+pub(crate) struct ChunkSource {
+    pub world: Option<Arc<GridHierarchy>>,
+    pub generator: Option<SeededGenerator>,
+    /// Chunks still loading by texture index, cleared with a new world.
+    pub pending: HashMap<usize, Task<Option<Vec<u8>>>>,
+}
+
+/// A generator and the seed of the world it builds.
+pub(crate) type SeededGenerator = (u64, Arc<dyn WorldGenerator>);
+
+impl ChunkSource {
+    /// Loads the chunk from its region file if it was saved there, otherwise
+    /// from the world or the generator. Replaces the chunk still loading into
+    /// the same texture.
+    fn spawn_load(
+        &mut self,
+        texture_index: usize,
+        chunk: IVec3,
+        chunk_size: u32,
+        region_cache: Option<RegionCache>,
+    ) {
+        let world = self.world.clone();
+        let generator = self.generator.clone();
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            if let Some(region_cache) = region_cache {
+                match region_cache.load_chunk(chunk, chunk_size).await {
+                    Ok(Some(data)) => return Some(data),
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to load chunk {}: {}", chunk, e),
+                }
+            }
+            if let Some(data) = world.as_ref().and_then(|gh| gh.chunks.get(&chunk)) {
+                return Some(data.clone());
+            }
+
+            let (seed, generator) = generator?;
+            generator.generate_chunk(seed, chunk, chunk_size)
+        });
+        self.pending.insert(texture_index, task);
+    }
+}
+
+/// Chunks that left the active chunks this frame by the texture they were in,
+/// read back before the texture is reused.
+#[derive(Resource, ExtractResource, Clone, Default)]
+pub(crate) struct ChunkEvictions(Arc<Vec<(u32, Arc<QueuedSave>)>>);
+
+/// Moves the active chunks so they are centred on the chunk the anchor is in.
+/// Chunks that stay active keep their texture, so only the new ones are
+/// uploaded once they finished loading.
+pub(super) fn stream_chunks(
+    anchors: Query<&GlobalTransform, With<ChunkAnchor>>,
+    mut chunk_source: ResMut<ChunkSource>,
+    region_cache: Option<Res<RegionCache>>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+    mut voxel_mirror: ResMut<VoxelMirror>,
+    mut evictions: ResMut<ChunkEvictions>,
+) {
+    evictions.0 = default();
+
+    match anchors.get_single() {
+        Ok(anchor) => move_active_chunks(
+            anchor,
+            &mut chunk_source,
+            region_cache.as_deref(),
+            &mut voxel_uniforms,
+            &mut voxel_mirror,
+            &mut evictions,
+        ),
+        Err(QuerySingleError::MultipleEntities(_)) => {
+            warn_once!("There is more than one ChunkAnchor, the active chunks stay where they are");
+        }
+        Err(QuerySingleError::NoEntities(_)) => {}
+    }
+
+    // after moving, so a chunk is uploaded before it can be evicted again
+    chunk_source
+        .pending
+        .retain(|&texture_index, task| match block_on(poll_once(task)) {
+            Some(data) => {
+                voxel_mirror.replace_chunk(texture_index, data);
+                false
+            }
+            None => true,
+        });
+}
+
+fn move_active_chunks(
+    anchor: &GlobalTransform,
+    chunk_source: &mut ChunkSource,
+    region_cache: Option<&RegionCache>,
+    voxel_uniforms: &mut VoxelUniforms,
+    voxel_mirror: &mut VoxelMirror,
+    evictions: &mut ChunkEvictions,
+) {
+    let chunk_size = voxel_uniforms.chunk_size;
+    let pos = voxel_at(
+        anchor.translation(),
+        voxel_uniforms.texture_size,
+        voxel_uniforms.voxels_per_meter,
+    );
+    let origin = pos.div_euclid(IVec3::splat(chunk_size as i32));
+    if origin == voxel_uniforms.origin_chunk {
+        return;
+    }
+
+    // a chunk that leaves frees the texture of the chunk that replaces it
+    let grid = voxel_uniforms.chunk_grid;
+    let mut evicted = Vec::new();
+    for i in 0..voxel_uniforms.chunks().len() {
+        let position = active_chunk_position(i, origin, grid);
+        let old_position = voxel_uniforms.active_chunks[i].position;
+        if old_position == position {
+            continue;
+        }
+
+        // a chunk that never arrived isn't in the texture to be saved
+        let arrived = !chunk_source.pending.contains_key(&i);
+        if let Some(region_cache) = region_cache.filter(|_| arrived) {
+            evicted.push((i as u32, Arc::new(region_cache.queue_save(old_position))));
+        }
+        chunk_source.spawn_load(i, position, chunk_size, region_cache.cloned());
+        voxel_mirror.replace_chunk(i, None);
+        voxel_uniforms.active_chunks[i].position = position;
+    }
+
+    debug!("Streamed active chunks to {}", origin);
+    voxel_uniforms.origin_chunk = origin;
+    evictions.0 = Arc::new(evicted);
+}
+
+/// The voxel a position is in. Floored, truncating would move positions on the
+/// negative side a voxel towards zero, so chunks would stream a voxel late.
+fn voxel_at(world_pos: Vec3, texture_size: u32, voxels_per_meter: f32) -> IVec3 {
+    (world_pos * voxels_per_meter).floor().as_ivec3() + IVec3::splat(texture_size as i32 / 2)
+}
+
+/// Evicted chunks copied out of their textures, waiting for the copies to be
+/// mapped.
+#[derive(Resource, Default)]
+pub(super) struct ChunkReadBacks(Vec<ChunkReadBack>);
+
+struct ChunkReadBack {
+    save: Arc<QueuedSave>,
+    chunk_size: u32,
+    voxel_format: VoxelFormat,
+    buffer: Buffer,
+    mapped: MapResult,
+}
+
+/// Writes the chunks that left the active chunks to their region files. The
+/// textures are reused this frame so every evicted chunk is copied to its own
+/// buffer right away, the copies are saved on the io task pool once they are
+/// mapped. Loading the chunk waits until it is mapped and takes it from memory
+/// until it is saved, so a chunk that comes straight back is never loaded from
+/// an older file.
+pub(super) fn persist_evicted_chunks(
+    evictions: Res<ChunkEvictions>,
+    mut read_backs: ResMut<ChunkReadBacks>,
+    voxel_data: Res<VoxelData>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    (pipeline_cache, brick_pipelines): (Res<PipelineCache>, Option<Res<bricks::Pipelines>>),
+) {
+    if !read_backs.0.is_empty() {
+        render_device.poll(wgpu::Maintain::Poll);
+    }
+    read_backs.0.retain(|read_back| {
+        let Some(result) = read_back.mapped.get() else {
+            return true;
+        };
+        let save = read_back.save.clone();
+        let data = result.clone().map_err(|e| e.to_string()).map(|()| {
+            let texture_data = read_mapped_texture(
+                &read_back.buffer,
+                read_back.chunk_size,
+                read_back.voxel_format,
+            );
+            // the cache holds narrow chunks, keep what fits rather than the
+            // whole chunk going missing
+            let (data, lost) = read_back.voxel_format.narrow_lossy(texture_data);
+            if lost > 0 {
+                error!(
+                    "Chunk {} has {} voxels with materials above 255, they are lost \
+                    when it is evicted",
+                    save.chunk, lost
+                );
+            }
+            data
+        });
+        read_back.buffer.destroy();
+
+        let chunk_size = read_back.chunk_size;
+        let data = data.map(|data| save.set_data(data));
+        IoTaskPool::get()
+            .spawn(async move {
+                if let Err(e) = data.and_then(|data| save.save(chunk_size, &data)) {
+                    error!("Failed to save chunk {}: {}", save.chunk, e);
+                }
+            })
+            .detach();
+        false
+    });
+
+    let size = voxel_data.chunk_size;
+    let unpack = brick_pipelines.and_then(|pipelines| pipelines.unpack(&pipeline_cache));
+    for (texture_index, save) in evictions.0.iter() {
+        let buffer = create_read_back_buffer(&render_device, size, voxel_data.voxel_format);
+        let copied = copy_chunk(
+            &voxel_data,
+            *texture_index as usize,
+            unpack,
+            &buffer,
+            &render_device,
+            &render_queue,
+        );
+        if let Err(e) = copied {
+            error!("Failed to save chunk {}: {}", save.chunk, e);
+            buffer.destroy();
+            continue;
+        }
+
+        let mapped = MapResult::default();
+        let result = mapped.clone();
+        buffer
+            .slice(..)
+            .map_async(MapMode::Read, move |r| _ = result.set(r));
+        read_backs.0.push(ChunkReadBack {
+            save: save.clone(),
+            chunk_size: size,
+            voxel_format: voxel_data.voxel_format,
+            buffer,
+            mapped,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::tasks::TaskPool;
+    use std::path::PathBuf;
+
+    fn chunk(size: u32, material: u8) -> Vec<u8> {
+        let mut data = vec![0; (size * size * size * 2) as usize];
+        data[0] = material;
+        data
+    }
+
+    /// Removed when dropped, so a failing test doesn't leave it behind.
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn world(material: u8) -> Arc<GridHierarchy> {
+        let mut gh = GridHierarchy::empty(8);
+        gh.chunks.insert(IVec3::X, chunk(8, material));
+        Arc::new(gh)
+    }
+
+    #[test]
+    fn anchors_below_zero_are_floored() {
+        assert_eq!(
+            voxel_at(Vec3::new(0.5, 0.0, -0.5), 8, 1.0),
+            IVec3::new(4, 4, 3)
+        );
+        // 4.5 meters below the center of chunk zero is in the chunk below it
+        let pos = voxel_at(Vec3::new(0.0, -4.5, 0.0), 8, 1.0);
+        assert_eq!(pos.div_euclid(IVec3::splat(8)), IVec3::new(0, -1, 0));
+    }
+
+    #[test]
+    fn chunks_of_the_last_world_are_not_loaded() {
+        AsyncComputeTaskPool::get_or_init(TaskPool::new);
+        let directory = TempDir(std::env::temp_dir().join(format!(
+            "bevy_voxel_engine_streamed_worlds_{}",
+            std::process::id()
+        )));
+        let mut region_cache = RegionCache::new(&directory.0).unwrap();
+
+        // the first world's chunk is edited and evicted
+        let mut chunk_source = ChunkSource {
+            world: Some(world(1)),
+            ..default()
+        };
+        region_cache.save_chunk(IVec3::X, 8, &chunk(8, 2)).unwrap();
+        chunk_source.spawn_load(0, IVec3::X, 8, Some(region_cache.clone()));
+        let loaded = block_on(chunk_source.pending.remove(&0).unwrap());
+        assert_eq!(loaded, Some(chunk(8, 2)));
+
+        // the second world streams in its own chunk
+        chunk_source.world = Some(world(3));
+        region_cache.load_world();
+        chunk_source.spawn_load(0, IVec3::X, 8, Some(region_cache.clone()));
+        let loaded = block_on(chunk_source.pending.remove(&0).unwrap());
+        assert_eq!(loaded, Some(chunk(8, 3)));
+    }
+}
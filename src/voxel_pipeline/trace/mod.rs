@@ -1,4 +1,5 @@
 use super::voxel_world::VoxelData;
+use crate::VoxelEngineSettings;
 use bevy::{
     asset::{embedded_asset, load_internal_asset},
     core_pipeline::fullscreen_vertex_shader::fullscreen_shader_vertex_state,
@@ -71,6 +72,9 @@ pub struct TraceSettings {
     pub show_ray_steps: bool,
     pub samples: u32,
     pub shadows: bool,
+    /// Diffuse bounces traced for indirect light, at most 2. With none the
+    /// indirect light is a constant ambient darkened by ambient occlusion.
+    pub indirect_bounces: u32,
 }
 
 impl Default for TraceSettings {
@@ -79,6 +83,7 @@ impl Default for TraceSettings {
             show_ray_steps: false,
             samples: 1,
             shadows: true,
+            indirect_bounces: 0,
         }
     }
 }
@@ -93,6 +98,7 @@ pub struct TraceUniforms {
     pub show_ray_steps: u32,
     pub samples: u32,
     pub shadows: u32,
+    pub indirect_bounces: u32,
 }
 
 #[derive(Component, Deref, DerefMut)]
@@ -134,6 +140,7 @@ fn prepare_uniforms(
             show_ray_steps: settings.show_ray_steps as u32,
             samples: settings.samples,
             shadows: settings.shadows as u32,
+            indirect_bounces: settings.indirect_bounces.min(2),
         };
 
         let mut uniform_buffer = UniformBuffer::from(uniforms);
@@ -152,6 +159,7 @@ impl FromWorld for TracePipelineData {
         let asset_server = render_world.resource::<AssetServer>();
 
         let voxel_bind_group_layout = voxel_data.bind_group_layout.clone();
+        let shader_defs = render_world.resource::<VoxelEngineSettings>().shader_defs();
 
         let trace_shader_handle =
             asset_server.load("embedded://bevy_voxel_engine/voxel_pipeline/trace/trace.wgsl");
@@ -191,6 +199,36 @@ impl FromWorld for TracePipelineData {
                         },
                         count: None,
                     },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::WriteOnly,
+                            format: TextureFormat::Rgba16Float,
+                            view_dimension: TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: false },
+                            view_dimension: TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Texture {
+                            sample_type: TextureSampleType::Float { filterable: false },
+                            view_dimension: TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
                 ],
             );
 
@@ -203,7 +241,7 @@ impl FromWorld for TracePipelineData {
             vertex: fullscreen_shader_vertex_state(),
             fragment: Some(FragmentState {
                 shader: trace_shader_handle,
-                shader_defs: Vec::new(),
+                shader_defs,
                 entry_point: "fragment".into(),
                 targets: vec![Some(ColorTargetState {
                     format: ViewTarget::TEXTURE_FORMAT_HDR,
@@ -1,8 +1,11 @@
 use super::{TracePipelineData, ViewTraceUniformBuffer};
 use crate::voxel_pipeline::{
-    attachments::RenderAttachments, voxel_world::VoxelData, RenderGraphSettings,
+    attachments::RenderAttachments,
+    voxel_world::{view_voxel_data, TargetVoxelWorld},
+    RenderGraphSettings,
 };
 use bevy::{
+    core::FrameCount,
     prelude::*,
     render::{
         render_asset::RenderAssets,
@@ -21,6 +24,7 @@ impl ViewNode for TraceNode {
         &'static ViewTarget,
         &'static ViewTraceUniformBuffer,
         &'static RenderAttachments,
+        Option<&'static TargetVoxelWorld>,
     );
 
     fn run(
@@ -31,7 +35,6 @@ impl ViewNode for TraceNode {
         world: &World,
     ) -> Result<(), render_graph::NodeRunError> {
         let pipeline_cache = world.resource::<PipelineCache>();
-        let voxel_data = world.resource::<VoxelData>();
         let trace_pipeline_data = world.resource::<TracePipelineData>();
         let render_graph_settings = world.resource::<RenderGraphSettings>();
 
@@ -39,7 +42,10 @@ impl ViewNode for TraceNode {
             return Ok(());
         }
 
-        let (target, trace_uniform_buffer, render_attachments) = view_query;
+        let (target, trace_uniform_buffer, render_attachments, target_world) = view_query;
+        let Some(voxel_data) = view_voxel_data(world, target_world) else {
+            return Ok(());
+        };
 
         let trace_pipeline =
             match pipeline_cache.get_render_pipeline(trace_pipeline_data.trace_pipeline_id) {
@@ -52,14 +58,16 @@ impl ViewNode for TraceNode {
 
         let gpu_images = world.get_resource::<RenderAssets<GpuImage>>().unwrap();
 
-        let normal = &gpu_images
-            .get(&render_attachments.normal)
-            .expect("normal image not found")
-            .texture_view;
-        let position = &gpu_images
-            .get(&render_attachments.position)
-            .expect("position image not found")
-            .texture_view;
+        let texture_view = |handle: &Handle<Image>| {
+            &gpu_images
+                .get(handle)
+                .expect("render attachment not found")
+                .texture_view
+        };
+
+        // the attachments written last frame are read this one
+        let current = world.resource::<FrameCount>().0 as usize % 2;
+        let last = 1 - current;
 
         let trace_bind_group = render_context.render_device().create_bind_group(
             None,
@@ -71,11 +79,33 @@ impl ViewNode for TraceNode {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: BindingResource::TextureView(&normal),
+                    resource: BindingResource::TextureView(texture_view(
+                        &render_attachments.normal,
+                    )),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: BindingResource::TextureView(&position),
+                    resource: BindingResource::TextureView(texture_view(
+                        &render_attachments.position[current],
+                    )),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(texture_view(
+                        &render_attachments.history[current],
+                    )),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(texture_view(
+                        &render_attachments.position[last],
+                    )),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: BindingResource::TextureView(texture_view(
+                        &render_attachments.history[last],
+                    )),
                 },
             ],
         );
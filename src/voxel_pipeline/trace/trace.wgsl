@@ -1,19 +1,24 @@
 #import bevy_core_pipeline::fullscreen_vertex_shader::FullscreenVertexOutput
 #import bevy_voxel_engine::common::{
-    VOXELS_PER_METER,
     PI,
+    Material,
     VoxelUniforms,
     TraceUniforms,
     Ray,
-    skybox
+    voxel_material,
+    skybox,
+    cosine_hemisphere,
 }
 #import bevy_voxel_engine::raytracing::{
     shoot_ray,
+    world_origin,
 }
 #import bevy_voxel_engine::bindings::{
-    voxel_worlds,
     voxel_uniforms,
-    gh
+    gh,
+    get_chunk_index,
+    load_voxel,
+    material_properties,
 }
 
 @group(0) @binding(3)
@@ -25,15 +30,30 @@ var<uniform> trace_uniforms: TraceUniforms;
 var normal: texture_storage_2d<rgba16float, read_write>;
 @group(1) @binding(2)
 var position: texture_storage_2d<rgba32float, read_write>;
+@group(1) @binding(3)
+var history: texture_storage_2d<rgba16float, write>;
+@group(1) @binding(4)
+var last_position: texture_2d<f32>;
+@group(1) @binding(5)
+var last_history: texture_2d<f32>;
+
+// frames of indirect light averaged at most, fewer react faster to changes
+const MAX_HISTORY: f32 = 32.0;
 
 struct DirectLightningInfo {
     color: vec3<f32>,
     shadow: f32,
 };
 
-fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32>, pos: vec3<f32>, normal: vec3<f32>, seed: vec3<u32>, shadow_samples: u32) -> DirectLightningInfo {
-    // Diffuse
-    let diffuse = max(dot(normal, -normalize(sun_dir)), 0.0);
+fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32>, surface: Material, view_dir: vec3<f32>, pos: vec3<f32>, normal: vec3<f32>, seed: vec3<u32>, shadow_samples: u32) -> DirectLightningInfo {
+    // Diffuse, metals only reflect
+    let diffuse = max(dot(normal, -normalize(sun_dir)), 0.0) * (1.0 - surface.metalness);
+
+    // Specular, rough surfaces get a wider and dimmer highlight
+    let half_dir = normalize(-normalize(sun_dir) - view_dir);
+    let shininess = mix(256.0, 4.0, surface.roughness);
+    let specular_strength = max(surface.specular, surface.metalness) * (1.0 - surface.roughness);
+    let specular = pow(max(dot(normal, half_dir), 0.0), shininess) * specular_strength;
 
     // Shadow
     var shadow = 1.0;
@@ -45,38 +65,70 @@ fn calculate_direct(sun_dir: vec3<f32>, sky_color: vec3<f32>, material: vec4<f32
     }
 
     // Emissive
-    var emissive = vec3(0.0);
-    if material.a != 0.0 {
-        emissive = vec3(material.rgb);
-    }
+    let emissive = material.rgb * surface.emission;
 
-    let color = diffuse * shadow + emissive;
+    let color = (diffuse + specular) * shadow + emissive;
 
     return DirectLightningInfo(color, shadow);
 }
-fn get_chunk_index(world_pos: vec3<f32>) -> i32 {
-    let chunk_pos = floor(world_pos / f32(voxel_uniforms.chunk_size));
-    for (var i = 0; i < 27; i++) {
-        if (all(chunk_pos == vec3<f32>(voxel_uniforms.active_chunks[i].position))) {
-            return i32(voxel_uniforms.active_chunks[i].texture_index);
+
+// light coming from a random direction of the hemisphere around the normal,
+// bouncing off diffuse surfaces that are lit by the sun
+fn trace_indirect(pos: vec3<f32>, normal: vec3<f32>, time_of_day: f32, seed: vec3<u32>) -> vec3<f32> {
+    var ray_pos = pos;
+    var ray_normal = normal;
+    var throughput = vec3(1.0);
+    var light = vec3(0.0);
+    for (var i = 0u; i < trace_uniforms.indirect_bounces; i += 1u) {
+        let dir = cosine_hemisphere(ray_normal, seed + i * 7u);
+        let hit = shoot_ray(Ray(ray_pos, dir), 0.0, 0u);
+        let skybox_info = skybox(dir, time_of_day);
+        if !hit.hit {
+            light += throughput * skybox_info.sky_color;
+            break;
+        }
+
+        let surface = material_properties(voxel_material(hit.data));
+        let direct = calculate_direct(skybox_info.sun_dir, skybox_info.sky_color, hit.material, surface, dir, hit.pos, hit.normal, seed + i * 7u + 3u, 1u);
+        throughput *= hit.material.rgb;
+        light += throughput * direct.color;
+
+        ray_pos = hit.pos;
+        ray_normal = hit.normal;
+    }
+    return light;
+}
+
+// blends the indirect light with what the same surface got last frame
+fn accumulate_indirect(pixel: vec2<i32>, pos: vec3<f32>, light: vec3<f32>) -> vec4<f32> {
+    var last = vec4(0.0);
+
+    let clip = trace_uniforms.last_camera * vec4(pos, 1.0);
+    let uv = (clip.xy / clip.w * vec2(1.0, -1.0)) * 0.5 + 0.5;
+    let last_pixel = vec2<i32>(floor(uv * vec2<f32>(textureDimensions(last_history))));
+    if clip.w > 0.0 && all(last_pixel >= vec2(0)) && all(last_pixel < vec2<i32>(textureDimensions(last_history))) {
+        let last_pos = textureLoad(last_position, last_pixel, 0).xyz;
+        if distance(last_pos, pos) < 1.0 / voxel_uniforms.voxels_per_meter {
+            last = textureLoad(last_history, last_pixel, 0);
         }
     }
-    return -1; // Out of bounds
+
+    let samples = min(last.a + 1.0, MAX_HISTORY);
+    return vec4(mix(last.rgb, light, 1.0 / samples), samples);
 }
+
 fn get_voxel(pos: vec3<f32>) -> f32 {
     let chunk_index = get_chunk_index(pos);
     if (chunk_index == -1) {
         return 0.0;
     }
 
-    let chunk_pos = pos % vec3(f32(voxel_uniforms.chunk_size));
-    if any(chunk_pos < vec3(0.0)) || any(chunk_pos >= vec3(f32(voxel_uniforms.chunk_size))) {
-        return 0.0;
-    }
-
-    let voxel = textureLoad(voxel_worlds[chunk_index], vec3<i32>(chunk_pos.zyx));
+    // % keeps the sign, chunks below the origin chunk have negative positions
+    let chunk_size = f32(voxel_uniforms.chunk_size);
+    let chunk_pos = pos - floor(pos / chunk_size) * chunk_size;
+    let voxel = load_voxel(chunk_index, min(vec3<i32>(chunk_pos), vec3(i32(voxel_uniforms.chunk_size) - 1)));
     
-    return min(f32(voxel.r & 0xFFu), 1.0);
+    return min(f32(voxel_material(voxel)), 1.0);
 }
 
 // https://www.shadertoy.com/view/ldl3DS
@@ -151,18 +203,25 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
     let skybox_info = skybox(ray.dir, w);
 
     var samples = 0.0;
+    var indirect_history = vec4(0.0);
     if hit.hit {
         // Direct lighting
-        let direct_lighting = calculate_direct(skybox_info.sun_dir, skybox_info.sky_color, hit.material, hit.pos, hit.normal, seed + 1u, trace_uniforms.samples);
+        let surface = material_properties(voxel_material(hit.data));
+        let direct_lighting = calculate_direct(skybox_info.sun_dir, skybox_info.sky_color, hit.material, surface, ray.dir, hit.pos, hit.normal, seed + 1u, trace_uniforms.samples);
 
         // Indirect lighting
-        let texture_coords = hit.pos * VOXELS_PER_METER;
+        let texture_coords = (hit.pos - world_origin()) * voxel_uniforms.voxels_per_meter + f32(voxel_uniforms.texture_size) / 2.0;
         let ao = voxel_ao(texture_coords, hit.normal.zxy, hit.normal.yzx);
         let uv = glmod(vec2(dot(hit.normal * texture_coords.yzx, vec3(1.0)), dot(hit.normal * texture_coords.zxy, vec3(1.0))), vec2(1.0));
 
         let interpolated_ao_pweig = mix(mix(ao.z, ao.w, uv.x), mix(ao.y, ao.x, uv.x), uv.y);
         let voxel_ao = pow(interpolated_ao_pweig, 1.0 / 3.0);
-        let indirect_lighting_color = vec3(0.3 * voxel_ao);
+        var indirect_lighting_color = vec3(0.3 * voxel_ao);
+        if trace_uniforms.indirect_bounces != 0u {
+            let light = trace_indirect(hit.pos, hit.normal, w, seed + 2u);
+            indirect_history = accumulate_indirect(vec2<i32>(in.position.xy), hit.reprojection_pos, light);
+            indirect_lighting_color = indirect_history.rgb;
+        }
 
         let sun_progress = calculate_sun_progress(skybox_info.sun_dir);
 
@@ -179,6 +238,7 @@ fn fragment(in: FullscreenVertexOutput) -> @location(0) vec4<f32> {
 
     textureStore(normal, vec2<i32>(in.position.xy), vec4(hit.normal, 0.0));
     textureStore(position, vec2<i32>(in.position.xy), vec4(hit.reprojection_pos, 0.0));
+    textureStore(history, vec2<i32>(in.position.xy), indirect_history);
 
     return vec4<f32>(output_color, 1.0);
 }
\ No newline at end of file
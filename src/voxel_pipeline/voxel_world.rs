@@ -1,62 +1,81 @@
+use super::{
+    compute::bricks,
+    edit::VoxelMirror,
+    objects::VoxelObjectBuffers,
+    save::MapResult,
+    streaming::{ChunkAnchor, ChunkSource, SeededGenerator},
+};
 use crate::{
-    load::{GridHierarchy, Pallete},
-    LoadVoxelWorld,
+    load::{
+        GridHierarchy, Material, Palette, PaletteLoader, Pallete, VoxWorldAsset, VoxWorldLoader,
+        VoxelAnimation, VoxelAnimationLoader, VoxelPrefab, VoxelPrefabLoader, MAX_CHUNK_SIZE,
+    },
+    material, ActivePalette, ChunkStorage, LoadVoxelWorld, MaterialRegistry, RegionCache,
+    VoxelEngineSettings, VoxelFormat, WideMaterials, WorldLoadFailed, WorldLoaded,
 };
 use bevy::{
+    asset::LoadState,
     prelude::*,
     render::{
+        extract_component::{ExtractComponent, ExtractComponentPlugin},
         extract_resource::{ExtractResource, ExtractResourcePlugin},
         render_resource::*,
-        renderer::{RenderDevice, RenderQueue},
+        renderer::{RenderAdapter, RenderDevice, RenderQueue},
         Render, RenderApp, RenderSet,
     },
+    tasks::{block_on, poll_once, AsyncComputeTaskPool, Task},
+    utils::HashMap,
 };
-use std::{num::NonZeroU32, sync::Arc};
+use std::{num::NonZeroU32, path::Path, sync::Arc};
 
 pub struct VoxelWorldPlugin;
 
+/// Size of the active chunks array in the uniforms, the shaders only declare
+/// the chunks of the configured grid.
+pub const MAX_ACTIVE_CHUNKS: usize = 125;
+
+/// Voxels along each side of a brick of [`ChunkStorage::Bricks`].
+pub const BRICK_SIZE: u32 = 8;
+
+/// Bricks along x and y of the brick pool, it grows along z.
+const BRICK_POOL_WIDTH: u32 = 64;
+
+/// The pool can be at most 256 bricks deep, 2048 voxels.
+pub const MAX_BRICK_CAPACITY: u32 = BRICK_POOL_WIDTH * BRICK_POOL_WIDTH * 256;
+
 impl Plugin for VoxelWorldPlugin {
-    fn build(&self, _app: &mut App) {}
+    fn build(&self, app: &mut App) {
+        // untyped loads of the shared extensions go to the last loader, the
+        // world loader is added once the chunk size is known
+        app.init_asset::<VoxelPrefab>()
+            .init_asset_loader::<VoxelPrefabLoader>()
+            .init_asset::<VoxelAnimation>()
+            .init_asset_loader::<VoxelAnimationLoader>()
+            .init_asset::<VoxWorldAsset>()
+            .init_asset::<Palette>()
+            .init_asset_loader::<PaletteLoader>()
+            .init_resource::<ActivePalette>()
+            .add_event::<WorldLoaded>()
+            .add_event::<WorldLoadFailed>()
+            .init_resource::<MaterialRegistry>()
+            .init_resource::<WideMaterials>()
+            .add_plugins(ExtractResourcePlugin::<MaterialRegistry>::default())
+            .add_plugins(ExtractResourcePlugin::<WideMaterials>::default());
+    }
 
     fn finish(&self, app: &mut App) {
         let render_device = app.sub_app(RenderApp).world().resource::<RenderDevice>();
+        let render_adapter = app.sub_app(RenderApp).world().resource::<RenderAdapter>();
 
         let render_queue = app.sub_app(RenderApp).world().resource::<RenderQueue>();
+        let settings = app.world().resource::<VoxelEngineSettings>();
+        let settings = supported_settings(settings, render_device, render_adapter);
+        info!("Storing voxel chunks as {:?}", settings.chunk_storage);
+        let chunk_count = settings.active_chunk_count();
 
-        let gh = GridHierarchy::empty(256);
-        let buffer_size = gh.get_buffer_size();
-        let texture_size = gh.texture_size;
-        let gh_offsets = gh.get_offsets();
-
-        let mut levels = [UVec4::ZERO; 8];
-        let mut offsets = [UVec4::ZERO; 8];
-        for i in 0..8 {
-            levels[i] = UVec4::new(gh.levels[i], 0, 0, 0);
-            offsets[i] = UVec4::new(gh_offsets[i], 0, 0, 0);
-        }
-
-        // Uniforms
-        let voxel_uniforms = VoxelUniforms {
-            pallete: gh.pallete.into(),
-            portals: [ExtractedPortal::default(); 32],
-            levels,
-            offsets,
-            texture_size,
-            world_size: texture_size * 3,
-            chunk_size: texture_size, // Set an appropriate chunk size
-            active_chunks: [ChunkInfo::default(); 27], // 3x3x3 grid of chunks
-        };
-        let mut uniform_buffer = UniformBuffer::from(voxel_uniforms.clone());
-        uniform_buffer.write_buffer(&render_device, &render_queue);
-
-        // Storage
-        let grid_hierarchy = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: &vec![0; buffer_size],
-            label: None,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
+        let gh = GridHierarchy::empty(settings.chunk_size);
+        let voxel_uniforms = VoxelUniforms::new(&settings, &gh);
 
-        // Sampler
         let texture_sampler = render_device.create_sampler(&SamplerDescriptor {
             mag_filter: FilterMode::Linear,
             min_filter: FilterMode::Linear,
@@ -82,10 +101,13 @@ impl Plugin for VoxelWorldPlugin {
                     visibility: ShaderStages::VERTEX_FRAGMENT | ShaderStages::COMPUTE,
                     ty: BindingType::StorageTexture {
                         access: StorageTextureAccess::ReadWrite,
-                        format: TextureFormat::R16Uint,
+                        format: settings.voxel_format.texture_format(),
                         view_dimension: TextureViewDimension::D3,
                     },
-                    count: NonZeroU32::new(27), // 3x3x3 grid of chunks
+                    count: match settings.chunk_storage {
+                        ChunkStorage::TextureArray => NonZeroU32::new(chunk_count as u32),
+                        _ => None,
+                    },
                 },
                 BindGroupLayoutEntry {
                     binding: 2,
@@ -103,90 +125,618 @@ impl Plugin for VoxelWorldPlugin {
                     ty: BindingType::Sampler(SamplerBindingType::Filtering),
                     count: None,
                 },
-            ],
+            ]
+            .into_iter()
+            .chain(
+                // brick index and allocator
+                (4..6)
+                    .filter(|_| settings.chunk_storage == ChunkStorage::Bricks)
+                    .map(|binding| BindGroupLayoutEntry {
+                        binding,
+                        visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(4),
+                        },
+                        count: None,
+                    }),
+            )
+            .chain(
+                (settings.voxel_format == VoxelFormat::Wide).then_some(BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: ShaderStages::FRAGMENT | ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(WideMaterialEntry::SHADER_SIZE.into()),
+                    },
+                    count: None,
+                }),
+            )
+            .chain(VoxelObjectBuffers::layout_entries())
+            .collect::<Vec<_>>(),
         );
-        let chunk_textures: [TextureView; 27] = std::array::from_fn(|_| {
-            let texture = render_device.create_texture_with_data(
-                &render_queue,
-                &TextureDescriptor {
-                    label: None,
-                    size: Extent3d {
-                        width: voxel_uniforms.chunk_size,
-                        height: voxel_uniforms.chunk_size,
-                        depth_or_array_layers: voxel_uniforms.chunk_size,
+        let copy_bind_group_layout = (settings.chunk_storage == ChunkStorage::Bricks).then(|| {
+            render_device.create_bind_group_layout(
+                "brick copy bind group layout",
+                &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::ReadWrite,
+                            format: settings.voxel_format.texture_format(),
+                            view_dimension: TextureViewDimension::D3,
+                        },
+                        count: None,
                     },
-                    mip_level_count: 1,
-                    sample_count: 1,
-                    dimension: TextureDimension::D3,
-                    format: TextureFormat::R16Uint,
-                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_DST,
-                    view_formats: &[],
-                },
-                TextureDataOrder::default(),
-                &gh.texture_data.clone(),
-            );
-            texture.create_view(&TextureViewDescriptor::default())
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: BufferSize::new(BrickCopy::SHADER_SIZE.into()),
+                        },
+                        count: None,
+                    },
+                ],
+            )
+        });
+        let wide_materials = (settings.voxel_format == VoxelFormat::Wide).then(|| {
+            render_device.create_buffer(&BufferDescriptor {
+                label: Some("wide materials"),
+                size: WIDE_MATERIALS as u64 * WideMaterialEntry::SHADER_SIZE.get(),
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
         });
-        let chunk_texture_refs: [&wgpu::TextureView; 27] =
-            chunk_textures.each_ref().map(|tv| &**tv);
 
-        let bind_group = render_device.create_bind_group(
-            None,
-            &bind_group_layout,
-            &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: uniform_buffer.binding().unwrap(),
-                },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::TextureViewArray(&chunk_texture_refs),
-                },
-                BindGroupEntry {
-                    binding: 2,
-                    resource: grid_hierarchy.as_entire_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: BindingResource::Sampler(&texture_sampler),
-                },
-            ],
+        let voxel_data = VoxelData::new(
+            render_device,
+            render_queue,
+            &settings,
+            voxel_uniforms.clone(),
+            (bind_group_layout, copy_bind_group_layout),
+            texture_sampler,
+            wide_materials,
         );
 
-        app.insert_resource(LoadVoxelWorld::None)
+        app.register_asset_loader(VoxWorldLoader {
+            chunk_size: settings.chunk_size,
+        });
+
+        // the storage is resolved before any pipeline reads the settings
+        app.insert_resource(settings.clone())
+            .insert_resource(LoadVoxelWorld::None)
             .insert_resource(NewGridHierarchy::None)
+            .init_resource::<PendingWorldLoad>()
+            .insert_resource(VoxelMirror::new(&Arc::new(gh.clone()), settings.chunk_grid))
+            .insert_resource(WorldPallete(gh.pallete.clone()))
             .insert_resource(voxel_uniforms)
             .add_plugins(ExtractResourcePlugin::<NewGridHierarchy>::default())
             .add_plugins(ExtractResourcePlugin::<VoxelUniforms>::default())
-            .add_systems(Update, load_voxel_world);
+            .add_plugins(ExtractResourcePlugin::<WorldPallete>::default())
+            .add_plugins(ExtractComponentPlugin::<NewGridHierarchy>::default())
+            .add_plugins(ExtractComponentPlugin::<VoxelUniforms>::default())
+            .add_plugins(ExtractComponentPlugin::<TargetVoxelWorld>::default())
+            .add_systems(
+                Update,
+                (
+                    load_voxel_world,
+                    (init_extra_worlds, load_extra_worlds).chain(),
+                    apply_material_registry
+                        .after(load_voxel_world)
+                        .after(load_extra_worlds),
+                    apply_active_palette.after(load_voxel_world),
+                ),
+            );
 
         let render_app = app.sub_app_mut(RenderApp);
 
         render_app
-            .insert_resource(VoxelData {
-                uniform_buffer,
-                chunk_textures,
-                grid_hierarchy,
-                texture_sampler,
-                bind_group_layout,
-                bind_group,
-            })
+            .insert_resource(voxel_data)
+            .init_resource::<ExtraVoxelData>()
+            .insert_resource(settings)
             .add_systems(Render, prepare_uniforms.in_set(RenderSet::Prepare))
             .add_systems(Render, load_voxel_world_prepare.in_set(RenderSet::Prepare))
-            .add_systems(Render, queue_bind_group.in_set(RenderSet::Queue));
+            .add_systems(
+                Render,
+                queue_bind_group
+                    .in_set(RenderSet::Prepare)
+                    .after(load_voxel_world_prepare),
+            )
+            .add_systems(Render, prepare_extra_worlds.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_wide_materials.in_set(RenderSet::Prepare));
     }
 }
 
 #[derive(Resource)]
 pub struct VoxelData {
     pub uniform_buffer: UniformBuffer<VoxelUniforms>,
-    pub chunk_textures: [TextureView; 27],
+    pub chunk_storage: ChunkStorage,
+    pub voxel_format: VoxelFormat,
+    pub chunk_size: u32,
+    pub chunk_grid: UVec3,
+    /// One texture per chunk, or only the atlas.
+    pub chunk_textures: Vec<Texture>,
+    pub chunk_texture_views: Vec<TextureView>,
     pub grid_hierarchy: Buffer,
+    /// Only with [`ChunkStorage::Bricks`], the brick pool is the only chunk
+    /// texture then.
+    pub bricks: Option<Bricks>,
+    /// Only with [`VoxelFormat::Wide`], the materials from 256 on.
+    pub wide_materials: Option<Buffer>,
+    pub objects: VoxelObjectBuffers,
     pub texture_sampler: Sampler,
     pub bind_group_layout: BindGroupLayout,
     pub bind_group: BindGroup,
 }
 
+impl VoxelData {
+    /// Empty chunks the size of the chunks in the uniforms. Worlds share the
+    /// layouts, the sampler and the wide materials.
+    fn new(
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        settings: &VoxelEngineSettings,
+        voxel_uniforms: VoxelUniforms,
+        (bind_group_layout, copy_bind_group_layout): (BindGroupLayout, Option<BindGroupLayout>),
+        texture_sampler: Sampler,
+        wide_materials: Option<Buffer>,
+    ) -> Self {
+        let chunk_size = voxel_uniforms.texture_size;
+        let mut uniform_buffer = UniformBuffer::from(voxel_uniforms);
+        uniform_buffer.write_buffer(render_device, render_queue);
+
+        let grid_hierarchy = create_grid_hierarchy_buffer(render_device, settings, chunk_size);
+        let chunk_textures = create_chunk_textures(render_device, settings, chunk_size);
+        let chunk_texture_views: Vec<TextureView> = chunk_textures
+            .iter()
+            .map(|texture| texture.create_view(&TextureViewDescriptor::default()))
+            .collect();
+        let bricks = copy_bind_group_layout
+            .map(|layout| Bricks::new(render_device, layout, settings, chunk_size));
+        let objects = VoxelObjectBuffers::new(render_device);
+
+        // bound once every buffer is in place
+        let empty_layout = render_device.create_bind_group_layout(None, &[]);
+        let bind_group = render_device.create_bind_group(None, &empty_layout, &[]);
+
+        let mut voxel_data = Self {
+            uniform_buffer,
+            chunk_storage: settings.chunk_storage,
+            voxel_format: settings.voxel_format,
+            chunk_size,
+            chunk_grid: settings.chunk_grid,
+            chunk_textures,
+            chunk_texture_views,
+            grid_hierarchy,
+            bricks,
+            wide_materials,
+            objects,
+            texture_sampler,
+            bind_group_layout,
+            bind_group,
+        };
+        voxel_data.update_bind_group(render_device);
+        voxel_data
+    }
+
+    /// Empty chunks and grid hierarchy of the size of the chunks of a newly
+    /// loaded world, with the chunks of the world written into them.
+    fn load_world(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        settings: &VoxelEngineSettings,
+        gh: &GridHierarchy,
+    ) {
+        self.grid_hierarchy = create_grid_hierarchy_buffer(render_device, settings, gh.texture_size);
+
+        let chunk_textures = create_chunk_textures(render_device, settings, gh.texture_size);
+        self.chunk_texture_views = chunk_textures
+            .iter()
+            .map(|texture| texture.create_view(&TextureViewDescriptor::default()))
+            .collect();
+        self.chunk_textures = chunk_textures;
+        self.chunk_size = gh.texture_size;
+        if let Some(bricks) = self.bricks.take() {
+            let layout = bricks.copy_bind_group_layout;
+            self.bricks = Some(Bricks::new(render_device, layout, settings, gh.texture_size));
+        }
+
+        self.write_world(render_queue, settings, gh);
+    }
+
+    /// Writes the chunks of a world around chunk zero into empty chunks.
+    fn write_world(
+        &mut self,
+        render_queue: &RenderQueue,
+        settings: &VoxelEngineSettings,
+        gh: &GridHierarchy,
+    ) {
+        // new textures are empty, so only chunks with voxels are written
+        for i in 0..settings.active_chunk_count() {
+            let position = active_chunk_position(i, IVec3::ZERO, settings.chunk_grid);
+            if let Some(data) = gh.chunks.get(&position) {
+                let size = UVec3::splat(gh.texture_size);
+                self.write_chunk(render_queue, i, UVec3::ZERO, size, data);
+            }
+        }
+    }
+
+    fn write_uniforms(
+        &mut self,
+        voxel_uniforms: &VoxelUniforms,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+    ) {
+        self.uniform_buffer.set(voxel_uniforms.clone());
+        self.uniform_buffer.write_buffer(render_device, render_queue);
+    }
+
+    /// Binds the current chunks and grid hierarchy.
+    fn update_bind_group(&mut self, render_device: &RenderDevice) {
+        let chunk_texture_refs: Vec<&wgpu::TextureView> =
+            self.chunk_texture_views.iter().map(|tv| &**tv).collect();
+        let chunk_binding = match self.chunk_storage {
+            ChunkStorage::TextureArray => BindingResource::TextureViewArray(&chunk_texture_refs),
+            _ => BindingResource::TextureView(&self.chunk_texture_views[0]),
+        };
+
+        let mut entries = vec![
+            BindGroupEntry {
+                binding: 0,
+                resource: self.uniform_buffer.binding().unwrap(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: chunk_binding,
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: self.grid_hierarchy.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: BindingResource::Sampler(&self.texture_sampler),
+            },
+        ];
+        if let Some(bricks) = &self.bricks {
+            entries.extend(bricks.bind_group_entries());
+        }
+        if let Some(wide_materials) = &self.wide_materials {
+            entries.push(BindGroupEntry {
+                binding: 7,
+                resource: wide_materials.as_entire_binding(),
+            });
+        }
+        entries.extend(self.objects.bind_group_entries());
+        self.bind_group = render_device.create_bind_group(None, &self.bind_group_layout, &entries);
+    }
+
+    /// The texture a chunk is stored in and the corner of the chunk in it.
+    pub fn chunk_texture(&self, texture_index: usize) -> (&Texture, Origin3d) {
+        match self.chunk_storage {
+            ChunkStorage::Atlas => {
+                // the textures are indexed with zyx
+                let corner = chunk_slot(texture_index, self.chunk_grid) * self.chunk_size;
+                let origin = Origin3d {
+                    x: corner.z,
+                    y: corner.y,
+                    z: corner.x,
+                };
+                (&self.chunk_textures[0], origin)
+            }
+            // bricks are packed and unpacked through the staging texture
+            ChunkStorage::Bricks => (&self.bricks.as_ref().unwrap().staging, Origin3d::ZERO),
+            _ => (&self.chunk_textures[texture_index], Origin3d::ZERO),
+        }
+    }
+
+    /// Writes a box of voxels into a chunk, the data is in the same order and
+    /// narrow layout as the chunk data. Writes to bricks wait for the brick
+    /// pipelines.
+    pub(super) fn write_chunk(
+        &mut self,
+        render_queue: &RenderQueue,
+        texture_index: usize,
+        min: UVec3,
+        size: UVec3,
+        data: &[u8],
+    ) {
+        let data = self.voxel_format.widen(data);
+        if let Some(bricks) = &mut self.bricks {
+            bricks.pending.push(ChunkWrite {
+                texture_index,
+                min,
+                size,
+                data: data.into_owned(),
+            });
+            return;
+        }
+        write_texture(
+            render_queue,
+            self.chunk_texture(texture_index),
+            (min, size),
+            &data,
+            self.voxel_format,
+        );
+    }
+}
+
+/// Writes a box of voxels at `min` into the chunk at the corner of a texture,
+/// the data is already in the layout of the texture.
+pub(super) fn write_texture(
+    render_queue: &RenderQueue,
+    (texture, corner): (&Texture, Origin3d),
+    (min, size): (UVec3, UVec3),
+    data: &[u8],
+    voxel_format: VoxelFormat,
+) {
+    render_queue.write_texture(
+        ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: Origin3d {
+                x: corner.x + min.z,
+                y: corner.y + min.y,
+                z: corner.z + min.x,
+            },
+            aspect: TextureAspect::All,
+        },
+        data,
+        ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(size.z * voxel_format.bytes_per_voxel()),
+            rows_per_image: Some(size.y),
+        },
+        Extent3d {
+            width: size.z,
+            height: size.y,
+            depth_or_array_layers: size.x,
+        },
+    );
+}
+
+/// Gpu memory of [`ChunkStorage::Bricks`]. Every brick sized cell of the
+/// active chunks has an entry in the index that is zero while the cell is
+/// empty, or one more than the brick in the pool holding its voxels. The
+/// shaders allocate bricks as they write, and free them again through the
+/// allocator. The cpu reads and writes whole chunks through the staging
+/// texture, which the brick pipelines copy from and to the pool.
+pub struct Bricks {
+    pub index: Buffer,
+    /// Counts, then the free list and the bricks freed since the free list
+    /// was last refilled, see `BrickAllocator` in common.wgsl.
+    pub allocator: Buffer,
+    /// The count of voxel writes the full pool dropped is copied here, see
+    /// [`BrickPoolOverflow`](super::compute::bricks::BrickPoolOverflow).
+    pub(super) dropped_read_back: Buffer,
+    pub(super) dropped_map: Option<MapResult>,
+    /// Dropped writes counted so far.
+    pub(super) dropped: u32,
+    pub staging: Texture,
+    /// Which chunk and box of voxels the brick pipelines copy, see
+    /// [`Bricks::set_copy`].
+    pub copy: Buffer,
+    pub copy_bind_group_layout: BindGroupLayout,
+    pub copy_bind_group: BindGroup,
+    /// Chunk writes waiting for the brick pipelines.
+    pub(super) pending: Vec<ChunkWrite>,
+}
+
+pub(super) struct ChunkWrite {
+    pub texture_index: usize,
+    pub min: UVec3,
+    pub size: UVec3,
+    pub data: Vec<u8>,
+}
+
+#[derive(Default, Clone, Debug, PartialEq, ShaderType)]
+pub struct BrickCopy {
+    pub chunk_index: u32,
+    pub min: UVec3,
+    pub size: UVec3,
+}
+
+impl Bricks {
+    /// Every brick starts out free.
+    fn new(
+        render_device: &RenderDevice,
+        copy_bind_group_layout: BindGroupLayout,
+        settings: &VoxelEngineSettings,
+        chunk_size: u32,
+    ) -> Self {
+        // cells for the largest chunks, smaller chunks use the start of it
+        let cells = (settings.chunk_size / BRICK_SIZE).pow(3) as usize;
+        let index = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("brick index"),
+            contents: bytemuck::cast_slice(&vec![0u32; cells * settings.active_chunk_count()]),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        });
+
+        let allocator = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("brick allocator"),
+            contents: bytemuck::cast_slice(&bricks::allocator_contents(settings.brick_capacity)),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        });
+        let dropped_read_back = render_device.create_buffer(&BufferDescriptor {
+            label: Some("dropped brick writes read back"),
+            size: 4,
+            usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let staging = render_device.create_texture(&TextureDescriptor {
+            label: Some("brick staging texture"),
+            size: Extent3d {
+                width: chunk_size,
+                height: chunk_size,
+                depth_or_array_layers: chunk_size,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D3,
+            format: settings.voxel_format.texture_format(),
+            usage: TextureUsages::STORAGE_BINDING
+                | TextureUsages::COPY_DST
+                | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let staging_view = staging.create_view(&TextureViewDescriptor::default());
+
+        let copy = render_device.create_buffer(&BufferDescriptor {
+            label: Some("brick copy uniform"),
+            size: BrickCopy::SHADER_SIZE.get(),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let copy_bind_group = render_device.create_bind_group(
+            None,
+            &copy_bind_group_layout,
+            &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&staging_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: copy.as_entire_binding(),
+                },
+            ],
+        );
+
+        Self {
+            index,
+            allocator,
+            dropped_read_back,
+            dropped_map: None,
+            dropped: 0,
+            staging,
+            copy,
+            copy_bind_group_layout,
+            copy_bind_group,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Takes effect with the next submit, so there can only be one copy per
+    /// submit. Batched chunk writes copy theirs in with the command encoder.
+    pub fn set_copy(&self, render_queue: &RenderQueue, copy: BrickCopy) {
+        let mut bytes = encase::UniformBuffer::new(Vec::new());
+        bytes.write(&copy).unwrap();
+        render_queue.write_buffer(&self.copy, 0, bytes.as_ref());
+    }
+
+    fn bind_group_entries(&self) -> [BindGroupEntry<'_>; 2] {
+        [
+            BindGroupEntry {
+                binding: 4,
+                resource: self.index.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: self.allocator.as_entire_binding(),
+            },
+        ]
+    }
+}
+
+/// Materials in [`VoxelData::wide_materials`], the ones from 256 to 4095.
+const WIDE_MATERIALS: usize = 4096 - 256;
+
+#[derive(Default, Clone, Copy, ShaderType)]
+pub struct WideMaterialEntry {
+    pub colour: Vec4,
+    pub material: MaterialEntry,
+}
+
+/// A grid hierarchy for every active chunk, by texture.
+fn create_grid_hierarchy_buffer(
+    render_device: &RenderDevice,
+    settings: &VoxelEngineSettings,
+    chunk_size: u32,
+) -> Buffer {
+    let buffer_size =
+        GridHierarchy::empty(chunk_size).get_buffer_size() * settings.active_chunk_count();
+    render_device.create_buffer_with_data(&BufferInitDescriptor {
+        contents: &vec![0; buffer_size],
+        label: None,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+    })
+}
+
+/// The settings with the chunk storage resolved, settings the gpu can't run are
+/// logged and replaced by ones it can. Panics if the gpu can't read and write
+/// chunk textures of either voxel format.
+fn supported_settings(
+    settings: &VoxelEngineSettings,
+    render_device: &RenderDevice,
+    render_adapter: &RenderAdapter,
+) -> VoxelEngineSettings {
+    let mut settings = settings.clone();
+    let features = render_device.features();
+    let resolve = |voxel_format| {
+        settings
+            .chunk_storage
+            .resolve(render_adapter, features, voxel_format)
+    };
+    settings.chunk_storage = match resolve(settings.voxel_format) {
+        Ok(chunk_storage) => chunk_storage,
+        Err(e) => {
+            // the other format has a texture format of its own
+            let voxel_format = match settings.voxel_format {
+                VoxelFormat::Narrow => VoxelFormat::Wide,
+                VoxelFormat::Wide => VoxelFormat::Narrow,
+            };
+            match resolve(voxel_format) {
+                Ok(chunk_storage) => {
+                    error!("{}, using {:?} voxels instead", e, voxel_format);
+                    settings.voxel_format = voxel_format;
+                    chunk_storage
+                }
+                Err(_) => panic!("{}", e),
+            }
+        }
+    };
+
+    let max_size = render_device.limits().max_texture_dimension_3d;
+    if settings.chunk_storage == ChunkStorage::Atlas {
+        let chunks_across = settings.chunk_grid.max_element();
+        let atlas_size = chunks_across * settings.chunk_size;
+        if atlas_size > max_size {
+            let chunk_size = 1 << (max_size / chunks_across).max(8).ilog2();
+            error!(
+                "The chunk atlas would be {} voxels wide, the gpu supports at most {}, using \
+                 chunks of size {} instead",
+                atlas_size, max_size, chunk_size
+            );
+            settings.chunk_size = chunk_size;
+        }
+    }
+    if settings.chunk_storage == ChunkStorage::Bricks {
+        let pool_depth = brick_pool_layers(settings.brick_capacity) * BRICK_SIZE;
+        if pool_depth > max_size {
+            let brick_capacity = max_size / BRICK_SIZE * BRICK_POOL_WIDTH * BRICK_POOL_WIDTH;
+            error!(
+                "The brick pool would be {} voxels deep, the gpu supports at most {}, using \
+                 {} bricks instead",
+                pool_depth, max_size, brick_capacity
+            );
+            settings.brick_capacity = brick_capacity;
+        }
+    }
+    settings
+}
+
+/// Layers of bricks in a pool of `capacity` bricks.
+fn brick_pool_layers(capacity: u32) -> u32 {
+    capacity.div_ceil(BRICK_POOL_WIDTH * BRICK_POOL_WIDTH)
+}
+
 #[derive(Default, Debug, Clone, Copy, ShaderType)]
 pub struct PalleteEntry {
     pub colour: Vec4,
@@ -202,6 +752,72 @@ impl Into<[PalleteEntry; 256]> for Pallete {
     }
 }
 
+/// Gpu side [`Material`], indexed by palette index like [`PalleteEntry`].
+#[derive(Default, Debug, Clone, Copy, ShaderType)]
+pub struct MaterialEntry {
+    pub roughness: f32,
+    pub metalness: f32,
+    pub specular: f32,
+    pub transparency: f32,
+    pub ior: f32,
+    pub emission: f32,
+    // arrays in uniforms need a stride that is a multiple of 16
+    pub padding: Vec2,
+}
+
+impl From<Material> for MaterialEntry {
+    fn from(material: Material) -> Self {
+        Self {
+            roughness: material.roughness,
+            metalness: material.metalness,
+            specular: material.specular,
+            transparency: material.transparency,
+            ior: material.ior,
+            emission: material.emission,
+            padding: Vec2::ZERO,
+        }
+    }
+}
+
+impl From<MaterialEntry> for Material {
+    fn from(entry: MaterialEntry) -> Self {
+        Self {
+            roughness: entry.roughness,
+            metalness: entry.metalness,
+            specular: entry.specular,
+            transparency: entry.transparency,
+            ior: entry.ior,
+            emission: entry.emission,
+        }
+    }
+}
+
+/// Slots of the materials the shaders give a behaviour, looked up in the
+/// [`MaterialRegistry`]. Ranges are first and last slot.
+#[derive(Default, Debug, Clone, Copy, ShaderType)]
+pub struct MaterialIds {
+    pub grass: u32,
+    pub water: u32,
+    pub grass_blades: UVec2,
+    pub fire: UVec2,
+}
+
+impl MaterialIds {
+    /// Names that aren't registered keep their default slots.
+    pub fn new(registry: &MaterialRegistry) -> Self {
+        let range = |name, default| {
+            let ids = registry.range(name).unwrap_or(default);
+            UVec2::new(*ids.start() as u32, *ids.end() as u32)
+        };
+        Self {
+            grass: registry.get("grass").unwrap_or(material::GRASS) as u32,
+            water: registry.get("water").unwrap_or(material::WATER) as u32,
+            grass_blades: range("grass_blades", material::GRASS_BLADES),
+            fire: range("fire", material::FIRE),
+        }
+    }
+}
+
 #[derive(Default, Debug, Clone, Copy, ShaderType)]
 pub struct ExtractedPortal {
     pub transformation: Mat4,
@@ -211,23 +827,134 @@ pub struct ExtractedPortal {
 
 #[derive(Default, Clone, Copy, ShaderType)]
 pub struct ChunkInfo {
-    pub position: Vec3,
+    /// Chunk coordinate, in chunks from chunk zero.
+    pub position: IVec3,
     pub texture_index: u32,
 }
 
-#[derive(Resource, ExtractResource, Clone, ShaderType)]
+/// Chunks are kept in the texture of their position modulo the chunk grid, so
+/// the texture of a chunk is found without searching the active chunks. The
+/// shaders use the same mapping.
+pub fn chunk_texture_index(position: IVec3, grid: UVec3) -> usize {
+    let slot = position.rem_euclid(grid.as_ivec3()).as_uvec3();
+    (slot.x + (slot.y + slot.z * grid.y) * grid.x) as usize
+}
+
+/// Inverse of [`chunk_texture_index`], the position of a texture in the grid.
+fn chunk_slot(texture_index: usize, grid: UVec3) -> UVec3 {
+    let index = texture_index as u32;
+    UVec3::new(
+        index % grid.x,
+        (index / grid.x) % grid.y,
+        index / (grid.x * grid.y),
+    )
+}
+
+/// Chunk coordinate of the chunk in a texture while the active chunks are
+/// centred on `origin`.
+pub fn active_chunk_position(texture_index: usize, origin: IVec3, grid: UVec3) -> IVec3 {
+    let slot = chunk_slot(texture_index, grid).as_ivec3();
+    let grid = grid.as_ivec3();
+    let min = origin - grid / 2;
+    min + (slot - min).rem_euclid(grid)
+}
+
+/// A resource for the main world and a component of every [`ExtraVoxelWorld`].
+#[derive(Resource, ExtractResource, Component, ExtractComponent, Clone, ShaderType)]
 pub struct VoxelUniforms {
     pub pallete: [PalleteEntry; 256],
+    pub materials: [MaterialEntry; 256],
+    pub material_ids: MaterialIds,
     pub portals: [ExtractedPortal; 32],
     pub levels: [UVec4; 8],
     pub offsets: [UVec4; 8],
     pub texture_size: u32,
     pub chunk_size: u32,
     pub world_size: u32,
-    pub active_chunks: [ChunkInfo; 27],
+    pub voxels_per_meter: f32,
+    /// Chunk the active chunks are centred on, and render space with them.
+    /// Render space and the compute passes cover every active chunk.
+    pub origin_chunk: IVec3,
+    /// Active chunks along each axis, from [`VoxelEngineSettings`].
+    pub chunk_grid: UVec3,
+    /// Indexed by texture, see [`chunk_texture_index`]. Only the first chunks
+    /// of the grid are used, see [`VoxelUniforms::chunks`].
+    pub active_chunks: [ChunkInfo; MAX_ACTIVE_CHUNKS],
 }
-#[derive(Resource, ExtractResource, Clone)]
-enum NewGridHierarchy {
+
+impl VoxelUniforms {
+    fn new(settings: &VoxelEngineSettings, gh: &GridHierarchy) -> Self {
+        let gh_offsets = gh.get_offsets();
+        let mut offsets = [UVec4::ZERO; 8];
+        for i in 0..8 {
+            offsets[i] = UVec4::new(gh_offsets[i], 0, 0, 0);
+        }
+
+        let mut voxel_uniforms = Self {
+            pallete: [PalleteEntry::default(); 256],
+            materials: [MaterialEntry::default(); 256],
+            material_ids: MaterialIds::new(&MaterialRegistry::default()),
+            portals: [ExtractedPortal::default(); 32],
+            levels: [UVec4::ZERO; 8],
+            offsets,
+            texture_size: 0,
+            chunk_size: 0,
+            world_size: 0,
+            voxels_per_meter: settings.voxels_per_meter,
+            origin_chunk: IVec3::ZERO,
+            chunk_grid: settings.chunk_grid,
+            active_chunks: [ChunkInfo::default(); MAX_ACTIVE_CHUNKS],
+        };
+        voxel_uniforms.load(gh);
+        voxel_uniforms
+    }
+
+    /// Takes the palette, materials and chunk size of a newly loaded world,
+    /// the active chunks start out around chunk zero.
+    fn load(&mut self, gh: &GridHierarchy) {
+        let mut levels = [UVec4::ZERO; 8];
+        for i in 0..8 {
+            levels[i] = UVec4::new(gh.levels[i], 0, 0, 0);
+        }
+
+        self.pallete = gh.pallete.clone().into();
+        self.materials = gh.materials.map(MaterialEntry::from);
+        self.levels = levels;
+        self.texture_size = gh.texture_size;
+        self.chunk_size = gh.texture_size;
+        self.world_size = gh.texture_size * self.chunk_grid.max_element();
+
+        self.origin_chunk = IVec3::ZERO;
+        for i in 0..self.chunks().len() {
+            self.active_chunks[i] = ChunkInfo {
+                position: active_chunk_position(i, IVec3::ZERO, self.chunk_grid),
+                texture_index: i as u32,
+            };
+        }
+    }
+
+    /// Material ids and overridden material properties from the registry.
+    fn apply_material_registry(&mut self, material_registry: &MaterialRegistry) {
+        self.material_ids = MaterialIds::new(material_registry);
+        for (id, material) in material_registry.overrides() {
+            self.materials[id as usize] = material.into();
+        }
+    }
+
+    /// The active chunks of the grid.
+    pub fn chunks(&self) -> &[ChunkInfo] {
+        let grid = self.chunk_grid;
+        &self.active_chunks[..(grid.x * grid.y * grid.z) as usize]
+    }
+
+    /// Workgroups of 4x4x4 voxels that cover every active chunk, the compute
+    /// passes over the chunk grid are dispatched with this.
+    pub fn grid_dispatch_size(&self) -> UVec3 {
+        self.chunk_grid * self.chunk_size / 4
+    }
+}
+#[derive(Resource, ExtractResource, Component, ExtractComponent, Clone)]
+pub(crate) enum NewGridHierarchy {
     Some(Arc<GridHierarchy>),
     None,
 }
@@ -238,127 +965,484 @@ fn prepare_uniforms(
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
 ) {
-    voxel_data.uniform_buffer.set(voxel_uniforms.clone());
-    voxel_data
-        .uniform_buffer
-        .write_buffer(&render_device, &render_queue);
+    voxel_data.write_uniforms(&voxel_uniforms, &render_device, &render_queue);
 }
+
 fn load_voxel_world(
     mut load_voxel_world: ResMut<LoadVoxelWorld>,
     mut new_gh: ResMut<NewGridHierarchy>,
     mut voxel_uniforms: ResMut<VoxelUniforms>,
+    (mut pending, mut generator): (ResMut<PendingWorldLoad>, Local<Option<SeededGenerator>>),
+    (mut voxel_mirror, mut chunk_source, region_cache): (
+        ResMut<VoxelMirror>,
+        ResMut<ChunkSource>,
+        Option<ResMut<RegionCache>>,
+    ),
+    (vox_worlds, asset_server, settings): (
+        Res<Assets<VoxWorldAsset>>,
+        Res<AssetServer>,
+        Res<VoxelEngineSettings>,
+    ),
+    (mut world_loaded, mut world_load_failed, anchors): (
+        EventWriter<WorldLoaded>,
+        EventWriter<WorldLoadFailed>,
+        Query<(), With<ChunkAnchor>>,
+    ),
 ) {
+    *new_gh = NewGridHierarchy::None;
+
+    // generated worlds keep building chunks as they stream in
     match load_voxel_world.as_ref() {
-        LoadVoxelWorld::Empty(_) | LoadVoxelWorld::File(_) => {
-            let gh = match load_voxel_world.as_ref() {
-                LoadVoxelWorld::Empty(size) => GridHierarchy::empty(*size),
-                LoadVoxelWorld::File(path) => {
-                    let file = std::fs::read(path).unwrap();
-                    GridHierarchy::from_vox(&file).unwrap()
-                }
-                LoadVoxelWorld::None => unreachable!(),
-            };
+        LoadVoxelWorld::Generated { seed, generator: g } => *generator = Some((*seed, g.clone())),
+        LoadVoxelWorld::None => {}
+        _ => *generator = None,
+    }
+
+    // worlds only stream with an anchor
+    let Some(gh) = take_requested_world(
+        &mut load_voxel_world,
+        &mut pending,
+        &vox_worlds,
+        &asset_server,
+        &settings,
+        !anchors.is_empty(),
+    ) else {
+        return;
+    };
+    let gh = match gh {
+        Ok(gh) => gh,
+        Err(e) => {
+            error!("Failed to load voxel world: {}", e);
+            world_load_failed.send(WorldLoadFailed(e));
+            return;
+        }
+    };
+
+    // streaming moves the active chunks to the anchor
+    voxel_uniforms.load(&gh);
+
+    voxel_mirror.reset(&gh);
+    chunk_source.world = Some(gh.clone());
+    chunk_source.generator = generator.take();
+    chunk_source.pending.clear();
+    // chunks saved while the last world was loaded don't belong to this one
+    if let Some(mut region_cache) = region_cache {
+        region_cache.load_world();
+    }
+
+    *new_gh = NewGridHierarchy::Some(gh);
+    world_loaded.send(WorldLoaded);
+}
 
-            let mut levels = [UVec4::ZERO; 8];
-            for i in 0..8 {
-                levels[i] = UVec4::new(gh.levels[i], 0, 0, 0);
+/// A requested world being read, parsed or generated on the task pool.
+#[derive(Resource, Component, Default)]
+pub(crate) struct PendingWorldLoad(Option<Task<Result<Arc<GridHierarchy>, String>>>);
+
+/// The world a load request asks for, none while there is nothing to load or
+/// the world is still loading. The request is cleared once it is taken, the
+/// world is loaded and checked on the task pool. Worlds that
+/// don't stream have to fit in the active chunks.
+fn take_requested_world(
+    load_voxel_world: &mut LoadVoxelWorld,
+    pending: &mut PendingWorldLoad,
+    vox_worlds: &Assets<VoxWorldAsset>,
+    asset_server: &AssetServer,
+    settings: &VoxelEngineSettings,
+    streams: bool,
+) -> Option<Result<Arc<GridHierarchy>, String>> {
+    // a new request replaces the one still loading
+    if !matches!(load_voxel_world, LoadVoxelWorld::None) {
+        pending.0 = None;
+    }
+
+    let settings = settings.clone();
+    let chunk_size = settings.chunk_size;
+    let spawn = |load: Box<dyn FnOnce() -> Result<Arc<GridHierarchy>, String> + Send>| {
+        AsyncComputeTaskPool::get().spawn(async move { check_world(load()?, &settings, streams) })
+    };
+    match std::mem::replace(load_voxel_world, LoadVoxelWorld::None) {
+        LoadVoxelWorld::None => {}
+        LoadVoxelWorld::Empty(size) => {
+            if !size.is_power_of_two() || !(8..=MAX_CHUNK_SIZE).contains(&size) {
+                return Some(Err(format!("Invalid world size {}", size)));
             }
+            pending.0 = Some(spawn(Box::new(move || {
+                Ok(Arc::new(GridHierarchy::empty(size)))
+            })));
+        }
+        LoadVoxelWorld::File(path) => {
+            pending.0 = Some(spawn(Box::new(move || {
+                std::fs::read(&path)
+                    .map_err(|e| format!("{}: {}", path, e))
+                    .and_then(|file| GridHierarchy::from_file(&file, Path::new(&path), chunk_size))
+                    .map(Arc::new)
+            })));
+        }
+        LoadVoxelWorld::Native(path) => {
+            pending.0 = Some(spawn(Box::new(move || {
+                std::fs::read(&path)
+                    .map_err(|e| format!("{}: {}", path, e))
+                    .and_then(|file| GridHierarchy::from_native(&file))
+                    .map(Arc::new)
+            })));
+        }
+        LoadVoxelWorld::Generated { seed, generator } => {
+            pending.0 = Some(spawn(Box::new(move || {
+                generator.generate(seed).map(Arc::new)
+            })));
+        }
+        LoadVoxelWorld::Asset(handle) => match vox_worlds.get(&handle) {
+            Some(vox_world) => {
+                let gh = vox_world.0.clone();
+                pending.0 = Some(spawn(Box::new(move || Ok(gh))));
+            }
+            None => match asset_server.get_load_state(&handle) {
+                Some(LoadState::Failed(e)) => return Some(Err(e.to_string())),
+                _ => {
+                    // still loading
+                    *load_voxel_world = LoadVoxelWorld::Asset(handle);
+                }
+            },
+        },
+    }
 
-            voxel_uniforms.pallete = gh.pallete.clone().into();
-            voxel_uniforms.levels = levels;
-            voxel_uniforms.texture_size = gh.texture_size;
-
-            // Initialize active chunks
-            for i in 0..27 {
-                let x = (i % 3) as f32 - 1.0;
-                let y = ((i / 3) % 3) as f32 - 1.0;
-                let z = (i / 9) as f32 - 1.0;
-                voxel_uniforms.active_chunks[i] = ChunkInfo {
-                    position: Vec3::new(x, y, z) * voxel_uniforms.chunk_size as f32,
-                    texture_index: i as u32,
-                };
+    let loaded = block_on(poll_once(pending.0.as_mut()?))?;
+    pending.0 = None;
+    Some(loaded)
+}
+
+/// Fails for worlds the engine settings don't allow.
+fn check_world(
+    gh: Arc<GridHierarchy>,
+    settings: &VoxelEngineSettings,
+    streams: bool,
+) -> Result<Arc<GridHierarchy>, String> {
+    if gh.texture_size > settings.chunk_size {
+        return Err(format!(
+            "Chunks of size {} are bigger than the {} allowed by the engine settings",
+            gh.texture_size, settings.chunk_size
+        ));
+    }
+
+    let grid = settings.chunk_grid.as_ivec3();
+    let min = -grid / 2;
+    let outside = |chunk: &&IVec3| chunk.cmplt(min).any() || chunk.cmpge(min + grid).any();
+    match gh.chunks.keys().find(outside) {
+        Some(chunk) if !streams => Err(format!(
+            "World does not fit in the active chunks, chunk {} is outside of the {}x{}x{} grid",
+            chunk, grid.x, grid.y, grid.z
+        )),
+        _ => Ok(gh),
+    }
+}
+
+/// A voxel world besides the one [`LoadVoxelWorld`] loads as a resource, with
+/// chunks, a palette and a grid hierarchy of its own. Spawn it together with
+/// a [`LoadVoxelWorld`] component, which is loaded like the resource. Cameras
+/// trace it when they have a [`TargetVoxelWorld`] pointing at it.
+///
+/// Extra worlds use the same engine settings and take as much gpu memory as
+/// the main world. They don't stream, and edits, physics, animation,
+/// voxelization and saving only work on the main world.
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct ExtraVoxelWorld;
+
+/// Makes a camera trace an [`ExtraVoxelWorld`] instead of the main world.
+#[derive(Component, ExtractComponent, Clone, Copy, Debug)]
+pub struct TargetVoxelWorld(pub Entity);
+
+/// Gpu side of the loaded extra worlds by entity.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub struct ExtraVoxelData(HashMap<Entity, VoxelData>);
+
+/// The voxel data of the world a view traces, none until its world is
+/// loaded.
+pub(crate) fn view_voxel_data<'a>(
+    world: &'a World,
+    target: Option<&TargetVoxelWorld>,
+) -> Option<&'a VoxelData> {
+    match target {
+        Some(target) => world.resource::<ExtraVoxelData>().get(&target.0),
+        None => Some(world.resource::<VoxelData>()),
+    }
+}
+
+fn init_extra_worlds(
+    mut commands: Commands,
+    worlds: Query<Entity, Added<ExtraVoxelWorld>>,
+    settings: Res<VoxelEngineSettings>,
+) {
+    for entity in &worlds {
+        let gh = GridHierarchy::empty(settings.chunk_size);
+        commands.entity(entity).insert((
+            VoxelUniforms::new(&settings, &gh),
+            NewGridHierarchy::None,
+            PendingWorldLoad::default(),
+        ));
+    }
+}
+
+/// The load request of an extra world and what loading it writes.
+type ExtraWorldLoad = (
+    Entity,
+    &'static mut LoadVoxelWorld,
+    &'static mut VoxelUniforms,
+    &'static mut NewGridHierarchy,
+    &'static mut PendingWorldLoad,
+);
+
+fn load_extra_worlds(
+    mut worlds: Query<ExtraWorldLoad, With<ExtraVoxelWorld>>,
+    (vox_worlds, asset_server): (Res<Assets<VoxWorldAsset>>, Res<AssetServer>),
+    (material_registry, settings): (Res<MaterialRegistry>, Res<VoxelEngineSettings>),
+) {
+    for (entity, mut load_voxel_world, mut voxel_uniforms, mut new_gh, mut pending) in &mut worlds {
+        // only set for the frame the world is loaded in
+        if matches!(*new_gh, NewGridHierarchy::Some(_)) {
+            *new_gh = NewGridHierarchy::None;
+        }
+
+        let Some(gh) = take_requested_world(
+            &mut load_voxel_world,
+            &mut pending,
+            &vox_worlds,
+            &asset_server,
+            &settings,
+            false,
+        ) else {
+            continue;
+        };
+        let gh = match gh {
+            Ok(gh) => gh,
+            Err(e) => {
+                error!("Failed to load voxel world into {}: {}", entity, e);
+                continue;
             }
+        };
+
+        voxel_uniforms.load(&gh);
+        voxel_uniforms.apply_material_registry(&material_registry);
+        *new_gh = NewGridHierarchy::Some(gh);
+        info!("Loaded voxel world into {}", entity);
+    }
+}
+
+/// Keeps the shaders in sync with the registry, and puts the overridden
+/// material properties back after a world replaced them.
+fn apply_material_registry(
+    material_registry: Res<MaterialRegistry>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+    mut extra_worlds: Query<&mut VoxelUniforms, With<ExtraVoxelWorld>>,
+    mut world_loaded: EventReader<WorldLoaded>,
+) {
+    let loaded = world_loaded.read().count() > 0;
+    if !material_registry.is_changed() && !loaded {
+        return;
+    }
 
-            *new_gh = NewGridHierarchy::Some(Arc::new(gh));
-            *load_voxel_world = LoadVoxelWorld::None;
+    voxel_uniforms.apply_material_registry(&material_registry);
+    if material_registry.is_changed() {
+        for mut voxel_uniforms in &mut extra_worlds {
+            voxel_uniforms.apply_material_registry(&material_registry);
         }
-        LoadVoxelWorld::None => {
-            *new_gh = NewGridHierarchy::None;
+    }
+}
+
+/// Writes the active palette into the uniforms when it changes, or the
+/// palette of the loaded world when there is none.
+fn apply_active_palette(
+    active_palette: Res<ActivePalette>,
+    palettes: Res<Assets<Palette>>,
+    new_gh: Res<NewGridHierarchy>,
+    mut palette_events: EventReader<AssetEvent<Palette>>,
+    mut voxel_uniforms: ResMut<VoxelUniforms>,
+    mut world_pallete: ResMut<WorldPallete>,
+) {
+    let loaded = match new_gh.as_ref() {
+        NewGridHierarchy::Some(gh) => {
+            world_pallete.0 = gh.pallete.clone();
+            true
         }
+        NewGridHierarchy::None => false,
+    };
+
+    // the asset can finish loading or be edited after it was made active
+    let asset_changed = palette_events.read().any(|event| {
+        active_palette.0.as_ref().is_some_and(|handle| {
+            event.is_loaded_with_dependencies(handle) || event.is_modified(handle)
+        })
+    });
+
+    if !loaded && !asset_changed && !active_palette.is_changed() {
+        return;
+    }
+
+    let pallete = match &active_palette.0 {
+        Some(handle) => palettes.get(handle).map(|palette| palette.0.clone()),
+        None => Some(world_pallete.0.clone()),
+    };
+    if let Some(pallete) = pallete {
+        voxel_uniforms.pallete = pallete.into();
     }
 }
 
-fn load_voxel_world_prepare(
+/// The palette the main world was loaded with, saves keep it while an
+/// [`ActivePalette`] recolours the world.
+#[derive(Resource, ExtractResource, Clone)]
+pub(crate) struct WorldPallete(pub Pallete);
+
+/// One empty texture per chunk, a single atlas with the chunks laid out like
+/// the chunk grid, or the empty brick pool.
+fn create_chunk_textures(
+    render_device: &RenderDevice,
+    settings: &VoxelEngineSettings,
+    size: u32,
+) -> Vec<Texture> {
+    // the textures are indexed with zyx
+    let (count, extent) = match settings.chunk_storage {
+        ChunkStorage::Atlas => {
+            let atlas = settings.chunk_grid * size;
+            (1, UVec3::new(atlas.z, atlas.y, atlas.x))
+        }
+        ChunkStorage::Bricks => {
+            let layers = brick_pool_layers(settings.brick_capacity);
+            let pool = UVec3::new(BRICK_POOL_WIDTH, BRICK_POOL_WIDTH, layers) * BRICK_SIZE;
+            (1, pool.zyx())
+        }
+        _ => (settings.active_chunk_count(), UVec3::splat(size)),
+    };
+
+    (0..count)
+        .map(|_| {
+            render_device.create_texture(&TextureDescriptor {
+                label: None,
+                size: Extent3d {
+                    width: extent.x,
+                    height: extent.y,
+                    depth_or_array_layers: extent.z,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: TextureDimension::D3,
+                format: settings.voxel_format.texture_format(),
+                usage: TextureUsages::STORAGE_BINDING
+                    | TextureUsages::COPY_DST
+                    | TextureUsages::COPY_SRC,
+                view_formats: &[],
+            })
+        })
+        .collect()
+}
+
+pub(super) fn load_voxel_world_prepare(
     mut voxel_data: ResMut<VoxelData>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
     new_gh: Res<NewGridHierarchy>,
+    settings: Res<VoxelEngineSettings>,
 ) {
     if let NewGridHierarchy::Some(gh) = new_gh.as_ref() {
-        let buffer_size = gh.get_buffer_size();
+        voxel_data.load_world(&render_device, &render_queue, &settings, gh);
+    }
+}
 
-        // grid hierarchy
-        voxel_data.grid_hierarchy = render_device.create_buffer_with_data(&BufferInitDescriptor {
-            contents: &vec![0; buffer_size],
-            label: None,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
-        });
+pub(crate) fn queue_bind_group(
+    render_device: Res<RenderDevice>,
+    mut voxel_data: ResMut<VoxelData>,
+) {
+    voxel_data.update_bind_group(&render_device);
+}
+
+/// Creates the gpu side of newly loaded extra worlds and drops the ones of
+/// despawned worlds, then writes the uniforms of every extra world.
+pub(super) fn prepare_extra_worlds(
+    mut extra_voxel_data: ResMut<ExtraVoxelData>,
+    worlds: Query<(Entity, &VoxelUniforms, &NewGridHierarchy)>,
+    voxel_data: Res<VoxelData>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    settings: Res<VoxelEngineSettings>,
+) {
+    extra_voxel_data.retain(|entity, _| worlds.contains(*entity));
 
-        // voxel world
-        // let voxel_world =
-        // voxel_data.voxel_world = voxel_world.create_view(&TextureViewDescriptor::default());
-        let chunk_textures: [TextureView; 27] = std::array::from_fn(|_| {
-            let texture = render_device.create_texture_with_data(
+    for (entity, voxel_uniforms, new_gh) in &worlds {
+        if let NewGridHierarchy::Some(gh) = new_gh {
+            let copy_bind_group_layout = voxel_data
+                .bricks
+                .as_ref()
+                .map(|bricks| bricks.copy_bind_group_layout.clone());
+            let mut world_data = VoxelData::new(
+                &render_device,
                 &render_queue,
-                &TextureDescriptor {
-                    label: None,
-                    size: Extent3d {
-                        width: gh.texture_size,
-                        height: gh.texture_size,
-                        depth_or_array_layers: gh.texture_size,
-                    },
-                    mip_level_count: 1,
-                    sample_count: 1,
-                    dimension: TextureDimension::D3,
-                    format: TextureFormat::R16Uint,
-                    usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_DST,
-                    view_formats: &[],
-                },
-                TextureDataOrder::default(),
-                &gh.texture_data,
+                &settings,
+                voxel_uniforms.clone(),
+                (voxel_data.bind_group_layout.clone(), copy_bind_group_layout),
+                voxel_data.texture_sampler.clone(),
+                voxel_data.wide_materials.clone(),
             );
-            texture.create_view(&TextureViewDescriptor::default())
-        });
+            world_data.write_world(&render_queue, &settings, gh);
+            extra_voxel_data.insert(entity, world_data);
+        }
 
-        voxel_data.chunk_textures = chunk_textures;
+        let Some(world_data) = extra_voxel_data.get_mut(&entity) else {
+            continue;
+        };
+        world_data.write_uniforms(voxel_uniforms, &render_device, &render_queue);
+        world_data.update_bind_group(&render_device);
     }
 }
 
-fn queue_bind_group(render_device: Res<RenderDevice>, mut voxel_data: ResMut<VoxelData>) {
-    let chunk_texture_refs: [&wgpu::TextureView; 27] =
-        voxel_data.chunk_textures.each_ref().map(|tv| &**tv);
+/// Uploads the wide materials whenever they change.
+fn prepare_wide_materials(
+    voxel_data: Res<VoxelData>,
+    wide_materials: Res<WideMaterials>,
+    render_queue: Res<RenderQueue>,
+) {
+    let Some(buffer) = &voxel_data.wide_materials else {
+        return;
+    };
+    if !wide_materials.is_changed() {
+        return;
+    }
 
-    let bind_group = render_device.create_bind_group(
-        None,
-        &voxel_data.bind_group_layout,
-        &[
-            BindGroupEntry {
-                binding: 0,
-                resource: voxel_data.uniform_buffer.binding().unwrap(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: BindingResource::TextureViewArray(&chunk_texture_refs),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: voxel_data.grid_hierarchy.as_entire_binding(),
-            },
-            BindGroupEntry {
-                binding: 3,
-                resource: BindingResource::Sampler(&voxel_data.texture_sampler),
-            },
-        ],
-    );
-    voxel_data.bind_group = bind_group;
+    let mut entries = vec![WideMaterialEntry::default(); WIDE_MATERIALS];
+    for (id, colour, material) in wide_materials.iter() {
+        entries[id as usize - 256] = WideMaterialEntry {
+            colour: colour.to_linear().to_vec4(),
+            material: material.into(),
+        };
+    }
+    let mut bytes = encase::StorageBuffer::new(Vec::new());
+    bytes.write(&entries).unwrap();
+    render_queue.write_buffer(buffer, 0, bytes.as_ref());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_chunks_map_back_to_their_texture() {
+        let grids = [UVec3::splat(3), UVec3::new(5, 3, 1), UVec3::ONE, UVec3::splat(5)];
+        let origins = [
+            IVec3::ZERO,
+            IVec3::new(1, 2, 3),
+            IVec3::new(-1, 0, 0),
+            IVec3::new(-7, -12, 5),
+            IVec3::splat(-1000),
+        ];
+        for grid in grids {
+            for origin in origins {
+                let count = (grid.x * grid.y * grid.z) as usize;
+                for i in 0..count {
+                    let position = active_chunk_position(i, origin, grid);
+                    assert_eq!(chunk_texture_index(position, grid), i);
+
+                    // the active chunks are centred on the origin chunk
+                    let offset = (position - origin).abs().as_uvec3();
+                    assert!(offset.cmple(grid / 2).all(), "{}", position);
+                }
+            }
+        }
+    }
 }
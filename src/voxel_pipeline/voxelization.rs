@@ -1,5 +1,5 @@
 use super::voxel_world::{VoxelData, VoxelUniforms};
-use crate::{Flags, RenderGraphSettings, VOXELS_PER_METER};
+use crate::{Flags, MaterialRegistry, RenderGraphSettings, VoxelEngineSettings};
 
 use bevy::{
     asset::{load_internal_asset, Handle},
@@ -155,7 +155,7 @@ fn update_cameras(
                 _ => panic!("Too many voxelization cameras"),
             };
 
-            let side = size as f32 / VOXELS_PER_METER / 2.0;
+            let side = size as f32 / voxel_uniforms.voxels_per_meter / 2.0;
 
             *projection = Projection::Orthographic(OrthographicProjection {
                 near: -side,
@@ -170,6 +170,15 @@ fn update_cameras(
             i += 1;
         }
     }
+
+    // the cameras follow the origin chunk while the world streams
+    let origin = voxel_uniforms.origin_chunk.as_vec3() * voxel_uniforms.chunk_size as f32
+        / voxel_uniforms.voxels_per_meter;
+    for (mut transform, _) in voxelization_cameras.iter_mut() {
+        if transform.translation != origin {
+            transform.translation = origin;
+        }
+    }
 }
 
 #[derive(Component, Clone, ExtractComponent)]
@@ -181,7 +190,7 @@ pub struct VoxelizationMaterial {
 impl Default for VoxelizationMaterial {
     fn default() -> Self {
         Self {
-            material: VoxelizationMaterialType::Material(10),
+            material: VoxelizationMaterialType::Named("default".to_string()),
             flags: Flags::ANIMATION_FLAG,
         }
     }
@@ -191,6 +200,11 @@ impl Default for VoxelizationMaterial {
 pub enum VoxelizationMaterialType {
     Texture(Handle<Image>),
     Material(u8),
+    /// A material from [`WideMaterials`](crate::WideMaterials), needs
+    /// [`VoxelFormat::Wide`](crate::VoxelFormat::Wide).
+    Wide(u16),
+    /// A name from the [`MaterialRegistry`], unknown names voxelize as empty.
+    Named(String),
 }
 
 #[derive(Clone, ShaderType)]
@@ -199,11 +213,13 @@ pub struct VoxelizationUniforms {
     flags: u32,
 }
 
-impl From<&VoxelizationMaterial> for VoxelizationUniforms {
-    fn from(value: &VoxelizationMaterial) -> Self {
+impl VoxelizationUniforms {
+    fn new(value: &VoxelizationMaterial, material_registry: &MaterialRegistry) -> Self {
         let material = match &value.material {
             VoxelizationMaterialType::Texture(_) => 255,
             VoxelizationMaterialType::Material(material) => *material as u32,
+            VoxelizationMaterialType::Wide(material) => *material as u32,
+            VoxelizationMaterialType::Named(name) => material_registry.get(name).unwrap_or(0) as u32,
         };
         Self {
             material,
@@ -226,6 +242,7 @@ pub struct VoxelizationPipeline {
     mesh_pipeline: MeshPipeline,
     world_bind_group_layout: BindGroupLayout,
     voxelization_bind_group_layout: BindGroupLayout,
+    shader_defs: Vec<ShaderDefVal>,
 }
 
 impl FromWorld for VoxelizationPipeline {
@@ -270,6 +287,7 @@ impl FromWorld for VoxelizationPipeline {
             mesh_pipeline: world.resource::<MeshPipeline>().clone(),
             world_bind_group_layout,
             voxelization_bind_group_layout,
+            shader_defs: world.resource::<VoxelEngineSettings>().shader_defs(),
         }
     }
 }
@@ -291,6 +309,16 @@ impl SpecializedMeshPipeline for VoxelizationPipeline {
             .vertex
             .shader_defs
             .push("MESH_BINDGROUP_1".into());
+        descriptor
+            .vertex
+            .shader_defs
+            .extend(self.shader_defs.iter().cloned());
+        descriptor
+            .fragment
+            .as_mut()
+            .unwrap()
+            .shader_defs
+            .extend(self.shader_defs.iter().cloned());
 
         descriptor.layout = vec![
             self.mesh_pipeline.get_view_layout(key.into()).clone(),
@@ -371,17 +399,16 @@ fn queue_bind_group(
     voxelization_materials: Query<(Entity, &VoxelizationMaterial)>,
     gpu_images: Res<RenderAssets<GpuImage>>,
     voxelization_pipeline: Res<VoxelizationPipeline>,
-    fallback_images: Res<FallbackImage>,
+    (fallback_images, material_registry): (Res<FallbackImage>, Res<MaterialRegistry>),
     mut voxelization_uniforms: ResMut<VoxelizationUniformsResource>,
 ) {
     for (entity, voxelization_material) in voxelization_materials.iter() {
+        let new_uniforms = VoxelizationUniforms::new(voxelization_material, &material_registry);
         let uniforms = voxelization_uniforms
             .entry(entity)
-            .or_insert(UniformBuffer::from(VoxelizationUniforms::from(
-                voxelization_material,
-            )));
+            .or_insert(UniformBuffer::from(new_uniforms.clone()));
 
-        uniforms.set(voxelization_material.into());
+        uniforms.set(new_uniforms);
         uniforms.write_buffer(&render_device, &render_queue);
 
         let sampler = render_device.create_sampler(&SamplerDescriptor::default());